@@ -5,24 +5,32 @@
  */
 
 use std::net::SocketAddr;
+#[cfg(not(unix))]
+use std::process::exit;
 use std::sync::Arc;
 
-use log::{trace, debug, info, warn, error};
+use log::{debug, info, warn, error};
 
-use reqwest;
-use reqwest::StatusCode as ReqwestStatusCode;
+use ipnet::IpNet;
 
 use serde_derive::{Deserialize, Serialize};
 
-use tokio::sync::Mutex;
+use tokio::sync::{watch, Mutex};
 
 use warp;
 use warp::{Filter};
 
+use crate::backoff::BackoffConfig;
+use crate::bubble_client::{BubbleClient, BubbleClientConfig, BubbleError, BubbleRegistration};
+use crate::host_keys::{is_valid_bubble_name, HostKeyStore};
 use crate::pass::is_correct_password;
-use crate::ssh::{spawn_ssh, stop_ssh_and_checker, SshContainer};
-use crate::net::is_valid_ip;
-use crate::util::HEADER_BUBBLE_SESSION;
+use crate::route_manager::NextHop;
+use crate::routes::RouteRegistry;
+use crate::ssh::{TunnelError, TunnelTarget};
+use crate::tunnel::{RegisterError, TunnelManager};
+use crate::net::{find_vpn_ip, is_valid_ip, parse_ip};
+#[cfg(unix)]
+use crate::util::bind_private_socket;
 
 const MAX_POST_LIMIT: u64 = 1024 * 16;
 
@@ -39,12 +47,29 @@ pub struct AdminUnregistration {
     password: Option<String>
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AdminTunnelStatusRequest {
+    password: Option<String>
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ValidAdminRegistration {
     password: String,
     session: String,
     bubble: String,
-    ip: String
+    ip: String,
+    ip_found: bool
+}
+
+/// What we registered with the bubble, kept from a successful registration until unregister,
+/// so the same record can be deleted later without validating the request again
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActiveRegistration {
+    pub bubble: String,
+    pub session: String,
+    pub ip: String,
+    /// the admin omitted the ip and we found it in --vpn-subnet, so it may change with the network
+    pub ip_found: bool
 }
 
 impl AdminRegistration {
@@ -58,81 +83,106 @@ impl AdminRegistration {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
-struct BubbleRegistration {
-    key: String,
-    ip: String,
-    auth_token: String
+/// Where the admin server listens, and what it needs to register this device with a bubble
+pub struct AdminConfig {
+    /// listen on 127.0.0.1 at this port
+    pub port: Option<u16>,
+    /// listen on a private Unix socket at this path
+    pub socket: Option<String>,
+    /// where the tunnel delivers connections from the bubble
+    pub proxy_target: TunnelTarget,
+    pub password_hash: String,
+    pub auth_token: Arc<String>,
+    pub ssh_priv_key: Arc<String>,
+    pub ssh_pub_key: Arc<String>,
+    /// where to look for our VPN address when a registration omits it
+    pub vpn_subnet: IpNet,
+    pub bubble_config: BubbleClientConfig
 }
 
-struct BubbleInternalRegistration {
-    ip: Arc<String>,
-    bubble: Arc<String>,
-    session: Arc<String>
-}
+pub async fn start_admin (admin_reg : Arc<Mutex<Option<ActiveRegistration>>>,
+                          config : AdminConfig,
+                          check_ssh_interval : u64,
+                          backoff_config : BackoffConfig,
+                          host_keys : HostKeyStore,
+                          routes : Arc<RouteRegistry>) {
+    let config = Arc::new(config);
+    let tunnel = TunnelManager::new(check_ssh_interval, backoff_config, host_keys);
 
-impl BubbleInternalRegistration {
-    pub fn new(reg : &BubbleRegistration, bubble : String, session : String) -> BubbleInternalRegistration {
-        BubbleInternalRegistration {
-            ip: Arc::new(reg.ip.clone()),
-            bubble: Arc::new(bubble.clone()),
-            session: Arc::new(session.clone())
-        }
-    }
-}
-
-#[derive(Debug, Deserialize, Serialize, Clone)]
-struct BubbleRegistrationResponse {
-    port: u16,
-    host_key: String
-}
-
-pub async fn start_admin (admin_reg : Arc<Mutex<Option<AdminRegistration>>>,
-                          admin_port : u16,
-                          proxy_port : u16,
-                          password_hash: String,
-                          auth_token : Arc<String>,
-                          ssh_priv_key : Arc<String>,
-                          ssh_pub_key : Arc<String>,
-                          check_ssh_interval : u64) {
-    let admin_sock : SocketAddr = format!("127.0.0.1:{}", admin_port).parse().unwrap();
-    let ssh_container: Arc<Mutex<SshContainer>> = Arc::new(Mutex::new(SshContainer::new()));
+    tokio::spawn(reregister_on_gateway_change(
+        routes.subscribe_gateway(),
+        routes.gateway(),
+        admin_reg.clone(),
+        config.clone(),
+        tunnel.clone()));
 
     let admin_reg_clone = admin_reg.clone();
-    let password_hash_clone = password_hash.clone();
-    let ssh_container_clone = ssh_container.clone();
+    let config_clone = config.clone();
+    let tunnel_clone = tunnel.clone();
     let register = warp::post().and(warp::path!("register")
         .and(warp::body::content_length_limit(MAX_POST_LIMIT))
         .and(warp::body::json())
         .and(warp::any().map(move || admin_reg_clone.clone()))
-        .and(warp::any().map(move || proxy_port))
-        .and(warp::any().map(move || password_hash_clone.clone()))
-        .and(warp::any().map(move || auth_token.clone()))
-        .and(warp::any().map(move || ssh_priv_key.clone()))
-        .and(warp::any().map(move || ssh_pub_key.clone()))
-        .and(warp::any().map(move || ssh_container_clone.clone()))
-        .and(warp::any().map(move || check_ssh_interval))
+        .and(warp::any().map(move || config_clone.clone()))
+        .and(warp::any().map(move || tunnel_clone.clone()))
         .and_then(handle_register));
 
     let admin_reg_clone = admin_reg.clone();
+    let password_hash = config.password_hash.clone();
     let password_hash_clone = password_hash.clone();
-    let ssh_container_clone = ssh_container.clone();
+    let bubble_config = config.bubble_config;
+    let tunnel_clone = tunnel.clone();
     let unregister = warp::post().and(warp::path!("unregister")
         .and(warp::body::content_length_limit(MAX_POST_LIMIT))
         .and(warp::body::json())
         .and(warp::any().map(move || admin_reg_clone.clone()))
         .and(warp::any().map(move || password_hash_clone.clone()))
-        .and(warp::any().map(move || ssh_container_clone.clone()))
+        .and(warp::any().map(move || tunnel_clone.clone()))
+        .and(warp::any().map(move || routes.clone()))
+        .and(warp::any().map(move || bubble_config))
         .and_then(handle_unregister));
 
     let ping = warp::get().and(warp::path!("ping")
         .and_then(handle_ping));
 
-    let routes = register.or(unregister).or(ping);
+    let tunnel_status = warp::post().and(warp::path!("tunnel")
+        .and(warp::body::content_length_limit(MAX_POST_LIMIT))
+        .and(warp::body::json())
+        .and(warp::any().map(move || password_hash.clone()))
+        .and(warp::any().map(move || tunnel.clone()))
+        .and_then(handle_tunnel_status));
+
+    let routes = register.or(unregister).or(ping).or(tunnel_status);
+
+    #[cfg(unix)]
+    let socket_server = match config.socket.clone() {
+        Some(path) => {
+            let listener = bind_private_socket(path.as_str()).await;
+            let server = warp::serve(routes.clone()).run_incoming(listener);
+            info!("start_admin: Admin listening on unix:{}", path);
+            Some(tokio::spawn(server))
+        }
+        None => None
+    };
+    #[cfg(not(unix))]
+    let socket_server: Option<tokio::task::JoinHandle<()>> = match config.socket.clone() {
+        Some(path) => {
+            error!("start_admin: unix sockets are not supported on this platform, cannot listen on {}", path);
+            exit(2);
+        }
+        None => None
+    };
 
-    let admin_server = warp::serve(routes).run(admin_sock);
-    info!("start_admin: Admin listening on {}", admin_sock);
-    admin_server.await;
+    if config.port.is_some() {
+        let admin_sock : SocketAddr = format!("127.0.0.1:{}", config.port.unwrap()).parse().unwrap();
+        let admin_server = warp::serve(routes).run(admin_sock);
+        info!("start_admin: Admin listening on {}", admin_sock);
+        admin_server.await;
+    }
+    if socket_server.is_some() {
+        let result = socket_server.unwrap().await;
+        debug!("start_admin: Admin socket await result: {:?}", result);
+    }
 }
 
 async fn handle_ping() -> Result<impl warp::Reply, warp::Rejection> {
@@ -142,17 +192,38 @@ async fn handle_ping() -> Result<impl warp::Reply, warp::Rejection> {
     ))
 }
 
+async fn handle_tunnel_status(request : AdminTunnelStatusRequest,
+                              hashed_password : String,
+                              tunnel : Arc<TunnelManager>) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let password = match request.password {
+        Some(password) => password,
+        None => return Ok(Box::new(warp::reply::with_status(
+            "no password\n",
+            http::StatusCode::UNAUTHORIZED,
+        )))
+    };
+    match is_correct_password(password, hashed_password) {
+        Ok(true) => Ok(Box::new(warp::reply::json(&tunnel.report()))),
+        Ok(false) => Ok(Box::new(warp::reply::with_status(
+            "password was incorrect\n",
+            http::StatusCode::UNAUTHORIZED,
+        ))),
+        Err(e) => {
+            error!("handle_tunnel_status: error verifying password: {:?}", e);
+            Ok(Box::new(warp::reply::with_status(
+                "error verifying password\n",
+                http::StatusCode::UNAUTHORIZED,
+            )))
+        }
+    }
+}
+
 async fn handle_register(registration : AdminRegistration,
-                         admin_reg : Arc<Mutex<Option<AdminRegistration>>>,
-                         proxy_port : u16,
-                         hashed_password : String,
-                         auth_token : Arc<String>,
-                         ssh_priv_key : Arc<String>,
-                         ssh_pub_key : Arc<String>,
-                         ssh_container : Arc<Mutex<SshContainer>>,
-                         check_ssh_interval : u64) -> Result<impl warp::Reply, warp::Rejection> {
+                         admin_reg : Arc<Mutex<Option<ActiveRegistration>>>,
+                         config : Arc<AdminConfig>,
+                         tunnel : Arc<TunnelManager>) -> Result<impl warp::Reply, warp::Rejection> {
     // validate registration
-    let validated = validate_admin_registration(registration.clone());
+    let validated = validate_admin_registration(registration, &config.vpn_subnet);
     if validated.is_err() {
         let err = validated.err();
         if err.is_some() {
@@ -168,7 +239,7 @@ async fn handle_register(registration : AdminRegistration,
     }
     let validated = validated.unwrap();
 
-    let pass_result = is_correct_password(validated.password, hashed_password);
+    let pass_result = is_correct_password(validated.password, config.password_hash.clone());
     if pass_result.is_err() {
         error!("handle_register: error verifying password: {:?}", pass_result.err());
         Ok(warp::reply::with_status(
@@ -181,97 +252,56 @@ async fn handle_register(registration : AdminRegistration,
             http::StatusCode::UNAUTHORIZED,
         ))
     } else {
-        // do we have a previous registration?
-        let bubble_registration;
-        {
-            let mut guard = admin_reg.lock().await;
-            if (*guard).is_some() {
-                // shut down previous tunnel
-                debug!("handle_register: ssh_container exists, stopping current ssh tunnel and checker");
-                stop_ssh_and_checker(ssh_container.clone()).await;
+        let active = ActiveRegistration {
+            bubble: validated.bubble,
+            session: validated.session,
+            ip: validated.ip,
+            ip_found: validated.ip_found
+        };
+        let bubble_client = match BubbleClient::new(active.bubble.as_str(), active.session.as_str(), &config.bubble_config) {
+            Ok(client) => Arc::new(client),
+            Err(e) => {
+                error!("handle_register: error creating bubble client: {}", e);
+                return Ok(warp::reply::with_status(
+                    "invalid request object\n",
+                    http::StatusCode::UNAUTHORIZED,
+                ));
             }
+        };
+        let bubble_registration = BubbleRegistration {
+            key: config.ssh_pub_key.to_string(),
+            ip: active.ip.clone(),
+            auth_token: config.auth_token.to_string()
+        };
 
-            // create the registration object
-            bubble_registration = BubbleRegistration {
-                key: ssh_pub_key.to_string(),
-                ip: validated.ip,
-                auth_token: auth_token.to_string()
-            };
-            (*guard) = Some(registration);
-        }
-        let internal_reg = BubbleInternalRegistration::new(&bubble_registration, validated.bubble, validated.session);
+        // held until the new registration is stored, so register, unregister and re-registration never interleave
+        let mut guard = admin_reg.lock().await;
+        let previous = guard.take();
+        // a registration with another bubble, or for another ip, is not replaced by ours
+        let replaces_previous = matches!(&previous, Some(previous) if previous.bubble != active.bubble || previous.ip != active.ip);
 
         // PUT it and see if it worked
-        let client = reqwest::Client::new();
-        let url = format!("https://{}:1443/api/me/flexRouters", internal_reg.bubble.clone());
-        trace!("handle_register: registering ourself with {}, sending: {:?}", url, bubble_registration);
-        let session = internal_reg.session.clone();
-        match client.put(url.as_str())
-            .header(HEADER_BUBBLE_SESSION, session.to_string())
-            .json(&bubble_registration)
-            .send().await {
-            Ok(response) => {
-                match response.status() {
-                    ReqwestStatusCode::OK => {
-                        info!("handle_register: successfully registered with bubble");
-                        let body_bytes = &response.bytes().await.unwrap();
-                        let body = String::from_utf8(body_bytes.to_vec()).unwrap();
-                        let reg_opt = serde_json::from_str(body.as_str());
-                        if reg_opt.is_err() {
-                            error!("handle_register: error registering with bubble, error parsing response: {}", body);
-                            Ok(warp::reply::with_status(
-                                "error registering with bubble, error parsing response\n",
-                                http::StatusCode::PRECONDITION_FAILED,
-                            ))
-                        } else {
-                            let reg_response: BubbleRegistrationResponse = reg_opt.unwrap();
-                            trace!("handle_register: parsed response object: {:?}", reg_response);
-                            let ssh_result = spawn_ssh(
-                                ssh_container.clone(),
-                                internal_reg.ip.clone(),
-                                reg_response.port,
-                                proxy_port,
-                                internal_reg.bubble.clone(),
-                                internal_reg.session.clone(),
-                                reg_response.host_key,
-                                ssh_priv_key,
-                                check_ssh_interval).await;
-                            if ssh_result.is_err() {
-                                let err = ssh_result.err();
-                                if err.is_none() {
-                                    error!("handle_register: error spawning ssh");
-                                } else {
-                                    error!("handle_register: error spawning ssh: {:?}", err.unwrap());
-                                }
-                                Ok(warp::reply::with_status(
-                                    "error registering with bubble, error spawning ssh\n",
-                                    http::StatusCode::PRECONDITION_FAILED,
-                                ))
-                            } else {
-                                debug!("handle_register: spawned ssh tunnel");
-                                Ok(warp::reply::with_status(
-                                    "successfully registered with bubble\n",
-                                    http::StatusCode::OK,
-                                ))
-                            }
-                        }
-                    },
-                    _ => {
-                        let status_code = &response.status();
-                        let body_bytes = &response.bytes().await.unwrap();
-                        let body = String::from_utf8(body_bytes.to_vec()).unwrap();
-                        error!("handle_register: error registering with bubble: {:?}: {}", status_code, body);
-                        Ok(warp::reply::with_status(
-                            "error registering with bubble\n",
-                            http::StatusCode::PRECONDITION_FAILED,
-                        ))
-                    }
+        match register_with_bubble(&bubble_registration, bubble_client, config.proxy_target.clone(), config.ssh_priv_key.clone(), tunnel.clone()).await {
+            Ok(_) => {
+                // only delete the registration ours replaces once ours works
+                if replaces_previous {
+                    delete_from_bubble(previous.as_ref().unwrap(), &config.bubble_config).await;
                 }
-            },
-            Err(error) => {
-                error!("handle_register: error registering with bubble: {:?}", error);
+                (*guard) = Some(active);
+                Ok(warp::reply::with_status(
+                    "successfully registered with bubble\n",
+                    http::StatusCode::OK,
+                ))
+            }
+            Err(message) => {
+                // registering closed the previous tunnel: open it again, so a failed attempt leaves us where we were.
+                // the bubble may still hold a previous registration for the same ip: keep it for unregister to delete
+                (*guard) = match previous {
+                    Some(previous) if replaces_previous => restore_registration(previous, &config, tunnel).await,
+                    previous => previous
+                };
                 Ok(warp::reply::with_status(
-                    "error registering with bubble\n",
+                    message,
                     http::StatusCode::PRECONDITION_FAILED,
                 ))
             }
@@ -279,10 +309,140 @@ async fn handle_register(registration : AdminRegistration,
     }
 }
 
+// Register previous again after registering something else in its place failed.
+// Returns what we are registered as afterwards
+async fn restore_registration(previous : ActiveRegistration,
+                              config : &AdminConfig,
+                              tunnel : Arc<TunnelManager>) -> Option<ActiveRegistration> {
+    info!("restore_registration: registering {} with {} again", previous.ip, previous.bubble);
+    let bubble_client = match BubbleClient::new(previous.bubble.as_str(), previous.session.as_str(), &config.bubble_config) {
+        Ok(client) => Arc::new(client),
+        Err(e) => {
+            error!("restore_registration: error creating bubble client: {}", e);
+            return None;
+        }
+    };
+    let bubble_registration = BubbleRegistration {
+        key: config.ssh_pub_key.to_string(),
+        ip: previous.ip.clone(),
+        auth_token: config.auth_token.to_string()
+    };
+    match register_with_bubble(&bubble_registration, bubble_client, config.proxy_target.clone(), config.ssh_priv_key.clone(), tunnel).await {
+        Ok(_) => Some(previous),
+        Err(message) => {
+            error!("restore_registration: {}", message.trim());
+            None
+        }
+    }
+}
+
+// PUT our registration to the bubble and open the ssh tunnel it asks for.
+// On failure, returns the message for the admin client, which names the kind of failure
+async fn register_with_bubble(bubble_registration : &BubbleRegistration,
+                              bubble_client : Arc<BubbleClient>,
+                              proxy_target : TunnelTarget,
+                              ssh_priv_key : Arc<String>,
+                              tunnel : Arc<TunnelManager>) -> Result<(), &'static str> {
+    match tunnel.register(bubble_registration, bubble_client, proxy_target, ssh_priv_key).await {
+        Ok(_) => {
+            info!("register_with_bubble: registered with bubble and opened ssh tunnel");
+            Ok(())
+        }
+        Err(RegisterError::Bubble(BubbleError::InvalidResponse(body))) => {
+            error!("register_with_bubble: error registering with bubble, error parsing response: {}", body);
+            Err("error registering with bubble, error parsing response\n")
+        }
+        Err(RegisterError::Bubble(e)) => {
+            error!("register_with_bubble: error registering with bubble: {}", e);
+            Err("error registering with bubble\n")
+        }
+        Err(RegisterError::Tunnel(e)) => {
+            error!("register_with_bubble: error opening tunnel to {}: {}", bubble_registration.ip, e);
+            Err(match e {
+                TunnelError::Unreachable(_) => "error registering with bubble, bubble ssh port is unreachable\n",
+                TunnelError::HostKeyMismatch { .. } => "error registering with bubble, bubble host key did not match\n",
+                TunnelError::KeyFile(_) => "error registering with bubble, error reading ssh key\n",
+                TunnelError::AuthRefused(_) => "error registering with bubble, bubble refused ssh key\n",
+                TunnelError::PortInUse(_) => "error registering with bubble, tunnel port already in use on bubble\n",
+                TunnelError::ForwardingFailed(_) => "error registering with bubble, remote port forwarding failed\n",
+                TunnelError::Ssh(_) => "error registering with bubble, error opening tunnel\n"
+            })
+        }
+        Err(e @ RegisterError::HostKeyChanged { .. }) => {
+            error!("register_with_bubble: {}", e);
+            Err("error registering with bubble, bubble host key changed since first registration\n")
+        }
+    }
+}
+
+// When the device moves to another network, the old tunnel is dead and the bubble needs to hear
+// from us again: register again, from the new network, and open a new tunnel
+async fn reregister_on_gateway_change(mut gateways : watch::Receiver<NextHop>,
+                                      mut current_gateway : NextHop,
+                                      admin_reg : Arc<Mutex<Option<ActiveRegistration>>>,
+                                      config : Arc<AdminConfig>,
+                                      tunnel : Arc<TunnelManager>) {
+    while let Some(gateway) = gateways.recv().await {
+        if gateway == current_gateway {
+            continue;
+        }
+        current_gateway = gateway.clone();
+        // held until we are registered again, so an admin register or unregister waits for us
+        let mut guard = admin_reg.lock().await;
+        let active = match guard.clone() {
+            Some(active) => active,
+            None => {
+                debug!("reregister_on_gateway_change: gateway changed to {}, not registered with a bubble", gateway);
+                continue;
+            }
+        };
+        // register the ip the bubble knows us by. only an ip we found ourselves can have changed:
+        // then the bubble's record for the old one is stale
+        let mut ip = active.ip.clone();
+        if active.ip_found {
+            match find_vpn_ip(&config.vpn_subnet) {
+                Some(found) if found.to_string() != active.ip => {
+                    info!("reregister_on_gateway_change: VPN address changed from {} to {}", active.ip, found);
+                    delete_from_bubble(&active, &config.bubble_config).await;
+                    ip = found.to_string();
+                }
+                Some(_) => {}
+                None => debug!("reregister_on_gateway_change: no VPN address in {} yet, registering {} again", config.vpn_subnet, active.ip)
+            }
+        }
+        info!("reregister_on_gateway_change: gateway changed to {}, registering {} with {} again", gateway, ip, active.bubble);
+        let bubble_registration = BubbleRegistration {
+            key: config.ssh_pub_key.to_string(),
+            ip: ip.clone(),
+            auth_token: config.auth_token.to_string()
+        };
+        let bubble_client = match BubbleClient::new(active.bubble.as_str(), active.session.as_str(), &config.bubble_config) {
+            Ok(client) => Arc::new(client),
+            Err(e) => {
+                error!("reregister_on_gateway_change: error creating bubble client: {}", e);
+                (*guard) = None;
+                continue;
+            }
+        };
+        let result = register_with_bubble(&bubble_registration, bubble_client, config.proxy_target.clone(), config.ssh_priv_key.clone(), tunnel.clone()).await;
+        match result {
+            // keep what the bubble should know us by, for the next network change and for unregister
+            Ok(_) => (*guard) = Some(ActiveRegistration { ip, ..active }),
+            Err(message) => {
+                // without a tunnel we are not registered anywhere: the admin has to register again
+                error!("reregister_on_gateway_change: {}", message.trim());
+                (*guard) = None;
+            }
+        }
+    }
+}
+
 pub async fn handle_unregister(unregistration : AdminUnregistration,
-                               admin_reg : Arc<Mutex<Option<AdminRegistration>>>,
+                               admin_reg : Arc<Mutex<Option<ActiveRegistration>>>,
                                hashed_password : String,
-                               ssh_container : Arc<Mutex<SshContainer>>) -> Result<impl warp::Reply, warp::Rejection> {
+                               tunnel : Arc<TunnelManager>,
+                               routes : Arc<RouteRegistry>,
+                               bubble_config : BubbleClientConfig) -> Result<impl warp::Reply, warp::Rejection> {
     if unregistration.password.is_none() {
         return Ok(warp::reply::with_status(
             "no password\n",
@@ -304,38 +464,85 @@ pub async fn handle_unregister(unregistration : AdminUnregistration,
         ))
     } else {
         // do we have a previous registration?
+        let mut deleted_on_bubble = true;
         {
             let mut guard = admin_reg.lock().await;
-            if (*guard).is_some() {
-                // shut down previous tunnel
-                debug!("handle_register: ssh_container exists, stopping current ssh tunnel and checker");
-                stop_ssh_and_checker(ssh_container.clone()).await;
-                (*guard) = None;
+            if let Some(active) = guard.take() {
+                // tell the bubble to forget us, then shut down the tunnel
+                deleted_on_bubble = delete_from_bubble(&active, &bubble_config).await;
+                tunnel.unregister("unregistered by admin").await;
+                routes.flush().await;
                 info!("handle_unregister: successfully unregistered");
             } else {
                 warn!("handle_unregister: not registered, cannot unregister");
             }
         }
-        Ok(warp::reply::with_status(
-            "successfully unregistered from bubble\n",
-            http::StatusCode::OK,
-        ))
+        if deleted_on_bubble {
+            Ok(warp::reply::with_status(
+                "successfully unregistered from bubble\n",
+                http::StatusCode::OK,
+            ))
+        } else {
+            Ok(warp::reply::with_status(
+                "unregistered, but could not delete registration from bubble\n",
+                http::StatusCode::OK,
+            ))
+        }
     }
 }
 
-pub fn validate_admin_registration(reg : AdminRegistration) -> Result<ValidAdminRegistration, String>{
-    // validate ip
-    if reg.ip.is_none() || reg.password.is_none() || reg.bubble.is_none() || reg.session.is_none() {
+// DELETE our registration from the bubble, so it does not keep a stale flex router.
+// Returns false if the bubble could not be told
+async fn delete_from_bubble(registration : &ActiveRegistration,
+                            bubble_config : &BubbleClientConfig) -> bool {
+    let bubble_client = BubbleClient::new(registration.bubble.as_str(), registration.session.as_str(), bubble_config);
+    if bubble_client.is_err() {
+        error!("delete_from_bubble: error creating bubble client: {}", bubble_client.err().unwrap());
+        return false;
+    }
+    match bubble_client.unwrap().delete(registration.ip.as_str()).await {
+        Ok(_) => {
+            info!("delete_from_bubble: deleted registration of {} from {}", registration.ip, registration.bubble);
+            true
+        }
+        Err(e) => {
+            error!("delete_from_bubble: error deleting registration of {} from {}: {}", registration.ip, registration.bubble, e);
+            false
+        }
+    }
+}
+
+pub fn validate_admin_registration(reg : AdminRegistration, vpn_subnet : &IpNet) -> Result<ValidAdminRegistration, String>{
+    if reg.password.is_none() || reg.bubble.is_none() || reg.session.is_none() {
         return Err(String::from("required field not found"));
     }
-    let ip = reg.ip.unwrap();
-    if !is_valid_ip(&ip) {
-        return Err(String::from("ip was invalid"));
+    if !is_valid_bubble_name(reg.bubble.as_ref().unwrap()) {
+        return Err(String::from("bubble was not a valid hostname"));
     }
+    // validate ip, or find our VPN address if none was given
+    let ip_found = reg.ip.is_none();
+    let ip = match reg.ip {
+        Some(ip) => {
+            let parsed = parse_ip(&ip);
+            if parsed.is_none() || !is_valid_ip(&parsed.unwrap()) {
+                return Err(String::from("ip was invalid"));
+            }
+            parsed.unwrap()
+        }
+        None => match find_vpn_ip(vpn_subnet) {
+            Some(ip) => {
+                debug!("validate_admin_registration: no ip in registration, found VPN address {}", ip);
+                ip
+            }
+            None => return Err(format!("no ip in registration and no VPN address found in {}", vpn_subnet))
+        }
+    };
+    // the bubble expects IPv6 addresses without brackets, in their shortest form
     return Ok(ValidAdminRegistration {
         password: reg.password.unwrap(),
         session: reg.session.unwrap(),
         bubble: reg.bubble.unwrap(),
-        ip
+        ip: ip.to_string(),
+        ip_found
     })
 }
@@ -0,0 +1,94 @@
+#![deny(warnings)]
+/**
+ * Copyright (c) 2020 Bubble, Inc.  All rights reserved.
+ * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
+ */
+
+use rand::Rng;
+
+use tokio::time::{Duration, Instant};
+
+pub const DEFAULT_RECONNECT_INITIAL_DELAY: u64 = 1;
+pub const DEFAULT_RECONNECT_MAX_DELAY: u64 = 300;
+pub const DEFAULT_RECONNECT_MAX_FAILURES: u32 = 10;
+pub const DEFAULT_RECONNECT_HEALTHY_RESET: u64 = 60;
+
+/// How tunnel reconnects back off
+#[derive(Debug, Clone, Copy)]
+pub struct BackoffConfig {
+    /// delay after the first failure, doubled after each further failure
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// after this many consecutive failures, stop retrying on a timer
+    pub max_failures: u32,
+    /// a connection that stays up this long clears the failure count
+    pub healthy_reset: Duration
+}
+
+impl Default for BackoffConfig {
+    fn default () -> BackoffConfig {
+        BackoffConfig {
+            initial_delay: Duration::from_secs(DEFAULT_RECONNECT_INITIAL_DELAY),
+            max_delay: Duration::from_secs(DEFAULT_RECONNECT_MAX_DELAY),
+            max_failures: DEFAULT_RECONNECT_MAX_FAILURES,
+            healthy_reset: Duration::from_secs(DEFAULT_RECONNECT_HEALTHY_RESET)
+        }
+    }
+}
+
+/// Counts consecutive connection failures and says how long to wait before the next attempt.
+/// Delays grow exponentially up to max_delay, with jitter so that many flex routers losing the same
+/// bubble do not all retry at once. Times come from the tokio clock, so tests can pause it.
+#[derive(Debug)]
+pub struct Backoff {
+    config: BackoffConfig,
+    failures: u32,
+    connected_since: Option<Instant>
+}
+
+impl Backoff {
+    pub fn new (config : BackoffConfig) -> Backoff {
+        Backoff { config, failures: 0, connected_since: None }
+    }
+
+    pub fn failures (&self) -> u32 { self.failures }
+
+    /// True once max_failures consecutive attempts have failed
+    pub fn is_open (&self) -> bool { self.failures >= self.config.max_failures }
+
+    /// The longest delay after the nth consecutive failure, before jitter
+    pub fn max_delay_after (&self, failures : u32) -> Duration {
+        let doublings = failures.saturating_sub(1).min(31);
+        let delay = self.config.initial_delay.checked_mul(1u32 << doublings).unwrap_or(self.config.max_delay);
+        delay.min(self.config.max_delay)
+    }
+
+    /// Record a failed attempt. Returns how long to wait before the next one,
+    /// or None if there have been too many failures to keep retrying
+    pub fn record_failure (&mut self) -> Option<Duration> {
+        self.failures = self.failures.saturating_add(1);
+        self.connected_since = None;
+        if self.is_open() {
+            return None;
+        }
+        // "equal jitter": somewhere between half the delay and all of it
+        let delay = self.max_delay_after(self.failures);
+        let half = delay / 2;
+        let jitter = rand::thread_rng().gen_range(0, half.as_millis() as u64 + 1);
+        Some(half + Duration::from_millis(jitter))
+    }
+
+    /// Record a successful connection. The failure count is kept until the connection proves healthy
+    pub fn record_connected (&mut self, now : Instant) {
+        self.connected_since = Some(now);
+    }
+
+    /// Record that the connection is still healthy; clears the failure count once it has been for healthy_reset
+    pub fn record_healthy (&mut self, now : Instant) {
+        if let Some(since) = self.connected_since {
+            if self.failures > 0 && now.duration_since(since) >= self.config.healthy_reset {
+                self.failures = 0;
+            }
+        }
+    }
+}
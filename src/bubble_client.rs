@@ -0,0 +1,206 @@
+#![deny(warnings)]
+/**
+ * Copyright (c) 2020 Bubble, Inc.  All rights reserved.
+ * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
+ */
+
+use std::fmt;
+use std::time::Duration;
+
+use log::trace;
+
+use reqwest;
+use reqwest::header::{HeaderMap, HeaderValue};
+use reqwest::StatusCode as ReqwestStatusCode;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::util::HEADER_BUBBLE_SESSION;
+
+pub const DEFAULT_BUBBLE_API_PORT: u16 = 1443;
+pub const DEFAULT_BUBBLE_CONNECT_TIMEOUT: u64 = 10;
+pub const DEFAULT_BUBBLE_REQUEST_TIMEOUT: u64 = 20;
+
+/// How to reach the API of the bubbles we register with
+#[derive(Debug, Clone, Copy)]
+pub struct BubbleClientConfig {
+    pub port: u16,
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration
+}
+
+impl Default for BubbleClientConfig {
+    fn default () -> BubbleClientConfig {
+        BubbleClientConfig {
+            port: DEFAULT_BUBBLE_API_PORT,
+            connect_timeout: Duration::from_secs(DEFAULT_BUBBLE_CONNECT_TIMEOUT),
+            request_timeout: Duration::from_secs(DEFAULT_BUBBLE_REQUEST_TIMEOUT)
+        }
+    }
+}
+
+/// What we tell the bubble when registering: our SSH public key, our VPN address and our auth token
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct BubbleRegistration {
+    pub key: String,
+    pub ip: String,
+    pub auth_token: String
+}
+
+/// The bubble's answer to a registration: the port it listens on for our tunnel, and its SSH host key
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct BubbleRegistrationResponse {
+    pub port: u16,
+    pub host_key: String
+}
+
+/// What the bubble knows about our tunnel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TunnelStatus {
+    /// the bubble has not checked the tunnel yet
+    None,
+    Active,
+    Unreachable,
+    /// the registration was removed on the bubble
+    Deleted,
+    Unknown (String)
+}
+
+impl From<&str> for TunnelStatus {
+    fn from(status : &str) -> TunnelStatus {
+        match status {
+            "none" => TunnelStatus::None,
+            "active" => TunnelStatus::Active,
+            "unreachable" => TunnelStatus::Unreachable,
+            "deleted" => TunnelStatus::Deleted,
+            _ => TunnelStatus::Unknown(String::from(status))
+        }
+    }
+}
+
+impl fmt::Display for TunnelStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TunnelStatus::None => write!(f, "none"),
+            TunnelStatus::Active => write!(f, "active"),
+            TunnelStatus::Unreachable => write!(f, "unreachable"),
+            TunnelStatus::Deleted => write!(f, "deleted"),
+            TunnelStatus::Unknown(status) => write!(f, "{}", status)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BubbleError {
+    /// the request could not be built from what we were given
+    InvalidRequest (String),
+    /// the request could not be sent, or no response arrived in time
+    Request (reqwest::Error),
+    /// the bubble answered with an error status
+    Status (ReqwestStatusCode, String),
+    /// the bubble answered OK, but we could not understand the body
+    InvalidResponse (String)
+}
+
+impl fmt::Display for BubbleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BubbleError::InvalidRequest(message) => write!(f, "invalid request to bubble: {}", message),
+            BubbleError::Request(e) => write!(f, "error sending request to bubble: {}", e),
+            BubbleError::Status(status, body) => write!(f, "bubble returned {}: {}", status, body.trim()),
+            BubbleError::InvalidResponse(body) => write!(f, "invalid response from bubble: {}", body.trim())
+        }
+    }
+}
+
+impl std::error::Error for BubbleError {}
+
+impl From<reqwest::Error> for BubbleError {
+    fn from(e : reqwest::Error) -> BubbleError { BubbleError::Request(e) }
+}
+
+/// The flex router API of one bubble, called with the session of the user who registered us
+#[derive(Debug, Clone)]
+pub struct BubbleClient {
+    bubble: String,
+    base_url: String,
+    client: reqwest::Client
+}
+
+impl BubbleClient {
+    pub fn new (bubble : &str, session : &str, config : &BubbleClientConfig) -> Result<BubbleClient, BubbleError> {
+        let base_url = format!("https://{}:{}/api", bubble, config.port);
+        BubbleClient::with_base_url(bubble, base_url.as_str(), session, config)
+    }
+
+    /// A client for the API at base_url, for bubbles that are not reached over https on their hostname
+    pub fn with_base_url (bubble : &str, base_url : &str, session : &str, config : &BubbleClientConfig) -> Result<BubbleClient, BubbleError> {
+        let mut headers = HeaderMap::new();
+        let session_header = HeaderValue::from_str(session);
+        if session_header.is_err() {
+            return Err(BubbleError::InvalidRequest(String::from("session is not a valid header value")));
+        }
+        headers.insert(HEADER_BUBBLE_SESSION, session_header.unwrap());
+        let client = reqwest::Client::builder()
+            .connect_timeout(config.connect_timeout)
+            .timeout(config.request_timeout)
+            .default_headers(headers)
+            .build()?;
+        Ok(BubbleClient {
+            bubble: String::from(bubble),
+            base_url: String::from(base_url.trim_end_matches('/')),
+            client
+        })
+    }
+
+    /// The bubble's hostname
+    pub fn bubble (&self) -> &str { self.bubble.as_str() }
+
+    fn flex_router_url (&self, ip : &str) -> String {
+        format!("{}/me/flexRouters/{}", self.base_url, ip)
+    }
+
+    /// Register (or register again) with the bubble
+    pub async fn register (&self, registration : &BubbleRegistration) -> Result<BubbleRegistrationResponse, BubbleError> {
+        let url = format!("{}/me/flexRouters", self.base_url);
+        trace!("BubbleClient.register: registering with {}, sending: {:?}", url, registration);
+        let response = self.client.put(url.as_str()).json(registration).send().await?;
+        let body = BubbleClient::ok_body(response).await?;
+        match serde_json::from_str::<BubbleRegistrationResponse>(body.as_str()) {
+            Ok(reg_response) => Ok(reg_response),
+            Err(_) => Err(BubbleError::InvalidResponse(body))
+        }
+    }
+
+    /// What the bubble knows about the tunnel for the registration of ip
+    pub async fn status (&self, ip : &str) -> Result<TunnelStatus, BubbleError> {
+        let url = format!("{}/status", self.flex_router_url(ip));
+        let response = self.client.get(url.as_str()).send().await?;
+        let body = BubbleClient::ok_body(response).await?;
+        trace!("BubbleClient.status: tunnel status via {}: {}", url, body);
+        Ok(TunnelStatus::from(body.trim().replace('"', "").as_str()))
+    }
+
+    /// Remove the registration of ip from the bubble. Succeeds if there was no such registration
+    pub async fn delete (&self, ip : &str) -> Result<(), BubbleError> {
+        let url = self.flex_router_url(ip);
+        trace!("BubbleClient.delete: deleting registration via {}", url);
+        let response = self.client.delete(url.as_str()).send().await?;
+        if response.status() == ReqwestStatusCode::NOT_FOUND {
+            return Ok(());
+        }
+        BubbleClient::ok_body(response).await?;
+        Ok(())
+    }
+
+    async fn ok_body (response : reqwest::Response) -> Result<String, BubbleError> {
+        let status = response.status();
+        let body_bytes = response.bytes().await?;
+        let body = String::from_utf8_lossy(&body_bytes).to_string();
+        if status.is_success() {
+            Ok(body)
+        } else {
+            Err(BubbleError::Status(status, body))
+        }
+    }
+}
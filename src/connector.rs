@@ -0,0 +1,140 @@
+#![deny(warnings)]
+/**
+ * Copyright (c) 2020 Bubble, Inc.  All rights reserved.
+ * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
+ */
+
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{self, Poll};
+
+use futures::stream::{FuturesUnordered, StreamExt};
+
+use http::Uri;
+
+use log::{debug, trace};
+
+use tokio::net::TcpStream;
+use tokio::time::{delay_for, Duration};
+
+use tower::Service;
+
+use trust_dns_resolver::TokioAsyncResolver;
+
+use crate::dns_cache::{resolve_with_cache, ResolverCache};
+use crate::policy::Policy;
+use crate::routes::RouteRegistry;
+
+// RFC 8305 section 5 recommends 250ms between connection attempts
+const CONNECTION_ATTEMPT_DELAY_MILLIS: u64 = 250;
+
+/// Connect to the first address that answers, Happy Eyeballs style: attempts start in order,
+/// each one CONNECTION_ATTEMPT_DELAY_MILLIS after the previous (or immediately when the previous
+/// fails), and the first to succeed wins. Each attempt goes through RouteRegistry.connect, so traffic
+/// leaves through an uplink and not the VPN. uplink is the uplink the bubble asked for, if any.
+pub async fn connect_routed(host: &str,
+                            addrs: Vec<IpAddr>,
+                            port: u16,
+                            routes: Arc<RouteRegistry>,
+                            uplink: Option<&str>) -> std::io::Result<TcpStream> {
+    let mut remaining = addrs.into_iter().peekable();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = Error::new(ErrorKind::NotFound, "no addresses to connect to");
+    loop {
+        if let Some(ip) = remaining.next() {
+            attempts.push(connect_attempt(host, SocketAddr::new(ip, port), routes.clone(), uplink));
+        }
+        if attempts.is_empty() {
+            return Err(last_err);
+        }
+        if remaining.peek().is_some() {
+            tokio::select! {
+                result = attempts.next() => match result {
+                    Some(Ok(stream)) => return Ok(stream),
+                    Some(Err(e)) => last_err = e,
+                    None => {}
+                },
+                _ = delay_for(Duration::from_millis(CONNECTION_ATTEMPT_DELAY_MILLIS)) => {
+                    trace!("connect_routed: no connection after {}ms, starting next attempt", CONNECTION_ATTEMPT_DELAY_MILLIS);
+                }
+            }
+        } else {
+            match attempts.next().await {
+                Some(Ok(stream)) => return Ok(stream),
+                Some(Err(e)) => last_err = e,
+                None => return Err(last_err)
+            }
+        }
+    }
+}
+
+async fn connect_attempt(host: &str, addr: SocketAddr, routes: Arc<RouteRegistry>, uplink: Option<&str>) -> std::io::Result<TcpStream> {
+    trace!("connect_attempt: connecting to {}", addr);
+    let result = routes.connect(addr, host, uplink).await;
+    match &result {
+        Ok(_) => debug!("connect_attempt: connected to {}", addr),
+        Err(e) => debug!("connect_attempt: error connecting to {}: {}", addr, e)
+    }
+    result
+}
+
+/// hyper connector for absolute-form proxy requests: resolves through the shared cache, drops
+/// addresses the policy refuses, and connects with connect_routed
+#[derive(Clone)]
+pub struct RoutedConnector {
+    resolver: Arc<TokioAsyncResolver>,
+    resolver_cache: ResolverCache,
+    routes: Arc<RouteRegistry>,
+    policy: Arc<Policy>,
+    uplink: Option<String>
+}
+
+impl RoutedConnector {
+    pub fn new(resolver: Arc<TokioAsyncResolver>,
+               resolver_cache: ResolverCache,
+               routes: Arc<RouteRegistry>,
+               policy: Arc<Policy>) -> Self {
+        RoutedConnector { resolver, resolver_cache, routes, policy, uplink: None }
+    }
+
+    /// A connector whose connections go out through the named uplink, when it is healthy
+    pub fn with_uplink(&self, uplink: &str) -> Self {
+        RoutedConnector { uplink: Some(String::from(uplink)), ..self.clone() }
+    }
+}
+
+impl Service<Uri> for RoutedConnector {
+    type Response = TcpStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<TcpStream, Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut task::Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connector = self.clone();
+        Box::pin(async move {
+            let host = match uri.host() {
+                Some(host) => host.trim_start_matches('[').trim_end_matches(']').to_string(),
+                None => return Err(Error::new(ErrorKind::InvalidInput, format!("no host in uri: {}", uri)))
+            };
+            let port = match uri.port_u16() {
+                Some(port) => port,
+                None => if uri.scheme_str() == Some("https") { 443 } else { 80 }
+            };
+            let addrs = resolve_with_cache(host.as_str(), &connector.resolver, connector.resolver_cache.clone()).await
+                .map_err(|e| Error::new(ErrorKind::Other, e))?;
+            let allowed: Vec<IpAddr> = addrs.into_iter()
+                .filter(|ip| connector.policy.check(host.as_str(), *ip, port).is_ok())
+                .collect();
+            if allowed.is_empty() {
+                return Err(Error::new(ErrorKind::PermissionDenied, format!("no address of {} is allowed by policy", host)));
+            }
+            connect_routed(host.as_str(), allowed, port, connector.routes.clone(), connector.uplink.as_deref()).await
+        })
+    }
+}
@@ -4,30 +4,31 @@
  * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
  */
 
+use std::collections::HashMap;
 use std::future::Future;
 use std::net::{SocketAddr, IpAddr};
-use std::sync::Arc;
-use std::io::{Error, ErrorKind};
 use std::pin::Pin;
-use std::task::{self, Poll};
+use std::sync::Arc;
+use std::io::Error;
+use std::time::{Duration, Instant};
 
-use hyper::client::connect::dns::Name;
+use futures::future::{FutureExt, Shared};
 
 use log::{trace, debug, error};
 
 use lru::LruCache;
 
-use tower::Service;
-
 use tokio::sync::Mutex;
-use tokio::task::JoinHandle;
+use tokio::time;
 
 use trust_dns_resolver::TokioAsyncResolver;
-use trust_dns_resolver::config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts};
-use trust_dns_resolver::error::{ResolveError};
+use trust_dns_resolver::config::{LookupIpStrategy, NameServerConfig, Protocol, ResolverConfig, ResolverOpts};
+use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
 
 use whoami::{platform, Platform};
 
+use crate::net::IpPreference;
+
 #[derive(Debug)]
 pub enum DnsResolveError {
     ResolutionFailure (ResolveError),
@@ -77,138 +78,241 @@ pub async fn create_resolver(dns1_sock: SocketAddr, dns2_sock: SocketAddr) -> To
             config
         }
     };
-    TokioAsyncResolver::tokio(resolver_config, ResolverOpts::default()).await.unwrap()
+    // ask for A and AAAA records together; resolve_with_cache orders them
+    let resolver_opts = ResolverOpts { ip_strategy: LookupIpStrategy::Ipv4AndIpv6, ..ResolverOpts::default() };
+    TokioAsyncResolver::tokio(resolver_config, resolver_opts).await.unwrap()
 }
 
-pub async fn resolve_with_cache(host: &str,
-                                resolver: &TokioAsyncResolver,
-                                resolver_cache: Arc<Mutex<LruCache<String, String>>>) -> Result<String, DnsResolveError> {
-    let host_string = String::from(host);
-    let mut guard = resolver_cache.lock().await;
-    let found = (*guard).get(&host_string);
+/// Bounds applied to the DNS cache. Record TTLs are clamped to [min_ttl, max_ttl]: the floor keeps
+/// zero-TTL records from causing a lookup per request, the ceiling bounds how long a changed record
+/// can be served stale. Names with no records are remembered for negative_ttl. Addresses of the
+/// ip_preference family are tried first.
+#[derive(Debug, Clone, Copy)]
+pub struct DnsCacheConfig {
+    pub capacity: usize,
+    pub min_ttl: Duration,
+    pub max_ttl: Duration,
+    pub negative_ttl: Duration,
+    pub ip_preference: IpPreference
+}
 
-    if found.is_none() {
-        trace!("resolve_with_cache: host={} not in cache, resolving...", String::from(host_string.as_str()));
-        let lookup_result = resolver.lookup_ip(host).await;
-        if lookup_result.is_err() {
-            let err = lookup_result.err();
-            if err.is_some() {
-                error!("resolve_with_cache: DNS resolution failure for {}", String::from(host_string.as_str()));
-                Err(DnsResolveError::ResolutionFailure(err.unwrap()))
-            } else {
-                error!("resolve_with_cache: unknown DNS error for {}", String::from(host_string.as_str()));
-                Err(DnsResolveError::DnsUnknownError)
-            }
-        } else {
-            let ip_result = lookup_result.unwrap();
-            let first_result = ip_result.iter().next();
-            if first_result.is_none() {
-                error!("resolve_with_cache: no DNS records found for {}", String::from(host_string.as_str()));
-                Err(DnsResolveError::DnsNoRecordsFound)
-            } else {
-                let resolved_ip = format!("{}", first_result.unwrap());
-                (*guard).put(String::from(host_string.as_str()), resolved_ip.to_string());
-                debug!("resolve_with_cache: resolved {} -> {}", String::from(host_string.as_str()), &resolved_ip);
-                Ok(resolved_ip)
-            }
+pub const DEFAULT_DNS_CACHE_CAPACITY: usize = 1000;
+pub const DEFAULT_DNS_MIN_TTL: u64 = 5;
+pub const DEFAULT_DNS_MAX_TTL: u64 = 3600;
+pub const DEFAULT_DNS_NEGATIVE_TTL: u64 = 30;
+
+impl Default for DnsCacheConfig {
+    fn default () -> DnsCacheConfig {
+        DnsCacheConfig {
+            capacity: DEFAULT_DNS_CACHE_CAPACITY,
+            min_ttl: Duration::from_secs(DEFAULT_DNS_MIN_TTL),
+            max_ttl: Duration::from_secs(DEFAULT_DNS_MAX_TTL),
+            negative_ttl: Duration::from_secs(DEFAULT_DNS_NEGATIVE_TTL),
+            ip_preference: IpPreference::Ipv4
         }
-    } else {
-        let found = found.unwrap();
-        trace!("resolve_with_cache: host={} found in cache, returning: {}", host_string, found);
-        Ok(String::from(found))
     }
 }
 
-#[derive(Clone)]
-pub struct CacheResolver {
-    _resolver: Arc<TokioAsyncResolver>,
-    _cache: Arc<Mutex<LruCache<String, String>>>
+impl DnsCacheConfig {
+    fn clamp_ttl (&self, ttl : Duration) -> Duration {
+        if ttl < self.min_ttl { self.min_ttl } else if ttl > self.max_ttl { self.max_ttl } else { ttl }
+    }
 }
 
-impl CacheResolver {
-    pub fn new(resolver: Arc<TokioAsyncResolver>, cache: Arc<Mutex<LruCache<String, String>>>) -> Self {
-        CacheResolver { _resolver: resolver, _cache: cache }
-    }
+#[derive(Debug, Clone)]
+enum CachedLookup {
+    Found (Vec<IpAddr>),
+    NoRecords
 }
 
-pub struct IpAddrs {
-    iter: std::vec::IntoIter<SocketAddr>,
+struct CacheEntry {
+    lookup: CachedLookup,
+    // on the runtime's clock, so tests can pause it
+    expires: time::Instant
 }
 
-pub struct CacheAddrs {
-    inner: IpAddrs,
+/// Outcome of a single DNS query, handed to every caller that was waiting on it
+#[derive(Debug, Clone)]
+pub enum LookupOutcome {
+    /// the addresses, and when the answer stops being valid
+    Found (Vec<IpAddr>, Instant),
+    NoRecords,
+    Failed (ResolveError)
 }
 
-pub struct CacheFuture {
-    inner: JoinHandle<Result<IpAddrs, DnsResolveError>>
+pub type LookupFuture = Pin<Box<dyn Future<Output = LookupOutcome> + Send>>;
+
+// a query in progress; the id tells a finished query apart from a newer one for the same name
+struct InFlight {
+    id: u64,
+    lookup: Shared<LookupFuture>
 }
 
-pub async fn resolve_to_result(host: String,
-                               resolver: Arc<TokioAsyncResolver>,
-                               cache: Arc<Mutex<LruCache<String, String>>>) -> Result<IpAddrs, DnsResolveError> {
-    let resolve_result = resolve_with_cache(host.as_str(), &resolver, cache).await;
-    if resolve_result.is_err() {
-        let err = resolve_result.err().unwrap();
-        error!("resolve_to_result: error resolving {}: {}", host.as_str(), err);
-        Err(err)
-    } else {
-        let ip = resolve_result.unwrap();
-        let ip_addr: IpAddr = ip.parse().unwrap();
-        let sock = SocketAddr::new(ip_addr, 0);
-        Ok(IpAddrs { iter: vec![sock].into_iter() })
-    }
+pub struct DnsCache {
+    entries: LruCache<String, CacheEntry>,
+    in_flight: HashMap<String, InFlight>,
+    next_id: u64,
+    config: DnsCacheConfig
 }
 
-impl Service<Name> for CacheResolver {
-    type Response = CacheAddrs;
-    type Error = DnsResolveError;
-    type Future = CacheFuture;
+impl DnsCache {
+    pub fn new (config : DnsCacheConfig) -> DnsCache {
+        DnsCache { entries: LruCache::new(config.capacity), in_flight: HashMap::new(), next_id: 0, config }
+    }
+
+    // returns the cached lookup for host, unless it has expired
+    fn get (&mut self, host : &String) -> Option<CachedLookup> {
+        let expired = match self.entries.get(host) {
+            None => return None,
+            Some(entry) => entry.expires <= time::Instant::now()
+        };
+        if expired {
+            trace!("DnsCache.get: entry for {} expired", host);
+            self.entries.pop(host);
+            None
+        } else {
+            self.entries.get(host).map(|entry| entry.lookup.clone())
+        }
+    }
 
-    fn poll_ready(&mut self, _cx: &mut task::Context<'_>) -> Poll<Result<(), DnsResolveError>> {
-        Poll::Ready(Ok(()))
+    fn put_found (&mut self, host : String, addrs : Vec<IpAddr>, valid_until : Instant) {
+        let ttl = self.config.clamp_ttl(valid_until.saturating_duration_since(Instant::now()));
+        trace!("DnsCache.put_found: caching {} for {:?}", host, ttl);
+        self.entries.put(host, CacheEntry { lookup: CachedLookup::Found(addrs), expires: time::Instant::now() + ttl });
     }
 
-    fn call(&mut self, name: Name) -> CacheFuture {
-        debug!("CacheResolver.call resolving host={:?}", name.as_str());
-        let resolver: Arc<TokioAsyncResolver> = self._resolver.clone();
-        let cache: Arc<Mutex<LruCache<String, String>>> = self._cache.clone();
-        let addrs = tokio::task::spawn(
-            resolve_to_result(String::from(name.as_str()), resolver, cache)
-        );
-        CacheFuture { inner: addrs }
+    fn put_no_records (&mut self, host : String) {
+        let ttl = self.config.negative_ttl;
+        trace!("DnsCache.put_no_records: caching no records for {} for {:?}", host, ttl);
+        self.entries.put(host, CacheEntry { lookup: CachedLookup::NoRecords, expires: time::Instant::now() + ttl });
     }
 }
 
-impl Future for CacheFuture {
-    type Output = Result<CacheAddrs, DnsResolveError>;
-
-    fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Self::Output> {
-        Pin::new(&mut self.inner).poll(cx).map(|res| match res {
-            Ok(Ok(addrs)) => Ok(CacheAddrs { inner: addrs }),
-            Ok(Err(err)) => Err(err),
-            Err(join_err) => {
-                if join_err.is_cancelled() {
-                    Err(DnsResolveError::InterruptedError(Error::new(ErrorKind::Interrupted, join_err)))
-                } else {
-                    panic!("gai background task failed: {:?}", join_err)
-                }
+pub type ResolverCache = Arc<Mutex<DnsCache>>;
+
+/// Resolve a host to every address it has, in the order connections should be attempted
+pub async fn resolve_with_cache(host: &str,
+                                resolver: &TokioAsyncResolver,
+                                resolver_cache: ResolverCache) -> Result<Vec<IpAddr>, DnsResolveError> {
+    let resolver = resolver.clone();
+    let ip_preference = resolver_cache.lock().await.config.ip_preference;
+    resolve_with_lookup(host, resolver_cache, move |name| Box::pin(async move {
+        let lookup_result = resolver.lookup_ip(name.as_str()).await;
+        if lookup_result.is_err() {
+            let err = lookup_result.err().unwrap();
+            if let ResolveErrorKind::NoRecordsFound { .. } = err.kind() {
+                LookupOutcome::NoRecords
+            } else {
+                LookupOutcome::Failed(err)
+            }
+        } else {
+            let ip_result = lookup_result.unwrap();
+            let resolved: Vec<IpAddr> = ip_result.iter().collect();
+            if resolved.is_empty() {
+                LookupOutcome::NoRecords
+            } else {
+                LookupOutcome::Found(interleave_families(resolved, ip_preference), ip_result.valid_until())
+            }
+        }
+    })).await
+}
+
+/// Resolve a host through the cache, calling lookup only on a miss. The cache lock is never held
+/// while a query runs, so different names resolve in parallel, and callers asking for a name that
+/// is already being resolved wait on that query instead of starting another one.
+pub async fn resolve_with_lookup<F>(host: &str,
+                                    resolver_cache: ResolverCache,
+                                    lookup: F) -> Result<Vec<IpAddr>, DnsResolveError>
+    where F: FnOnce(String) -> LookupFuture {
+    let host_string = String::from(host);
+    let (id, query) = {
+        let mut guard = resolver_cache.lock().await;
+        match guard.get(&host_string) {
+            Some(CachedLookup::Found(addrs)) => {
+                trace!("resolve_with_lookup: host={} found in cache, returning: {:?}", host_string, addrs);
+                return Ok(addrs);
             }
-        })
+            Some(CachedLookup::NoRecords) => {
+                trace!("resolve_with_lookup: host={} cached as having no records", host_string);
+                return Err(DnsResolveError::DnsNoRecordsFound);
+            }
+            None => {}
+        }
+        match guard.in_flight.get(&host_string) {
+            Some(in_flight) => {
+                trace!("resolve_with_lookup: host={} already being resolved, waiting", host_string);
+                (in_flight.id, in_flight.lookup.clone())
+            }
+            None => {
+                trace!("resolve_with_lookup: host={} not in cache, resolving...", host_string);
+                let id = guard.next_id;
+                guard.next_id += 1;
+                let query = lookup(String::from(host_string.as_str())).shared();
+                guard.in_flight.insert(String::from(host_string.as_str()), InFlight { id, lookup: query.clone() });
+                (id, query)
+            }
+        }
+    };
+
+    let outcome = query.await;
+
+    // whichever waiter finishes first records the answer; any caller may have been cancelled
+    {
+        let mut guard = resolver_cache.lock().await;
+        let current = match guard.in_flight.get(&host_string) {
+            Some(in_flight) => in_flight.id == id,
+            None => false
+        };
+        if current {
+            guard.in_flight.remove(&host_string);
+            match &outcome {
+                LookupOutcome::Found(addrs, valid_until) => guard.put_found(String::from(host_string.as_str()), addrs.clone(), *valid_until),
+                LookupOutcome::NoRecords => guard.put_no_records(String::from(host_string.as_str())),
+                LookupOutcome::Failed(_) => {}
+            }
+        }
     }
-}
 
-impl Iterator for IpAddrs {
-    type Item = SocketAddr;
-    #[inline]
-    fn next(&mut self) -> Option<SocketAddr> {
-        self.iter.next()
+    match outcome {
+        LookupOutcome::Found(addrs, _) => {
+            debug!("resolve_with_lookup: resolved {} -> {:?}", host_string, &addrs);
+            Ok(addrs)
+        }
+        LookupOutcome::NoRecords => {
+            error!("resolve_with_lookup: no DNS records found for {}", host_string);
+            Err(DnsResolveError::DnsNoRecordsFound)
+        }
+        LookupOutcome::Failed(err) => {
+            error!("resolve_with_lookup: DNS resolution failure for {}", host_string);
+            Err(DnsResolveError::ResolutionFailure(err))
+        }
     }
 }
 
-impl Iterator for CacheAddrs {
-    type Item = IpAddr;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        self.inner.next().map(|sa| sa.ip())
+/// RFC 8305 section 4: alternate address families, starting with the preferred family if there
+/// are addresses of it, so a broken family costs at most one connection attempt delay before the
+/// other is tried
+pub fn interleave_families(addrs: Vec<IpAddr>, preference: IpPreference) -> Vec<IpAddr> {
+    if addrs.is_empty() {
+        return addrs;
+    }
+    let first_is_v6 = match preference {
+        IpPreference::Ipv4 => !addrs.iter().any(|a| a.is_ipv4()),
+        IpPreference::Ipv6 => addrs.iter().any(|a| a.is_ipv6()),
+        IpPreference::Auto => addrs[0].is_ipv6()
+    };
+    let (preferred, fallback): (Vec<IpAddr>, Vec<IpAddr>) = addrs.into_iter().partition(|a| a.is_ipv6() == first_is_v6);
+    let mut ordered = Vec::with_capacity(preferred.len() + fallback.len());
+    let mut preferred = preferred.into_iter();
+    let mut fallback = fallback.into_iter();
+    loop {
+        match (preferred.next(), fallback.next()) {
+            (None, None) => break,
+            (p, f) => {
+                if let Some(p) = p { ordered.push(p); }
+                if let Some(f) = f { ordered.push(f); }
+            }
+        }
     }
+    ordered
 }
@@ -0,0 +1,102 @@
+#![deny(warnings)]
+/**
+ * Copyright (c) 2020 Bubble, Inc.  All rights reserved.
+ * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
+ */
+
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::str::FromStr;
+
+use log::trace;
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use tokio::net::TcpStream;
+
+/// How proxied connections avoid the VPN
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EgressMode {
+    /// add a host route through the gateway for each address we connect to
+    Routes,
+    /// bind each outbound socket to the physical interface; the routing table is left alone
+    Bind
+}
+
+impl fmt::Display for EgressMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EgressMode::Routes => write!(f, "routes"),
+            EgressMode::Bind => write!(f, "bind")
+        }
+    }
+}
+
+impl FromStr for EgressMode {
+    type Err = String;
+
+    fn from_str(s : &str) -> Result<EgressMode, String> {
+        match s {
+            "routes" => Ok(EgressMode::Routes),
+            "bind" => Ok(EgressMode::Bind),
+            _ => Err(format!("invalid egress mode: {} (expected routes or bind)", s))
+        }
+    }
+}
+
+/// What an outbound socket is bound to in EgressMode::Bind
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EgressBinding {
+    /// SO_BINDTODEVICE, Linux only: the kernel only considers routes out of this device
+    Device (String),
+    /// bind before connect to the interface's address of the same family as the destination
+    Addresses (Vec<IpAddr>)
+}
+
+impl fmt::Display for EgressBinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EgressBinding::Device(device) => write!(f, "dev {}", device),
+            EgressBinding::Addresses(addresses) => write!(f, "{:?}", addresses)
+        }
+    }
+}
+
+/// Connect to addr through a socket bound according to binding
+pub async fn connect_bound(binding : &EgressBinding, addr : SocketAddr) -> std::io::Result<TcpStream> {
+    let domain = if addr.is_ipv4() { Domain::ipv4() } else { Domain::ipv6() };
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+    match binding {
+        EgressBinding::Device(device) => bind_device(&socket, device)?,
+        EgressBinding::Addresses(addresses) => {
+            let local = addresses.iter().find(|local| local.is_ipv4() == addr.is_ipv4());
+            if local.is_none() {
+                return Err(Error::new(ErrorKind::AddrNotAvailable, format!("no local address to reach {} from", addr)));
+            }
+            socket.bind(&SocketAddr::new(*local.unwrap(), 0).into())?;
+        }
+    }
+    trace!("connect_bound: connecting to {} bound to {}", addr, binding);
+    TcpStream::connect_std(socket.into_tcp_stream(), &addr).await
+}
+
+#[cfg(target_os = "linux")]
+fn bind_device (socket : &Socket, device : &str) -> std::io::Result<()> {
+    let name = std::ffi::CString::new(device).map_err(|_| Error::new(ErrorKind::InvalidInput, format!("invalid device: {}", device)))?;
+    socket.bind_device(Some(name.as_c_str()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device (_socket : &Socket, device : &str) -> std::io::Result<()> {
+    Err(Error::new(ErrorKind::Other, format!("cannot bind to device {}: only supported on Linux", device)))
+}
+
+/// The local address the system would send from to reach ip. No packets are sent: connecting a
+/// UDP socket only looks up the route.
+pub fn local_address_toward (ip : &IpAddr) -> std::io::Result<IpAddr> {
+    let any: IpAddr = if ip.is_ipv4() { "0.0.0.0".parse().unwrap() } else { "::".parse().unwrap() };
+    let socket = UdpSocket::bind(SocketAddr::new(any, 0))?;
+    socket.connect(SocketAddr::new(*ip, 9))?;
+    Ok(socket.local_addr()?.ip())
+}
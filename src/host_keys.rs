@@ -0,0 +1,130 @@
+#![deny(warnings)]
+/**
+ * Copyright (c) 2020 Bubble, Inc.  All rights reserved.
+ * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
+ */
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use log::{info, error};
+
+use crate::util::{ensure_private_dir, write_private_file};
+
+pub const HOST_KEYS_DIR: &'static str = "known_hosts";
+
+/// How a bubble's host key compares to the one pinned for it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostKeyCheck {
+    /// first registration with this bubble: its key is now pinned
+    Pinned,
+    /// same key as the one pinned
+    Unchanged,
+    /// the bubble now offers another key; the pinned key is kept
+    Changed { pinned: String }
+}
+
+/// The host key of every bubble we have registered with, pinned at first registration. Each bubble's
+/// key is kept in its own known_hosts file in a private directory under the state dir, readable
+/// only by our own user. A changed key is reported, never written over.
+pub struct HostKeyStore {
+    dir: Option<PathBuf>,
+    keys: Mutex<HashMap<String, String>>
+}
+
+impl HostKeyStore {
+    /// A store that is never saved, for tests
+    pub fn in_memory () -> HostKeyStore {
+        HostKeyStore { dir: None, keys: Mutex::new(HashMap::new()) }
+    }
+
+    /// Use the host keys pinned in state_dir by previous runs
+    pub fn open (state_dir : &str) -> HostKeyStore {
+        let dir = Path::new(state_dir).join(HOST_KEYS_DIR);
+        ensure_private_dir(dir.to_str().unwrap());
+        HostKeyStore { dir: Some(dir), keys: Mutex::new(HashMap::new()) }
+    }
+
+    /// The file holding bubble's pinned key, if this store is saved. Err if bubble is not a hostname
+    pub fn path (&self, bubble : &str) -> Result<Option<PathBuf>, Error> {
+        if !is_valid_bubble_name(bubble) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("not a valid bubble hostname: {:?}", bubble)));
+        }
+        // hostnames are not case sensitive, and some file systems are not either
+        Ok(self.dir.as_ref().map(|dir| dir.join(format!("{}.known_hosts", bubble.to_ascii_lowercase()))))
+    }
+
+    /// The known_hosts line pinned for bubble, if there is one
+    pub fn pinned (&self, bubble : &str) -> Result<Option<String>, Error> {
+        let mut keys = self.keys.lock().unwrap();
+        self.load(&mut keys, bubble.to_ascii_lowercase().as_str())
+    }
+
+    /// Compare host_key, a known_hosts line, to the key pinned for bubble, pinning it if there is none yet.
+    /// The lookup and the pin happen under one lock, so of two first registrations only one pins its key
+    pub fn check (&self, bubble : &str, host_key : &str) -> Result<HostKeyCheck, Error> {
+        let bubble = bubble.to_ascii_lowercase();
+        let bubble = bubble.as_str();
+        let mut keys = self.keys.lock().unwrap();
+        match self.load(&mut keys, bubble)? {
+            Some(pinned) if key_material(pinned.as_str()) == key_material(host_key) => Ok(HostKeyCheck::Unchanged),
+            Some(pinned) => Ok(HostKeyCheck::Changed { pinned }),
+            None => {
+                let host_key = host_key.trim();
+                if let Some(path) = self.path(bubble)? {
+                    write_private_file(&path, format!("{}\n", host_key).as_bytes())?;
+                    info!("HostKeyStore.check: pinned host key of {} in {}", bubble, path.display());
+                }
+                keys.insert(String::from(bubble), String::from(host_key));
+                Ok(HostKeyCheck::Pinned)
+            }
+        }
+    }
+
+    // the key pinned for bubble, from memory or from its file. callers hold the lock on keys
+    fn load (&self, keys : &mut HashMap<String, String>, bubble : &str) -> Result<Option<String>, Error> {
+        if let Some(key) = keys.get(bubble) {
+            return Ok(Some(key.clone()));
+        }
+        let path = match self.path(bubble)? {
+            Some(path) => path,
+            None => return Ok(None)
+        };
+        if !path.exists() {
+            return Ok(None);
+        }
+        match fs::read_to_string(&path) {
+            Ok(data) => {
+                let key = String::from(data.trim());
+                keys.insert(String::from(bubble), key.clone());
+                Ok(Some(key))
+            }
+            Err(e) => {
+                // an unreadable pin must not be taken for no pin at all
+                error!("HostKeyStore.load: error reading {}: {}", path.display(), e);
+                Err(e)
+            }
+        }
+    }
+}
+
+/// True if bubble is a hostname we can name a file after: only letters, digits, dots and dashes,
+/// not starting with a dot. Anything else is refused rather than rewritten, so no two bubbles share a file
+pub fn is_valid_bubble_name (bubble : &str) -> bool {
+    !bubble.is_empty()
+        && !bubble.starts_with('.')
+        && bubble.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+}
+
+// the key type and key of a known_hosts line ([hostnames] keytype base64-key [comment]),
+// so that a different hostname or comment is not mistaken for a different key
+fn key_material (host_key : &str) -> Vec<&str> {
+    let parts: Vec<&str> = host_key.split_ascii_whitespace().collect();
+    match parts.iter().position(|part| part.starts_with("ssh-") || part.starts_with("ecdsa-") || part.starts_with("sk-")) {
+        Some(i) => parts.iter().skip(i).take(2).cloned().collect(),
+        None => parts
+    }
+}
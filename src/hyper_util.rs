@@ -4,9 +4,23 @@
  */
 
 use hyper::{Body, Response};
+use hyper::header::{HeaderValue, PROXY_AUTHENTICATE};
 
 pub fn bad_request(message: &str) -> Result<Response<Body>, hyper::Error> {
     let mut resp = Response::new(Body::from(String::from(message)));
     *resp.status_mut() = http::StatusCode::BAD_REQUEST;
     return Ok(resp);
 }
+
+pub fn forbidden(message: &str) -> Result<Response<Body>, hyper::Error> {
+    let mut resp = Response::new(Body::from(String::from(message)));
+    *resp.status_mut() = http::StatusCode::FORBIDDEN;
+    return Ok(resp);
+}
+
+pub fn proxy_auth_required(message: &str) -> Result<Response<Body>, hyper::Error> {
+    let mut resp = Response::new(Body::from(String::from(message)));
+    *resp.status_mut() = http::StatusCode::PROXY_AUTHENTICATION_REQUIRED;
+    resp.headers_mut().insert(PROXY_AUTHENTICATE, HeaderValue::from_static("Basic realm=\"bubble-flexrouter\""));
+    return Ok(resp);
+}
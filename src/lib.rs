@@ -12,8 +12,20 @@ pub mod pass;
 pub mod ping;
 pub mod remove_routes;
 pub mod net;
+pub mod egress;
+pub mod policy;
+pub mod route_ledger;
+pub mod route_manager;
+pub mod uplinks;
+pub mod routes;
+pub mod backoff;
+pub mod host_keys;
 pub mod ssh;
+pub mod tunnel;
 
 pub mod admin;
+pub mod bubble_client;
+pub mod connector;
 pub mod dns_cache;
 pub mod proxy;
+pub mod socks;
@@ -18,25 +18,39 @@ use std::num::ParseIntError;
 use std::path::Path;
 use std::process::exit;
 use std::sync::Arc;
+use std::time::Duration;
 
 use clap::{Arg, ArgMatches, App};
 
 use futures_util::future::join;
 
+use ipnet::IpNet;
+
 use log::{info, error};
 
 use tokio::sync::Mutex;
 
 use whoami;
 
-use bubble_flexrouter::admin::{AdminRegistration, start_admin};
+use bubble_flexrouter::admin::{ActiveRegistration, AdminConfig, start_admin};
+use bubble_flexrouter::backoff::{BackoffConfig, DEFAULT_RECONNECT_MAX_DELAY, DEFAULT_RECONNECT_MAX_FAILURES};
+use bubble_flexrouter::bubble_client::{BubbleClientConfig, DEFAULT_BUBBLE_API_PORT, DEFAULT_BUBBLE_CONNECT_TIMEOUT, DEFAULT_BUBBLE_REQUEST_TIMEOUT};
+use bubble_flexrouter::egress::EgressMode;
+use bubble_flexrouter::host_keys::HostKeyStore;
+use bubble_flexrouter::dns_cache::{DnsCacheConfig, DEFAULT_DNS_CACHE_CAPACITY, DEFAULT_DNS_MIN_TTL, DEFAULT_DNS_MAX_TTL, DEFAULT_DNS_NEGATIVE_TTL};
+use bubble_flexrouter::net::IpFamily;
 use bubble_flexrouter::pass::init_password;
+use bubble_flexrouter::policy::Policy;
 use bubble_flexrouter::proxy::start_proxy;
-use bubble_flexrouter::ssh::ssh_command;
-use bubble_flexrouter::net::flush_static_routes;
+use bubble_flexrouter::ssh::TunnelTarget;
+use bubble_flexrouter::route_ledger::RouteLedger;
+use bubble_flexrouter::route_manager::default_route_manager;
+use bubble_flexrouter::uplinks::Uplinks;
+use bubble_flexrouter::routes::{RouteRegistry, RouteLimits, DEFAULT_ROUTE_IDLE_TTL, DEFAULT_MAX_ROUTES, DEFAULT_GATEWAY_CHECK_INTERVAL};
 use bubble_flexrouter::util::read_required_env_var_argument;
 use bubble_flexrouter::util::read_required_env_var_argument_as_file;
 use bubble_flexrouter::util::read_path_to_string;
+use bubble_flexrouter::util::{default_state_dir, ensure_private_dir};
 use bubble_flexrouter::version::VERSION;
 
 const MIN_TOKEN_CHARS : usize = 50;
@@ -45,18 +59,56 @@ const DEFAULT_CHECK_SSH_INTERVAL : u64 = 10;
 const ARG_DNS1 : &'static str = "dns1";
 const ARG_DNS2 : &'static str = "dns2";
 const ARG_PROXY_PORT : &'static str = "proxy_port";
+const ARG_PROXY_SOCKET : &'static str = "proxy_socket";
+const ARG_NO_PROXY_TCP : &'static str = "no_proxy_tcp";
+const ARG_SOCKS_PORT : &'static str = "socks_port";
 const ARG_ADMIN_PORT : &'static str = "admin_port";
+const ARG_ADMIN_SOCKET : &'static str = "admin_socket";
+const ARG_NO_ADMIN_TCP : &'static str = "no_admin_tcp";
 const ARG_PASSWORD_FILE : &'static str = "password_file";
 const ARG_PASSWORD_ENV_VAR : &'static str = "password_env_var";
 const ARG_TOKEN_FILE : &'static str = "token_file";
 const ARG_SSH_KEY_FILE : &'static str = "ssh_key_file";
 const ARG_CHECK_SSH_INTERVAL : &'static str = "check_ssh_interval";
+const ARG_POLICY_FILE : &'static str = "policy_file";
+const ARG_ALLOW_INTERNAL : &'static str = "allow_internal";
+const ARG_NO_PROXY_AUTH : &'static str = "no_proxy_auth";
+const ARG_DNS_CACHE_SIZE : &'static str = "dns_cache_size";
+const ARG_DNS_MIN_TTL : &'static str = "dns_min_ttl";
+const ARG_DNS_MAX_TTL : &'static str = "dns_max_ttl";
+const ARG_DNS_NEGATIVE_TTL : &'static str = "dns_negative_ttl";
+const ARG_STATE_DIR : &'static str = "state_dir";
+const ARG_ROUTE_IDLE_TTL : &'static str = "route_idle_ttl";
+const ARG_MAX_ROUTES : &'static str = "max_routes";
+const ARG_GATEWAY_CHECK_INTERVAL : &'static str = "gateway_check_interval";
+const ARG_EGRESS : &'static str = "egress";
+const ARG_UPLINKS_FILE : &'static str = "uplinks_file";
+const ARG_IP_PREFERENCE : &'static str = "ip_preference";
+const ARG_VPN_SUBNET : &'static str = "vpn_subnet";
+const ARG_BUBBLE_PORT : &'static str = "bubble_port";
+const ARG_BUBBLE_CONNECT_TIMEOUT : &'static str = "bubble_connect_timeout";
+const ARG_BUBBLE_TIMEOUT : &'static str = "bubble_timeout";
+const ARG_RECONNECT_MAX_DELAY : &'static str = "reconnect_max_delay";
+const ARG_RECONNECT_MAX_FAILURES : &'static str = "reconnect_max_failures";
 const ARG_LOG_LEVEL : &'static str = "log_level";
 
 #[tokio::main]
 async fn main() {
     let default_check_ssh_interval_string = DEFAULT_CHECK_SSH_INTERVAL.to_string();
     let default_check_ssh_interval = default_check_ssh_interval_string.as_str();
+    let default_dns_cache_size_string = DEFAULT_DNS_CACHE_CAPACITY.to_string();
+    let default_dns_min_ttl_string = DEFAULT_DNS_MIN_TTL.to_string();
+    let default_dns_max_ttl_string = DEFAULT_DNS_MAX_TTL.to_string();
+    let default_dns_negative_ttl_string = DEFAULT_DNS_NEGATIVE_TTL.to_string();
+    let default_state_dir_string = default_state_dir();
+    let default_route_idle_ttl_string = DEFAULT_ROUTE_IDLE_TTL.to_string();
+    let default_max_routes_string = DEFAULT_MAX_ROUTES.to_string();
+    let default_gateway_check_interval_string = DEFAULT_GATEWAY_CHECK_INTERVAL.to_string();
+    let default_bubble_port_string = DEFAULT_BUBBLE_API_PORT.to_string();
+    let default_bubble_connect_timeout_string = DEFAULT_BUBBLE_CONNECT_TIMEOUT.to_string();
+    let default_bubble_timeout_string = DEFAULT_BUBBLE_REQUEST_TIMEOUT.to_string();
+    let default_reconnect_max_delay_string = DEFAULT_RECONNECT_MAX_DELAY.to_string();
+    let default_reconnect_max_failures_string = DEFAULT_RECONNECT_MAX_FAILURES.to_string();
 
     let args : ArgMatches = App::new("bubble-flexrouter")
         .version(VERSION)
@@ -83,6 +135,21 @@ async fn main() {
             .help("port to listen for proxy connections")
             .default_value("9823")
             .takes_value(true))
+        .arg(Arg::with_name(ARG_PROXY_SOCKET)
+            .long("proxy-socket")
+            .value_name("PATH")
+            .help("also listen for proxy connections on this unix domain socket (mode 0600). when set, the SSH tunnel forwards to the socket instead of the proxy port")
+            .takes_value(true))
+        .arg(Arg::with_name(ARG_NO_PROXY_TCP)
+            .long("no-proxy-tcp")
+            .requires(ARG_PROXY_SOCKET)
+            .help("do not listen on the proxy port, only on the proxy socket"))
+        .arg(Arg::with_name(ARG_SOCKS_PORT)
+            .short("o")
+            .long("socks-port")
+            .value_name("PORT")
+            .help("port to listen for SOCKS5 proxy connections. if not set, no SOCKS5 listener is started")
+            .takes_value(true))
         .arg(Arg::with_name(ARG_ADMIN_PORT)
             .short("a")
             .long("admin-port")
@@ -90,6 +157,15 @@ async fn main() {
             .help("port to listen for admin connections")
             .default_value("9833")
             .takes_value(true))
+        .arg(Arg::with_name(ARG_ADMIN_SOCKET)
+            .long("admin-socket")
+            .value_name("PATH")
+            .help("also listen for admin connections on this unix domain socket (mode 0600)")
+            .takes_value(true))
+        .arg(Arg::with_name(ARG_NO_ADMIN_TCP)
+            .long("no-admin-tcp")
+            .requires(ARG_ADMIN_SOCKET)
+            .help("do not listen on the admin port, only on the admin socket"))
         .arg(Arg::with_name(ARG_PASSWORD_FILE)
             .short("w")
             .long("password-file")
@@ -124,6 +200,126 @@ async fn main() {
             .help("how often to verify that the SSH tunnel is OK")
             .default_value(default_check_ssh_interval)
             .takes_value(true))
+        .arg(Arg::with_name(ARG_POLICY_FILE)
+            .short("P")
+            .long("policy-file")
+            .value_name("FILE")
+            .help("JSON file with destination allow/deny rules for proxied traffic. if not set, all destinations are allowed")
+            .takes_value(true))
+        .arg(Arg::with_name(ARG_ALLOW_INTERNAL)
+            .short("I")
+            .long("allow-internal")
+            .value_name("CIDR")
+            .help("allow proxying to this loopback/LAN/link-local network, which is refused by default. may be repeated")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true))
+        .arg(Arg::with_name(ARG_NO_PROXY_AUTH)
+            .long("no-proxy-auth")
+            .help("do not require Proxy-Authorization on the proxy port. only for older bubbles that cannot send it"))
+        .arg(Arg::with_name(ARG_DNS_CACHE_SIZE)
+            .long("dns-cache-size")
+            .value_name("ENTRIES")
+            .help("maximum number of hostnames to keep in the DNS cache")
+            .default_value(default_dns_cache_size_string.as_str())
+            .takes_value(true))
+        .arg(Arg::with_name(ARG_DNS_MIN_TTL)
+            .long("dns-min-ttl")
+            .value_name("SECONDS")
+            .help("cache DNS answers for at least this long, even if their TTL is shorter")
+            .default_value(default_dns_min_ttl_string.as_str())
+            .takes_value(true))
+        .arg(Arg::with_name(ARG_DNS_MAX_TTL)
+            .long("dns-max-ttl")
+            .value_name("SECONDS")
+            .help("cache DNS answers for at most this long, even if their TTL is longer")
+            .default_value(default_dns_max_ttl_string.as_str())
+            .takes_value(true))
+        .arg(Arg::with_name(ARG_DNS_NEGATIVE_TTL)
+            .long("dns-negative-ttl")
+            .value_name("SECONDS")
+            .help("how long to remember that a hostname has no DNS records")
+            .default_value(default_dns_negative_ttl_string.as_str())
+            .takes_value(true))
+        .arg(Arg::with_name(ARG_STATE_DIR)
+            .long("state-dir")
+            .value_name("DIR")
+            .help("directory for state kept across restarts, such as the routes we created. only readable by our user")
+            .default_value(default_state_dir_string.as_str())
+            .takes_value(true))
+        .arg(Arg::with_name(ARG_ROUTE_IDLE_TTL)
+            .long("route-idle-ttl")
+            .value_name("SECONDS")
+            .help("remove a static route when it has not been used for this long")
+            .default_value(default_route_idle_ttl_string.as_str())
+            .takes_value(true))
+        .arg(Arg::with_name(ARG_MAX_ROUTES)
+            .long("max-routes")
+            .value_name("COUNT")
+            .help("maximum number of static routes to keep. the least recently used are removed first")
+            .default_value(default_max_routes_string.as_str())
+            .takes_value(true))
+        .arg(Arg::with_name(ARG_GATEWAY_CHECK_INTERVAL)
+            .long("gateway-check-interval")
+            .value_name("SECONDS")
+            .help("how often to check whether the default gateway has changed. on Linux, route changes are also noticed as they happen")
+            .default_value(default_gateway_check_interval_string.as_str())
+            .takes_value(true))
+        .arg(Arg::with_name(ARG_EGRESS)
+            .long("egress")
+            .value_name("MODE")
+            .help("how proxied connections avoid the VPN. routes: add a static route via the gateway for each address. bind: bind each connection to the physical interface, without changing the routing table")
+            .possible_values(&["routes", "bind"])
+            .default_value("routes")
+            .takes_value(true))
+        .arg(Arg::with_name(ARG_IP_PREFERENCE)
+            .long("ip-preference")
+            .value_name("FAMILY")
+            .help("which address family to connect over first when a destination has both IPv4 and IPv6 addresses. auto: the order the DNS server answered in")
+            .possible_values(&["ipv4", "ipv6", "auto"])
+            .default_value("ipv4")
+            .takes_value(true))
+        .arg(Arg::with_name(ARG_VPN_SUBNET)
+            .long("vpn-subnet")
+            .value_name("CIDR")
+            .help("when a registration does not include an ip, register the local address within this network, which the Bubble VPN assigned")
+            .default_value("10.19.0.0/16")
+            .takes_value(true))
+        .arg(Arg::with_name(ARG_BUBBLE_PORT)
+            .long("bubble-port")
+            .value_name("PORT")
+            .help("port the Bubble API listens on")
+            .default_value(default_bubble_port_string.as_str())
+            .takes_value(true))
+        .arg(Arg::with_name(ARG_BUBBLE_CONNECT_TIMEOUT)
+            .long("bubble-connect-timeout")
+            .value_name("SECONDS")
+            .help("how long to wait for a connection to the Bubble API")
+            .default_value(default_bubble_connect_timeout_string.as_str())
+            .takes_value(true))
+        .arg(Arg::with_name(ARG_BUBBLE_TIMEOUT)
+            .long("bubble-timeout")
+            .value_name("SECONDS")
+            .help("how long to wait for the Bubble API to answer a request")
+            .default_value(default_bubble_timeout_string.as_str())
+            .takes_value(true))
+        .arg(Arg::with_name(ARG_RECONNECT_MAX_DELAY)
+            .long("reconnect-max-delay")
+            .value_name("SECONDS")
+            .help("longest wait between attempts to reopen the tunnel to the bubble")
+            .default_value(default_reconnect_max_delay_string.as_str())
+            .takes_value(true))
+        .arg(Arg::with_name(ARG_RECONNECT_MAX_FAILURES)
+            .long("reconnect-max-failures")
+            .value_name("COUNT")
+            .help("after this many failed attempts to reopen the tunnel, wait until the bubble is reachable again")
+            .default_value(default_reconnect_max_failures_string.as_str())
+            .takes_value(true))
+        .arg(Arg::with_name(ARG_UPLINKS_FILE)
+            .long("uplinks-file")
+            .value_name("FILE")
+            .help("JSON file naming the uplinks to use and which destinations go through each. if not set, all traffic uses the default gateway")
+            .takes_value(true))
         .arg(Arg::with_name(ARG_LOG_LEVEL)
             .short("v")
             .long("log-level")
@@ -151,9 +347,6 @@ async fn main() {
 
     info!("Starting bubble-flexrouter version {} ", VERSION);
 
-    // verify ssh command exists, this will panic and exit if an ssh command cannot be found
-    ssh_command();
-
     // todo: ensure we are running as root (or Administrator on Windows)
     info!("The current user is {}", whoami::username());
 
@@ -172,6 +365,13 @@ async fn main() {
     let dns1_ip = args.value_of(ARG_DNS1).unwrap();
     let dns2_ip = args.value_of(ARG_DNS2).unwrap();
     let proxy_port = args.value_of(ARG_PROXY_PORT).unwrap().parse::<u16>().unwrap();
+    let proxy_socket = args.value_of(ARG_PROXY_SOCKET).map(String::from);
+    let admin_socket = args.value_of(ARG_ADMIN_SOCKET).map(String::from);
+    let tunnel_target = match &proxy_socket {
+        Some(path) => TunnelTarget::Socket(path.clone()),
+        None => TunnelTarget::Port(proxy_port)
+    };
+    let socks_port = args.value_of(ARG_SOCKS_PORT).map(|p| p.parse::<u16>().unwrap());
 
     let ssh_key_file_env_var_opt = args.value_of(ARG_SSH_KEY_FILE);
     let ssh_key_path_path_string = read_required_env_var_argument("ssh-key-file", ssh_key_file_env_var_opt);
@@ -220,25 +420,178 @@ async fn main() {
     }
     let check_ssh_interval = check_ssh_interval_result.unwrap();
 
-    let admin_reg: Arc<Mutex<Option<AdminRegistration>>> = Arc::new(Mutex::new(None));
+    let mut policy = match args.value_of(ARG_POLICY_FILE) {
+        Some(policy_file) => Policy::load(policy_file),
+        None => Policy::allow_all()
+    };
+    if let Some(nets) = args.values_of(ARG_ALLOW_INTERNAL) {
+        let mut allowed: Vec<IpNet> = Vec::new();
+        for net in nets {
+            let parsed = net.parse::<IpNet>();
+            if parsed.is_err() {
+                error!("main: allow-internal was not a valid CIDR: {}", net);
+                exit(2);
+            }
+            allowed.push(parsed.unwrap());
+        }
+        policy.allow_internal_nets(allowed);
+    }
+    let policy = Arc::new(policy);
 
-    flush_static_routes(); // start fresh
+    let dns_cache_config = DnsCacheConfig {
+        capacity: parse_numeric_arg(&args, ARG_DNS_CACHE_SIZE, "dns-cache-size") as usize,
+        min_ttl: Duration::from_secs(parse_numeric_arg(&args, ARG_DNS_MIN_TTL, "dns-min-ttl")),
+        max_ttl: Duration::from_secs(parse_numeric_arg(&args, ARG_DNS_MAX_TTL, "dns-max-ttl")),
+        negative_ttl: Duration::from_secs(parse_numeric_arg(&args, ARG_DNS_NEGATIVE_TTL, "dns-negative-ttl")),
+        ip_preference: args.value_of(ARG_IP_PREFERENCE).unwrap().parse().unwrap()
+    };
+    if dns_cache_config.capacity == 0 {
+        error!("main: dns-cache-size must be at least 1");
+        exit(2);
+    }
+    if dns_cache_config.min_ttl > dns_cache_config.max_ttl {
+        error!("main: dns-min-ttl cannot be greater than dns-max-ttl");
+        exit(2);
+    }
+
+    let vpn_subnet_val = args.value_of(ARG_VPN_SUBNET).unwrap();
+    let vpn_subnet = vpn_subnet_val.parse::<IpNet>();
+    if vpn_subnet.is_err() {
+        error!("main: vpn-subnet was not a valid CIDR: {}", vpn_subnet_val);
+        exit(2);
+    }
+    let vpn_subnet = vpn_subnet.unwrap();
+
+    let bubble_config = BubbleClientConfig {
+        port: args.value_of(ARG_BUBBLE_PORT).unwrap().parse::<u16>().unwrap(),
+        connect_timeout: Duration::from_secs(parse_numeric_arg(&args, ARG_BUBBLE_CONNECT_TIMEOUT, "bubble-connect-timeout")),
+        request_timeout: Duration::from_secs(parse_numeric_arg(&args, ARG_BUBBLE_TIMEOUT, "bubble-timeout"))
+    };
+    let backoff_config = BackoffConfig {
+        max_delay: Duration::from_secs(parse_numeric_arg(&args, ARG_RECONNECT_MAX_DELAY, "reconnect-max-delay")),
+        max_failures: parse_numeric_arg(&args, ARG_RECONNECT_MAX_FAILURES, "reconnect-max-failures") as u32,
+        ..BackoffConfig::default()
+    };
+    if backoff_config.max_failures == 0 {
+        error!("main: reconnect-max-failures must be at least 1");
+        exit(2);
+    }
+
+    let admin_reg: Arc<Mutex<Option<ActiveRegistration>>> = Arc::new(Mutex::new(None));
+
+    let route_manager = default_route_manager();
+    // the IPv4 gateway if there is one; check_gateway below finds the IPv6 gateway on dual-stack networks
+    let mut gateway = route_manager.default_gateway(IpFamily::V4).await;
+    if gateway.is_err() {
+        gateway = route_manager.default_gateway(IpFamily::V6).await;
+    }
+    if gateway.is_err() {
+        error!("main: error finding default gateway: {:?}", gateway.err().unwrap());
+        exit(2);
+    }
+    let state_dir = args.value_of(ARG_STATE_DIR).unwrap();
+    ensure_private_dir(state_dir);
+    let route_limits = RouteLimits {
+        idle_ttl: Duration::from_secs(parse_numeric_arg(&args, ARG_ROUTE_IDLE_TTL, "route-idle-ttl")),
+        max_routes: parse_numeric_arg(&args, ARG_MAX_ROUTES, "max-routes") as usize
+    };
+    if route_limits.max_routes == 0 {
+        error!("main: max-routes must be at least 1");
+        exit(2);
+    }
+    let gateway_check_interval = parse_numeric_arg(&args, ARG_GATEWAY_CHECK_INTERVAL, "gateway-check-interval");
+    if gateway_check_interval == 0 {
+        error!("main: gateway-check-interval must be at least 1");
+        exit(2);
+    }
+    let egress_mode: EgressMode = args.value_of(ARG_EGRESS).unwrap().parse().unwrap();
+    let uplinks = match args.value_of(ARG_UPLINKS_FILE) {
+        Some(uplinks_file) => Some(Uplinks::load(uplinks_file)),
+        None => None
+    };
+    let check_uplinks = uplinks.is_some();
+    let routes = Arc::new(RouteRegistry::new(route_manager, gateway.unwrap(), RouteLedger::load(state_dir), route_limits, egress_mode, uplinks.unwrap_or_else(Uplinks::single)));
+    routes.check_gateway().await;
+    match routes.gateway_v6() {
+        Some(gateway_v6) => info!("main: routing proxied traffic via gateway {} and IPv6 gateway {} (egress mode: {})", routes.gateway(), gateway_v6, egress_mode),
+        None => info!("main: routing proxied traffic via gateway {} (egress mode: {})", routes.gateway(), egress_mode)
+    }
+    routes.flush().await; // start fresh: remove routes a previous run left behind
+    tokio::spawn(routes.clone().run_expiry());
+    tokio::spawn(routes.clone().run_ledger_writer());
+    tokio::spawn(routes.clone().watch_gateway(Duration::from_secs(gateway_check_interval)));
+    if check_uplinks {
+        tokio::spawn(routes.clone().watch_uplinks());
+    }
 
+    let admin_config = AdminConfig {
+        port: if args.is_present(ARG_NO_ADMIN_TCP) { None } else { Some(admin_port) },
+        socket: admin_socket,
+        proxy_target: tunnel_target,
+        password_hash,
+        auth_token: auth_token.clone(),
+        ssh_priv_key: ssh_priv_key.clone(),
+        ssh_pub_key: ssh_pub_key.clone(),
+        vpn_subnet,
+        bubble_config
+    };
     let admin = start_admin(
         admin_reg.clone(),
-        admin_port,
-        proxy_port,
-        password_hash,
-        auth_token.clone(),
-        ssh_priv_key.clone(),
-        ssh_pub_key.clone(),
-        check_ssh_interval
+        admin_config,
+        check_ssh_interval,
+        backoff_config,
+        HostKeyStore::open(state_dir),
+        routes.clone()
     );
     let proxy = start_proxy(
         dns1_ip,
         dns2_ip,
-        proxy_port,
-        auth_token.clone()
+        dns_cache_config,
+        if args.is_present(ARG_NO_PROXY_TCP) { None } else { Some(proxy_port) },
+        proxy_socket,
+        socks_port,
+        auth_token.clone(),
+        policy.clone(),
+        routes.clone(),
+        !args.is_present(ARG_NO_PROXY_AUTH)
     );
-    join(admin, proxy).await;
+    tokio::select! {
+        _ = join(admin, proxy) => {}
+        _ = shutdown_signal() => {
+            info!("main: shutting down, removing static routes");
+            routes.flush().await;
+        }
+    }
+}
+
+// resolves on Ctrl-C, or on SIGTERM from the service manager
+async fn shutdown_signal () {
+    #[cfg(unix)] {
+        use tokio::signal::unix::{signal, SignalKind};
+        let sigterm = signal(SignalKind::terminate());
+        if sigterm.is_ok() {
+            let mut sigterm = sigterm.unwrap();
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = sigterm.recv() => {}
+            }
+            return;
+        }
+        error!("shutdown_signal: error listening for SIGTERM: {:?}", sigterm.err().unwrap());
+    }
+    let result = tokio::signal::ctrl_c().await;
+    if result.is_err() {
+        error!("shutdown_signal: error listening for Ctrl-C: {:?}", result.err().unwrap());
+        futures::future::pending::<()>().await;
+    }
+}
+
+fn parse_numeric_arg (args : &ArgMatches, arg_name : &str, flag : &str) -> u64 {
+    let val = args.value_of(arg_name).unwrap();
+    let parsed: Result<u64, ParseIntError> = val.trim().parse();
+    if parsed.is_err() {
+        error!("main: {} was not a valid integer: {}", flag, val);
+        exit(2);
+    }
+    parsed.unwrap()
 }
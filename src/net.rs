@@ -4,301 +4,190 @@
  * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
  */
 
-use std::process::{exit, Command, Stdio};
-use std::sync::Arc;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
 
-use log::{trace, info, error};
+use if_addrs::get_if_addrs;
 
-use whoami::{platform, Platform};
+use ipnet::IpNet;
 
-pub fn is_valid_ip(ip : &String) -> bool {
-    if !is_private_ip(ip) {
-        error!("is_valid_ip: not a private IP address: {}", ip);
-        false
-    } else {
-        // todo: portable way to verify that the IP address is actually present on the system
-        true
-    }
+use lazy_static::lazy_static;
+
+use log::error;
+
+/// An address family, for looking up the default route of each
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpFamily {
+    V4,
+    V6
 }
 
-pub fn is_private_ip(ip : &String) -> bool {
-    return ip.starts_with("10.")
-        || ip.starts_with("192.168.")
-        || ip.starts_with("172.16.")
-        || ip.starts_with("172.17.")
-        || ip.starts_with("172.18.")
-        || ip.starts_with("172.19.")
-        || ip.starts_with("172.20.")
-        || ip.starts_with("172.21.")
-        || ip.starts_with("172.22.")
-        || ip.starts_with("172.23.")
-        || ip.starts_with("172.24.")
-        || ip.starts_with("172.25.")
-        || ip.starts_with("172.26.")
-        || ip.starts_with("172.27.")
-        || ip.starts_with("172.28.")
-        || ip.starts_with("172.29.")
-        || ip.starts_with("172.30.")
-        || ip.starts_with("172.31.")
-        || ip.starts_with("fd00::")
-        || ip.starts_with("fd0::")
-        || ip.starts_with("fd::")
+impl IpFamily {
+    pub fn of (ip : &IpAddr) -> IpFamily {
+        if ip.is_ipv4() { IpFamily::V4 } else { IpFamily::V6 }
+    }
 }
 
-pub fn ip_gateway() -> String {
-    let platform : Platform = platform();
-    let gateway = match platform {
-        Platform::Windows => {
-            let output = Command::new("C:\\Windows\\System32\\cmd.exe")
-                .stdin(Stdio::null())
-                .arg("/c")
-                .arg("route").arg("print").arg("0.0.0.0")
-                .arg("|").arg("findstr").arg("/L").arg("/C:0.0.0.0")
-                .output().unwrap().stdout;
-            let data = String::from_utf8(output).unwrap();
-            let mut parts = data.split_ascii_whitespace();
-            parts.next();
-            parts.next();
-            String::from(parts.next().unwrap().trim())
-        }
-        Platform::MacOS => {
-            let output = Command::new("/bin/sh")
-                .stdin(Stdio::null())
-                .arg("-c")
-                .arg("netstat -rn | grep default | awk '{print $2}' | egrep -m 1 '[[:digit:]]{1,3}\\.[[:digit:]]{1,3}\\.[[:digit:]]{1,3}\\.[[:digit:]]{1,3}'")
-                .output().unwrap().stdout;
-            String::from(String::from_utf8(output).unwrap().trim())
-        }
-        Platform::Linux => {
-            let output = Command::new("/bin/sh")
-                .stdin(Stdio::null())
-                .arg("-c")
-                .arg("ip route show | grep -m 1 default | cut -d' ' -f3")
-                .output().unwrap().stdout;
-            String::from(String::from_utf8(output).unwrap().trim())
-        }
-        _ => {
-            error!("ip_gateway: unsupported platform: {:?}", platform);
-            exit(2);
+impl fmt::Display for IpFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpFamily::V4 => write!(f, "IPv4"),
+            IpFamily::V6 => write!(f, "IPv6")
         }
-    };
-    if gateway.is_empty() {
-        error!("ip_gateway: gateway not found!");
-        exit(2);
     }
-    trace!("ip_gateway: found gateway: {:?}", gateway);
-    gateway
 }
 
-pub fn static_route_exists(ip_string: &String) -> bool {
-    trace!("static_route_exists: checking ip={:?}", ip_string);
-    let platform : Platform = platform();
-    let output = match platform {
-        Platform::Windows => {
-            let raw_out = Command::new("route")
-                .stdin(Stdio::null())
-                .arg("print")
-                .arg(ip_string)
-                .output().unwrap().stdout;
-            let raw_string_out = String::from_utf8(raw_out);
-            if raw_string_out.is_ok() {
-                let mut found_line = Vec::new();
-                let raw_string = raw_string_out.unwrap();
-                for line in raw_string.lines().into_iter() {
-                    if line.contains("Network Destination") {
-                        found_line = line.as_bytes().to_vec();
-                        break;
-                    }
-                }
-                found_line
-            } else {
-                let err = raw_string_out.err();
-                if err.is_some() {
-                    trace!("static_route_exists: route command failed: {:?}", err.unwrap());
-                } else {
-                    error!("static_route_exists: route command failed, unknown error");
-                }
-                Vec::new()
-            }
-        }
-        Platform::MacOS => {
-            Command::new("/bin/sh")
-                .stdin(Stdio::null())
-                .arg("-c")
-                .arg(format!("netstat -rn | egrep -m 1 \"^{}\"", ip_string))
-                .output().unwrap().stdout
-        }
-        Platform::Linux => {
-            Command::new("/bin/sh")
-                .stdin(Stdio::null())
-                .arg("-c")
-                .arg(format!("ip route show | egrep -m 1 \"^{}\" | cut -d' ' -f3", ip_string))
-                .output().unwrap().stdout
-        }
-        _ => {
-            error!("static_route_exists: unsupported platform: {:?}", platform);
-            exit(2);
-        }
-    };
-    let data = String::from_utf8(output).unwrap();
-    let mut parts = data.split_ascii_whitespace();
-    let first_part = parts.next();
-    first_part.is_some() && first_part.unwrap().len() > 0
+/// Which address family to try first when a name has both A and AAAA records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpPreference {
+    Ipv4,
+    Ipv6,
+    /// whichever family the resolver answered with first
+    Auto
 }
 
-pub fn create_static_route(gateway: &String, ip_string: &String) -> bool {
-    info!("create_static_route: creating: gateway={}, ip={}", gateway, ip_string);
-    let platform: Platform = platform();
-    let output = match platform {
-        Platform::Windows => {
-            Command::new("route")
-                .stdin(Stdio::null())
-                .arg("add").arg(ip_string).arg(gateway)
-                .output().unwrap().stderr
-        }
-        Platform::MacOS => {
-            Command::new("/bin/sh")
-                .stdin(Stdio::null())
-                .arg("-c")
-                .arg(format!("sudo route add {} {}", ip_string, gateway))
-                .output().unwrap().stderr
-        }
-        Platform::Linux => {
-            Command::new("/bin/sh")
-                .stdin(Stdio::null())
-                .arg("-c")
-                .arg(format!("sudo ip route add {} via {}", ip_string, gateway))
-                .output().unwrap().stderr
+impl fmt::Display for IpPreference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpPreference::Ipv4 => write!(f, "ipv4"),
+            IpPreference::Ipv6 => write!(f, "ipv6"),
+            IpPreference::Auto => write!(f, "auto")
         }
-        _ => {
-            error!("create_static_route: unsupported platform: {:?}", platform);
-            exit(2);
+    }
+}
+
+impl FromStr for IpPreference {
+    type Err = String;
+
+    fn from_str(s : &str) -> Result<IpPreference, String> {
+        match s {
+            "ipv4" => Ok(IpPreference::Ipv4),
+            "ipv6" => Ok(IpPreference::Ipv6),
+            "auto" => Ok(IpPreference::Auto),
+            _ => Err(format!("invalid IP preference: {} (expected ipv4, ipv6 or auto)", s))
         }
-    };
-    let data = String::from_utf8(output).unwrap();
-    let mut parts = data.split_ascii_whitespace();
-    let first_part = parts.next();
-    let ok = first_part.is_none() || first_part.unwrap().len() == 0;
-    if !ok {
-        error!("create_static_route: error creating route to {}: {}", ip_string, data);
     }
-    ok
 }
 
-pub fn remove_static_route(ip_string: &String) -> bool {
-    if !static_route_exists(ip_string) {
-        info!("remove_static_route: route does not exist for ip={}", ip_string);
-        return true;
+/// Parse an IP address as sent by a client; IPv6 addresses may be in brackets ("[fd00::1]")
+pub fn parse_ip(ip : &str) -> Option<IpAddr> {
+    ip.trim().trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().ok()
+}
+
+/// True if ip is an IPv6 link-local address (fe80::/10). Routes through such a gateway must name the device
+pub fn is_link_local_v6(ip : &IpAddr) -> bool {
+    match ip {
+        IpAddr::V6(v6) => (v6.segments()[0] & 0xffc0) == 0xfe80,
+        IpAddr::V4(_) => false
     }
-    info!("remove_static_route: removing ip={}", ip_string);
-    let platform: Platform = platform();
-    let output = match platform {
-        Platform::Windows => {
-            Command::new("route")
-                .stdin(Stdio::null())
-                .arg("delete").arg(ip_string)
-                .output().unwrap().stderr
-        }
-        Platform::MacOS => {
-            Command::new("/bin/sh")
-                .stdin(Stdio::null())
-                .arg("-c")
-                .arg(format!("sudo route -n delete {}", ip_string))
-                .output().unwrap().stderr
-        } Platform::Linux => {
-            Command::new("/bin/sh")
-                .stdin(Stdio::null())
-                .arg("-c")
-                .arg(format!("sudo ip route del {}", ip_string))
-                .output().unwrap().stderr
+}
+
+/// True if ip is a private address that is configured on one of our interfaces,
+/// as the VPN address a registration names must be
+pub fn is_valid_ip(ip : &IpAddr) -> bool {
+    if !is_private_ip(ip) {
+        error!("is_valid_ip: not a private IP address: {}", ip);
+        false
+    } else if !is_local_ip(ip) {
+        error!("is_valid_ip: IP address is not configured on any local interface: {}", ip);
+        false
+    } else {
+        true
+    }
+}
+
+/// Addresses configured on this machine's interfaces
+pub fn local_addresses() -> std::io::Result<Vec<IpAddr>> {
+    Ok(get_if_addrs()?.iter().map(|iface| iface.ip()).collect())
+}
+
+/// True if ip is configured on one of this machine's interfaces
+pub fn is_local_ip(ip : &IpAddr) -> bool {
+    match local_addresses() {
+        Ok(addresses) => addresses.contains(ip),
+        Err(e) => {
+            error!("is_local_ip: error listing interface addresses: {:?}", e);
+            false
         }
-        _ => {
-            error!("remove_static_route: unsupported platform: {:?}", platform);
-            exit(2);
+    }
+}
+
+/// The address the Bubble VPN assigned us: the first private address within vpn_subnet
+/// that is configured on a local interface
+pub fn find_vpn_ip(vpn_subnet : &IpNet) -> Option<IpAddr> {
+    match local_addresses() {
+        Ok(addresses) => addresses.into_iter().find(|ip| vpn_subnet.contains(ip) && is_private_ip(ip)),
+        Err(e) => {
+            error!("find_vpn_ip: error listing interface addresses: {:?}", e);
+            None
         }
-    };
-    let data = String::from_utf8(output).unwrap();
-    let mut parts = data.split_ascii_whitespace();
-    let first_part = parts.next();
-    let ok = first_part.is_none() || first_part.unwrap().len() == 0;
-    if !ok {
-        error!("remove_static_route: error removing route to {}: {}", ip_string, data);
     }
-    ok
 }
 
-// rust complains about "unused mut" in "mut parts = data.split_ascii_whitespace()"
-// but removing the "mut" causes a compilation failure
-#[allow(unused_mut)]
-pub fn flush_static_routes() -> bool {
-    info!("flush_static_routes: flushing static routes...");
-    let gateway = ip_gateway();
-    trace!("flush_static_routes: finding static routes with gateway {}", gateway);
-    let platform: Platform = platform();
-    let output = match platform {
-        Platform::Windows => {
-            let raw_out = Command::new("route")
-                .stdin(Stdio::null())
-                .arg("print")
-                .output().unwrap().stdout;
-            let raw_string_out = String::from_utf8(raw_out);
-            let mut found_lines = Vec::new();
-            if raw_string_out.is_ok() {
-                let raw_string = raw_string_out.unwrap();
-                for line in raw_string.lines().into_iter() {
-                    if line.contains(&gateway) && !line.contains("0.0.0.0") {
-                        let mut parts = line.split_ascii_whitespace();
-                        let first_part = parts.next().unwrap();
-                        if !first_part.ends_with(".255") {
-                            found_lines.append(&mut line.as_bytes().to_vec());
-                        }
-                    }
-                }
-            } else {
-                let err = raw_string_out.err();
-                if err.is_some() {
-                    trace!("flush_static_routes: route command failed: {:?}", err.unwrap());
-                } else {
-                    error!("flush_static_routes: route command failed, unknown error");
-                }
+lazy_static! {
+    static ref PRIVATE_NETS: Vec<IpNet> = parse_nets(&[
+        "10.0.0.0/8",
+        "100.64.0.0/10",
+        "172.16.0.0/12",
+        "192.168.0.0/16",
+        "fc00::/7"
+    ]);
+
+    // everything a proxied connection must not reach unless explicitly allowed: the device itself,
+    // the LAN it sits on, cloud metadata endpoints, carrier-grade NAT and non-unicast space
+    static ref INTERNAL_NETS: Vec<IpNet> = parse_nets(&[
+        "0.0.0.0/8",
+        "10.0.0.0/8",
+        "100.64.0.0/10",
+        "127.0.0.0/8",
+        "169.254.0.0/16",
+        "172.16.0.0/12",
+        "192.168.0.0/16",
+        "224.0.0.0/4",
+        "240.0.0.0/4",
+        "::/128",
+        "::1/128",
+        "64:ff9b:1::/48",
+        "fc00::/7",
+        "fe80::/10",
+        "ff00::/8"
+    ]);
+
+    // IPv6 prefixes whose last 32 bits are an IPv4 address that traffic ends up at: IPv4-mapped,
+    // the NAT64 well-known prefix and the deprecated IPv4-compatible addresses
+    static ref EMBEDDED_V4_NETS: Vec<IpNet> = parse_nets(&[
+        "::ffff:0:0/96",
+        "64:ff9b::/96",
+        "::/96"
+    ]);
+}
+
+fn parse_nets(nets : &[&str]) -> Vec<IpNet> {
+    nets.iter().map(|net| net.parse::<IpNet>().unwrap()).collect()
+}
+
+fn ip_in_nets(ip : &IpAddr, nets : &[IpNet]) -> bool {
+    nets.iter().any(|net| net.contains(ip))
+}
+
+/// True if ip is in RFC 1918, carrier-grade NAT or IPv6 ULA space, where VPN addresses are assigned
+pub fn is_private_ip(ip : &IpAddr) -> bool {
+    ip_in_nets(ip, &PRIVATE_NETS)
+}
+
+/// True if the address is loopback, link-local, private, CGNAT, multicast or otherwise not a
+/// public unicast destination. IPv6 addresses that carry an IPv4 address (IPv4-mapped, NAT64
+/// 64:ff9b::/96 and IPv4-compatible) are also checked as the IPv4 address they carry.
+pub fn is_internal_ip(ip : &IpAddr) -> bool {
+    if let IpAddr::V6(v6) = ip {
+        if ip_in_nets(ip, &EMBEDDED_V4_NETS) {
+            let octets = v6.octets();
+            let v4 = Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]);
+            if ip_in_nets(&IpAddr::V4(v4), &INTERNAL_NETS) {
+                return true;
             }
-            found_lines
-        }
-        Platform::MacOS => {
-            Command::new("/bin/sh")
-                .stdin(Stdio::null())
-                .arg("-c")
-                .arg(format!("netstat -rn | grep UGHS | grep {}", gateway))
-                .output().unwrap().stdout
-        } Platform::Linux => {
-            Command::new("/bin/sh")
-                .stdin(Stdio::null())
-                .arg("-c")
-                .arg(format!("sudo ip route show | egrep \"[[:digit:]]{{1,3}}\\.[[:digit:]]{{1,3}}\\.[[:digit:]]{{1,3}}\\.[[:digit:]]{{1,3}} via {}\"", gateway))
-                .output().unwrap().stdout
-        }
-        _ => {
-            error!("flush_static_routes: unsupported platform: {:?}", platform);
-            exit(2);
         }
-    };
-    let data = String::from_utf8(output).unwrap();
-    let mut lines = data.lines();
-    let mut all_ok = true;
-    for line in lines {
-        let mut parts = line.split_ascii_whitespace();
-        let first_part = parts.next().unwrap();
-        let ip_string = Arc::new(String::from(first_part));
-        info!("flush_static_routes: flushing route: {:?}", ip_string);
-        if !remove_static_route(&ip_string) {
-            error!("flush_static_routes: error flushing route: {:?}", ip_string);
-            all_ok = false;
-        }
-    }
-    if all_ok {
-        trace!("flush_static_routes: all static routes flushed");
-    } else {
-        error!("flush_static_routes: error flushing static routes");
     }
-    return all_ok;
+    ip_in_nets(ip, &INTERNAL_NETS)
 }
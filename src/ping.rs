@@ -61,6 +61,27 @@ impl Ping {
 
 }
 
+const PROXY_CREDENTIAL_PREFIX: &'static str = "bubble-flexrouter-proxy";
+
+/// The password a bubble must send (as HTTP Basic Proxy-Authorization, or SOCKS5 username/password
+/// auth) to use the proxy. The username is ignored. Derived from the auth token, so the bubble can
+/// compute it without the token itself ever crossing the proxy connection.
+pub fn proxy_credential(auth_token : Arc<String>) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(PROXY_CREDENTIAL_PREFIX.as_bytes());
+    hasher.update(b":");
+    hasher.update(auth_token.to_string());
+    hex::encode(hasher.finalize())
+}
+
+/// Compare a credential without leaking, through timing, how much of it was correct
+pub fn credential_matches(given : &[u8], expected : &[u8]) -> bool {
+    if given.len() != expected.len() {
+        return false;
+    }
+    given.iter().zip(expected.iter()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
 fn now () -> u64 {
     SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
 }
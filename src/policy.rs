@@ -0,0 +1,248 @@
+#![deny(warnings)]
+/**
+ * Copyright (c) 2020 Bubble, Inc.  All rights reserved.
+ * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
+ */
+
+use std::fs;
+use std::net::IpAddr;
+use std::process::exit;
+
+use ipnet::IpNet;
+
+use log::{debug, info, error};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::net::is_internal_ip;
+
+/// Destination policy for proxied traffic, loaded from a JSON file like:
+///
+/// ```json
+/// {
+///   "default": "allow",
+///   "rules": [
+///     { "action": "deny", "domains": [".mybank.com", "*.broker.example"] },
+///     { "action": "deny", "ports": [25, 465, 587] },
+///     { "action": "deny", "cidrs": ["203.0.113.0/24"], "ports": [443] }
+///   ]
+/// }
+/// ```
+///
+/// A rule matches when every criterion it sets matches; a criterion that is not set matches anything.
+/// A matching deny rule always wins over a matching allow rule. If no rule matches, `default` applies.
+///
+/// Independently of the rules, destinations on loopback, link-local, private, CGNAT and multicast
+/// addresses are always refused, unless they fall within one of the `allow_internal` CIDRs.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Policy {
+    #[serde(default = "default_action")]
+    pub default: PolicyAction,
+    #[serde(default)]
+    pub rules: Vec<PolicyRule>,
+    #[serde(default)]
+    pub allow_internal: Vec<IpNet>
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyAction {
+    Allow,
+    Deny
+}
+
+fn default_action() -> PolicyAction { PolicyAction::Allow }
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct PolicyRule {
+    pub action: PolicyAction,
+    /// exact names ("example.com"), suffixes (".example.com" matches example.com and all subdomains)
+    /// or globs ("*.example.com", "cdn?.example.com")
+    #[serde(default)]
+    pub domains: Vec<String>,
+    #[serde(default)]
+    pub cidrs: Vec<IpNet>,
+    #[serde(default)]
+    pub ports: Vec<u16>
+}
+
+#[derive(Debug)]
+pub enum ViolationReason {
+    DeniedByRule (usize),
+    NotAllowed,
+    InternalAddress
+}
+
+#[derive(Debug)]
+pub struct PolicyViolation {
+    pub destination: String,
+    pub reason: ViolationReason
+}
+
+impl std::fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.reason {
+            ViolationReason::DeniedByRule(index) => write!(f, "destination {} denied by policy rule #{}", self.destination, index),
+            ViolationReason::NotAllowed => write!(f, "destination {} not allowed by policy", self.destination),
+            ViolationReason::InternalAddress => write!(f, "destination {} is an internal address", self.destination)
+        }
+    }
+}
+
+impl std::error::Error for PolicyViolation {}
+
+impl Policy {
+    pub fn allow_all () -> Policy {
+        Policy { default: PolicyAction::Allow, rules: Vec::new(), allow_internal: Vec::new() }
+    }
+
+    pub fn load (path : &str) -> Policy {
+        let read_result = fs::read_to_string(path);
+        if read_result.is_err() {
+            error!("Policy.load: error reading policy file {}: {:?}", path, read_result.err().unwrap());
+            exit(2);
+        }
+        let parse_result = serde_json::from_str::<Policy>(read_result.unwrap().as_str());
+        if parse_result.is_err() {
+            error!("Policy.load: error parsing policy file {}: {:?}", path, parse_result.err().unwrap());
+            exit(2);
+        }
+        let policy = parse_result.unwrap();
+        info!("Policy.load: loaded {} rules from {}, default={:?}", policy.rules.len(), path, policy.default);
+        policy
+    }
+
+    /// Add CIDRs that proxied connections may reach even though they are internal addresses
+    pub fn allow_internal_nets (&mut self, nets : Vec<IpNet>) {
+        self.allow_internal.extend(nets);
+    }
+
+    /// Check a destination before it is resolved. Only an explicit deny, or an IP literal
+    /// pointing at an internal address, is final here: allow rules and the default may depend
+    /// on the address, which we do not know yet.
+    pub fn check_host (&self, host : &str, port : u16) -> Result<(), PolicyViolation> {
+        let ip = host.parse::<IpAddr>().ok();
+        if let Some(literal) = ip {
+            if !self.internal_allowed(&literal) {
+                return Err(self.violation(host, ip, port, ViolationReason::InternalAddress));
+            }
+        }
+        let (deny, _allow) = self.matches(Some(host), ip, port);
+        if let Some(index) = deny {
+            return Err(self.violation(host, ip, port, ViolationReason::DeniedByRule(index)));
+        }
+        Ok(())
+    }
+
+    /// Check a destination once its address is known
+    pub fn check (&self, host : &str, ip : IpAddr, port : u16) -> Result<(), PolicyViolation> {
+        if !self.internal_allowed(&ip) {
+            return Err(self.violation(host, Some(ip), port, ViolationReason::InternalAddress));
+        }
+        let (deny, allow) = self.matches(Some(host), Some(ip), port);
+        if let Some(index) = deny {
+            Err(self.violation(host, Some(ip), port, ViolationReason::DeniedByRule(index)))
+        } else if allow.is_some() || self.default == PolicyAction::Allow {
+            Ok(())
+        } else {
+            Err(self.violation(host, Some(ip), port, ViolationReason::NotAllowed))
+        }
+    }
+
+    fn internal_allowed (&self, ip : &IpAddr) -> bool {
+        !is_internal_ip(ip) || self.allow_internal.iter().any(|net| net.contains(ip))
+    }
+
+    fn violation (&self, host : &str, ip : Option<IpAddr>, port : u16, reason : ViolationReason) -> PolicyViolation {
+        let destination = match ip {
+            Some(ip) if ip.to_string() != host => format!("{} ({}):{}", host, ip, port),
+            _ => format!("{}:{}", host, port)
+        };
+        debug!("Policy: denied {}, reason={:?}", destination, reason);
+        PolicyViolation { destination, reason }
+    }
+
+    // returns the index of the first matching deny rule and of the first matching allow rule
+    fn matches (&self, host : Option<&str>, ip : Option<IpAddr>, port : u16) -> (Option<usize>, Option<usize>) {
+        let mut deny = None;
+        let mut allow = None;
+        for (index, rule) in self.rules.iter().enumerate() {
+            if rule.matches(host, ip, port) {
+                match rule.action {
+                    PolicyAction::Deny => if deny.is_none() { deny = Some(index) },
+                    PolicyAction::Allow => if allow.is_none() { allow = Some(index) }
+                }
+            }
+        }
+        (deny, allow)
+    }
+}
+
+impl PolicyRule {
+    fn matches (&self, host : Option<&str>, ip : Option<IpAddr>, port : u16) -> bool {
+        if !self.ports.is_empty() && !self.ports.contains(&port) {
+            return false;
+        }
+        if !self.domains.is_empty() {
+            let host_matches = match host {
+                Some(host) => {
+                    let host = normalize_domain(host);
+                    self.domains.iter().any(|d| domain_matches(normalize_domain(d).as_str(), host.as_str()))
+                }
+                None => false
+            };
+            if !host_matches {
+                return false;
+            }
+        }
+        if !self.cidrs.is_empty() {
+            let ip_matches = match ip {
+                Some(ip) => self.cidrs.iter().any(|net| net.contains(&ip)),
+                None => false
+            };
+            if !ip_matches {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+pub(crate) fn normalize_domain (name : &str) -> String {
+    name.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+pub(crate) fn domain_matches (pattern : &str, host : &str) -> bool {
+    if pattern.starts_with('.') {
+        host == &pattern[1..] || host.ends_with(pattern)
+    } else if pattern.contains('*') || pattern.contains('?') {
+        glob_matches(pattern.as_bytes(), host.as_bytes())
+    } else {
+        host == pattern
+    }
+}
+
+// '*' matches any run of characters, '?' matches exactly one
+fn glob_matches (pattern : &[u8], text : &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    while p < pattern.len() && pattern[p] == b'*' {
+        p += 1;
+    }
+    p == pattern.len()
+}
@@ -11,91 +11,152 @@
  * License: https://raw.githubusercontent.com/hyperium/hyper/master/LICENSE
  */
 
-extern crate lru;
-
+use std::collections::HashMap;
 use std::convert::Infallible;
 use std::net::{IpAddr, SocketAddr};
+#[cfg(not(unix))]
+use std::process::exit;
 use std::sync::Arc;
 
 use futures_util::future::try_join;
 
 use hyper::{Body, Client, Method, Request, Response, Server};
-use hyper::client::HttpConnector;
+use hyper::header::{HeaderValue, PROXY_AUTHORIZATION};
+use hyper::server::accept;
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::upgrade::Upgraded;
 use hyper_tls::HttpsConnector;
 
-use log::{debug, info, error, trace};
-
-use lru::LruCache;
+use log::{debug, info, warn, error, trace};
 
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
 use tokio::sync::Mutex;
 
 use trust_dns_resolver::TokioAsyncResolver;
 
+use crate::connector::{connect_routed, RoutedConnector};
 use crate::dns_cache::*;
-use crate::net::*;
-use crate::hyper_util::bad_request;
-use crate::ping::Ping;
+use crate::hyper_util::{bad_request, forbidden, proxy_auth_required};
+use crate::net::parse_ip;
+use crate::ping::{Ping, proxy_credential, credential_matches};
+use crate::policy::{Policy, PolicyViolation};
 use crate::remove_routes::RemoveRoutes;
+use crate::routes::RouteRegistry;
+use crate::socks::start_socks;
+#[cfg(unix)]
+use crate::util::bind_private_socket;
+use crate::util::HEADER_BUBBLE_UPLINK;
 
-type HttpClient = Client<hyper_tls::HttpsConnector<HttpConnector<CacheResolver>>, hyper::Body>;
+type HttpClient = Client<HttpsConnector<RoutedConnector>, hyper::Body>;
 
 pub async fn start_proxy (dns1_ip : &str,
                           dns2_ip: &str,
-                          proxy_port: u16,
-                          auth_token : Arc<String>) {
-    let dns1_sock : SocketAddr = format!("{}:53", dns1_ip).parse().unwrap();
-    let dns2_sock : SocketAddr = format!("{}:53", dns2_ip).parse().unwrap();
+                          dns_cache_config: DnsCacheConfig,
+                          proxy_port: Option<u16>,
+                          proxy_socket: Option<String>,
+                          socks_port: Option<u16>,
+                          auth_token : Arc<String>,
+                          policy : Arc<Policy>,
+                          routes : Arc<RouteRegistry>,
+                          require_proxy_auth : bool) {
+    // SocketAddr::new, not "ip:53", so IPv6 name servers work too
+    let dns1_sock = SocketAddr::new(parse_ip(dns1_ip).unwrap(), 53);
+    let dns2_sock = SocketAddr::new(parse_ip(dns2_ip).unwrap(), 53);
 
     let resolver = Arc::new(create_resolver(dns1_sock, dns2_sock).await);
-    let resolver_cache = Arc::new(Mutex::new(LruCache::new(1000)));
-
-    let http_resolver = CacheResolver::new(resolver.clone(), resolver_cache.clone());
-    let connector = HttpConnector::new_with_resolver(http_resolver);
-    let https = HttpsConnector::new_with_connector(connector);
+    let resolver_cache: ResolverCache = Arc::new(Mutex::new(DnsCache::new(dns_cache_config)));
+    let connector = RoutedConnector::new(resolver.clone(), resolver_cache.clone(), routes.clone(), policy.clone());
+    let https = HttpsConnector::new_with_connector(connector.clone());
     let client: HttpClient = Client::builder().build(https);
-    let gateway = Arc::new(ip_gateway());
-
-    let proxy_local_ip : IpAddr = "127.0.0.1".parse().unwrap();
-    let addr = SocketAddr::from((proxy_local_ip, proxy_port));
-
-    let make_service = make_service_fn(move |_| {
-        let client = client.clone();
-        let gateway = gateway.clone();
-        let resolver = resolver.clone();
-        let resolver_cache = resolver_cache.clone();
-        let auth_token = auth_token.clone();
-        async move {
-            Ok::<_, Infallible>(service_fn(
-                move |req| proxy(
-                    client.clone(),
-                    gateway.clone(),
-                    resolver.clone(),
-                    resolver_cache.clone(),
-                    auth_token.clone(),
-                    req)
-            ))
+    // the shared client's pooled connections may have gone out through any uplink, so each uplink has its own pool
+    let uplink_clients: Arc<HashMap<String, HttpClient>> = Arc::new(routes.uplinks().uplinks().iter()
+        .map(|uplink| {
+            let https = HttpsConnector::new_with_connector(connector.with_uplink(uplink.name.as_str()));
+            (uplink.name.clone(), Client::builder().build(https))
+        })
+        .collect());
+    let proxy_auth = if require_proxy_auth {
+        Some(Arc::new(proxy_credential(auth_token.clone())))
+    } else {
+        warn!("start_proxy: proxy authentication is disabled, any local process can use the proxy");
+        None
+    };
+
+    if socks_port.is_some() {
+        tokio::spawn(start_socks(socks_port.unwrap(), routes.clone(), resolver.clone(), resolver_cache.clone(), policy.clone(), proxy_auth.clone()));
+    }
+
+    let state = ProxyState { client, uplink_clients, routes, resolver, resolver_cache, auth_token, policy, proxy_auth };
+
+    #[cfg(unix)]
+    let socket_server = match proxy_socket {
+        Some(path) => {
+            let listener = bind_private_socket(path.as_str()).await;
+            let socket_state = state.clone();
+            let make_service = make_service_fn(move |_: &UnixStream| {
+                let state = socket_state.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req| proxy(state.clone(), req)))
+                }
+            });
+            let server = Server::builder(accept::from_stream(listener)).serve(make_service);
+            info!("start_proxy: Proxy listening on unix:{}", path);
+            Some(tokio::spawn(server))
+        }
+        None => None
+    };
+    #[cfg(not(unix))]
+    let socket_server: Option<tokio::task::JoinHandle<Result<(), hyper::Error>>> = match proxy_socket {
+        Some(path) => {
+            error!("start_proxy: unix sockets are not supported on this platform, cannot listen on {}", path);
+            exit(2);
         }
-    });
+        None => None
+    };
+
+    if proxy_port.is_some() {
+        let proxy_local_ip : IpAddr = "127.0.0.1".parse().unwrap();
+        let addr = SocketAddr::from((proxy_local_ip, proxy_port.unwrap()));
+        let make_service = make_service_fn(move |_: &AddrStream| {
+            let state = state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| proxy(state.clone(), req)))
+            }
+        });
 
-    let server = Server::bind(&addr).serve(make_service);
-    info!("start_proxy: Proxy listening on {}", addr);
-    let result = server.await;
-    debug!("start_proxy: Proxy await result: {:?}", result);
+        let server = Server::bind(&addr).serve(make_service);
+        info!("start_proxy: Proxy listening on {}", addr);
+        let result = server.await;
+        debug!("start_proxy: Proxy await result: {:?}", result);
+    }
+    if socket_server.is_some() {
+        let result = socket_server.unwrap().await;
+        debug!("start_proxy: Proxy socket await result: {:?}", result);
+    }
+}
+
+#[derive(Clone)]
+struct ProxyState {
+    client: HttpClient,
+    uplink_clients: Arc<HashMap<String, HttpClient>>,
+    routes: Arc<RouteRegistry>,
+    resolver: Arc<TokioAsyncResolver>,
+    resolver_cache: ResolverCache,
+    auth_token: Arc<String>,
+    policy: Arc<Policy>,
+    proxy_auth: Option<Arc<String>>
 }
 
 const PATH_PING : &'static str = "/ping";
 const PATH_REMOVE : &'static str = "/remove";
 const PATH_HEALTH : &'static str = "/health";
+const PATH_UPLINKS : &'static str = "/uplinks";
 
-async fn proxy(client: Client<HttpsConnector<HttpConnector<CacheResolver>>>,
-               gateway: Arc<String>,
-               resolver: Arc<TokioAsyncResolver>,
-               resolver_cache: Arc<Mutex<LruCache<String, String>>>,
-               auth_token : Arc<String>,
-               req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+async fn proxy(state: ProxyState, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let ProxyState { client, uplink_clients, routes, resolver, resolver_cache, auth_token, policy, proxy_auth } = state;
     let uri = req.uri();
     let host = uri.host();
     if host.is_none() {
@@ -125,17 +186,18 @@ async fn proxy(client: Client<HttpsConnector<HttpConnector<CacheResolver>>>,
                 error!("proxy(remove): invalid ping hash");
                 bad_request("(remove) invalid ping hash\n")
             } else {
-                let routes = remove_routes.routes.clone();
+                let hostnames = remove_routes.routes.clone();
                 let mut resolve_errors: Vec<(String, DnsResolveError)> = Vec::new();
-                for route in routes.into_iter() {
+                for route in hostnames.into_iter() {
                     let resolve_result = resolve_with_cache(route.as_str(), &resolver, resolver_cache.clone()).await;
                     if resolve_result.is_err() {
                         let err = resolve_result.err().unwrap();
                         error!("proxy(remove): error resolving hostname {:?}: {:?}", route.clone(), err);
                         resolve_errors.push((route.clone(), err));
                     } else {
-                        let ip_string = resolve_result.unwrap();
-                        remove_static_route(&ip_string);
+                        for ip in resolve_result.unwrap() {
+                            routes.remove_route(&ip).await;
+                        }
                     }
                 }
                 if resolve_errors.is_empty() {
@@ -145,6 +207,31 @@ async fn proxy(client: Client<HttpsConnector<HttpConnector<CacheResolver>>>,
                 }
             }
 
+        } else if path.eq(PATH_UPLINKS) && method == Method::POST {
+            let body_bytes = hyper::body::to_bytes(req.into_body()).await?;
+            let ping = match String::from_utf8(body_bytes.to_vec()) {
+                Ok(body) => serde_json::from_str::<Ping>(body.as_str()),
+                Err(e) => {
+                    error!("proxy(uplinks): request is not UTF-8: {}", e);
+                    return bad_request("(uplinks) invalid request\n");
+                }
+            };
+            let ping = match ping {
+                Ok(ping) => ping,
+                Err(e) => {
+                    error!("proxy(uplinks): error parsing request: {}", e);
+                    return bad_request("(uplinks) invalid request\n");
+                }
+            };
+            if !ping.verify(auth_token.clone()) {
+                error!("proxy(uplinks): invalid ping hash");
+                bad_request("(uplinks) invalid ping hash\n")
+            } else {
+                let health_json = serde_json::to_string(&routes.uplinks().health()).unwrap();
+                trace!("proxy: valid uplinks request, responding with: {}", health_json);
+                Ok(Response::new(Body::from(health_json)))
+            }
+
         } else if path.eq(PATH_HEALTH) && method == Method::GET {
             Ok(Response::new(Body::from("proxy is alive\n")))
 
@@ -154,27 +241,23 @@ async fn proxy(client: Client<HttpsConnector<HttpConnector<CacheResolver>>>,
         }
     }
 
-    let host = host.unwrap();
-    let host_string = Arc::new(String::from(host));
-    trace!("proxy: received request for host {:?}, resolving...", host_string.clone());
-    let resolve_result = resolve_with_cache(host, &resolver, resolver_cache).await;
-    if resolve_result.is_err() {
-        let err = resolve_result.err().unwrap();
-        error!("proxy: error resolving hostname {:?}: {:?}", host_string.clone(), err);
-        return bad_request(format!("Error: error resolving hostname: {:?}: {:?}\n", host_string.clone(), err).as_str());
+    // IPv6 literals arrive in brackets, e.g. http://[2001:db8::1]/
+    let host = host.unwrap().trim_start_matches('[').trim_end_matches(']');
+    if !proxy_authorized(req.headers().get(PROXY_AUTHORIZATION), &proxy_auth) {
+        error!("proxy: missing or invalid proxy credentials for request to {:?}", host);
+        return proxy_auth_required("Proxy authentication required\n");
     }
-    let ip_string = resolve_result.unwrap();
-    info!("proxy: host {} resolved to: {}", host, ip_string);
-    trace!("proxy: request is {:?}", req);
-
-    if !static_route_exists(&ip_string) {
-        if !create_static_route(&gateway, &ip_string) {
-            // we MUST fail here, without a valid static route, the request would go back out
-            // through the VPN interface, creating an infinite loop
-            error!("proxy: error creating static route to {:?}", ip_string);
-            return bad_request(format!("Error: error creating static route to {:?}\n", ip_string).as_str());
-        }
+    trace!("proxy: received request for host {:?}, resolving...", host);
+    let route_result = route_host(host, request_port(uri), &resolver, resolver_cache, &policy).await;
+    if route_result.is_err() {
+        return match route_result.err().unwrap() {
+            err @ RouteError::PolicyViolation(_) => forbidden(err.to_string().as_str()),
+            err => bad_request(err.to_string().as_str())
+        };
     }
+    let addrs = route_result.unwrap();
+    let uplink = req.headers().get(HEADER_BUBBLE_UPLINK).and_then(|h| h.to_str().ok()).map(String::from);
+    trace!("proxy: request is {:?}", req);
 
     if Method::CONNECT == req.method() {
         // Received an HTTP request like:
@@ -190,26 +273,36 @@ async fn proxy(client: Client<HttpsConnector<HttpConnector<CacheResolver>>>,
         // Note: only after client received an empty body with STATUS_OK can the
         // connection be upgraded, so we can't return a response inside
         // `on_upgrade` future.
-        if let Some(addr) = host_addr(uri, &ip_string) {
+        if let Some(port) = uri.port_u16() {
+            let host = String::from(host);
             tokio::task::spawn(async move {
                 match req.into_body().on_upgrade().await {
                     Ok(upgraded) => {
-                        if let Err(e) = tunnel(upgraded, addr).await {
+                        if let Err(e) = tunnel(upgraded, host.as_str(), addrs, port, routes, uplink).await {
                             error!("proxy: server io error: {}", e);
                         };
                     }
                     Err(e) => error!("proxy: upgrade error: {}", e),
                 }
             });
-            return Ok(Response::new(Body::empty()));
+            Ok(Response::new(Body::empty()))
         } else {
             error!("proxy: CONNECT host is not socket addr: {:?}", uri);
-            return bad_request("CONNECT must be to a socket address\n");
+            bad_request("CONNECT must be to a socket address\n")
         }
     } else {
         // client will resolves hostname to the same IP we resolved, using the CacheResolver
         debug!("proxy: requesting uri: {:?}", req.uri());
-        let result = client.request(req).await;
+        let mut req = req;
+        req.headers_mut().remove(PROXY_AUTHORIZATION);
+        req.headers_mut().remove(HEADER_BUBBLE_UPLINK);
+        // a pooled connection may be reused without connecting again, so the route is still in use
+        routes.touch(&addrs).await;
+        // an uplink we do not know is ignored, as it would be by the connector
+        let result = match uplink.and_then(|uplink| uplink_clients.get(&uplink)) {
+            Some(uplink_client) => uplink_client.request(req).await,
+            None => client.request(req).await
+        };
         if result.is_err() {
             let err = result.err();
             if err.is_none() {
@@ -223,18 +316,105 @@ async fn proxy(client: Client<HttpsConnector<HttpConnector<CacheResolver>>>,
     }
 }
 
-fn host_addr(uri: &http::Uri, ip: &String) -> Option<SocketAddr> {
-    Some(SocketAddr::new(ip.parse().unwrap(), u16::from(uri.port().unwrap())))
+#[derive(Debug)]
+pub enum RouteError {
+    PolicyViolation (PolicyViolation),
+    ResolutionFailed (String),
+}
+
+impl std::fmt::Display for RouteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RouteError::PolicyViolation(v) => writeln!(f, "Error: {}", v),
+            RouteError::ResolutionFailed(msg) => write!(f, "{}", msg)
+        }
+    }
+}
+
+// Check policy and resolve a host, returning every address the policy allows, in the order
+// connections should be attempted. Static routes are created later, by connect_routed,
+// for the address that is actually used
+pub async fn route_host(host: &str,
+                        port: u16,
+                        resolver: &TokioAsyncResolver,
+                        resolver_cache: ResolverCache,
+                        policy: &Policy) -> Result<Vec<IpAddr>, RouteError> {
+    let host_check = policy.check_host(host, port);
+    if host_check.is_err() {
+        let violation = host_check.err().unwrap();
+        error!("route_host: {}", violation);
+        return Err(RouteError::PolicyViolation(violation));
+    }
+
+    let resolve_result = resolve_with_cache(host, resolver, resolver_cache).await;
+    if resolve_result.is_err() {
+        let err = resolve_result.err().unwrap();
+        error!("route_host: error resolving hostname {:?}: {:?}", host, err);
+        return Err(RouteError::ResolutionFailed(format!("Error: error resolving hostname: {:?}: {:?}\n", host, err)));
+    }
+    let addrs = resolve_result.unwrap();
+    info!("route_host: host {} resolved to: {:?}", host, addrs);
+
+    let mut allowed = Vec::new();
+    let mut first_violation = None;
+    for ip in addrs {
+        match policy.check(host, ip, port) {
+            Ok(_) => allowed.push(ip),
+            Err(violation) => if first_violation.is_none() { first_violation = Some(violation) }
+        }
+    }
+    if allowed.is_empty() {
+        let violation = first_violation.unwrap();
+        error!("route_host: {}", violation);
+        return Err(RouteError::PolicyViolation(violation));
+    }
+    Ok(allowed)
+}
+
+// With proxy auth enabled, the request must carry Basic credentials whose password is the proxy credential
+fn proxy_authorized(header: Option<&HeaderValue>, proxy_auth: &Option<Arc<String>>) -> bool {
+    if proxy_auth.is_none() {
+        return true;
+    }
+    let expected = proxy_auth.as_ref().unwrap();
+    let value = match header.and_then(|h| h.to_str().ok()) {
+        Some(value) => value.trim(),
+        None => return false
+    };
+    if value.len() < 6 || !value[..6].eq_ignore_ascii_case("basic ") {
+        return false;
+    }
+    let decoded = match base64::decode(value[6..].trim()) {
+        Ok(decoded) => decoded,
+        Err(_) => return false
+    };
+    match decoded.iter().position(|b| *b == b':') {
+        Some(colon) => credential_matches(&decoded[colon + 1..], expected.as_bytes()),
+        None => false
+    }
+}
+
+// The destination port of a proxied request: explicit in CONNECT requests, otherwise implied by the scheme
+fn request_port(uri: &http::Uri) -> u16 {
+    match uri.port_u16() {
+        Some(port) => port,
+        None => if uri.scheme_str() == Some("https") { 443 } else { 80 }
+    }
 }
 
-// Create a TCP connection to host:port, build a tunnel between the connection and
+// Create a TCP connection to the first reachable address, build a tunnel between the connection and
 // the upgraded connection
-async fn tunnel(upgraded: Upgraded, addr: SocketAddr) -> std::io::Result<()> {
+async fn tunnel<T: AsyncRead + AsyncWrite>(upgraded: T, host: &str, addrs: Vec<IpAddr>, port: u16, routes: Arc<RouteRegistry>, uplink: Option<String>) -> std::io::Result<()> {
     // Connect to remote server
-    trace!("tunnel: connecting to {:?}", addr);
-    let mut server = TcpStream::connect(addr).await?;
-    trace!("tunnel: connected to {:?}", addr);
+    trace!("tunnel: connecting to {:?} port {}", addrs, port);
+    let server = connect_routed(host, addrs, port, routes.clone(), uplink.as_deref()).await?;
+    trace!("tunnel: connected to {:?}", server.peer_addr());
+    let _route_in_use = routes.open_tunnel(&server.peer_addr()?.ip()).await;
+    pipe(upgraded, server).await
+}
 
+// Copy data in both directions between a client connection and an already-connected server
+pub async fn pipe<T: AsyncRead + AsyncWrite>(upgraded: T, mut server: TcpStream) -> std::io::Result<()> {
     // Proxying data
     let amounts = {
         let (mut server_rd, mut server_wr) = server.split();
@@ -0,0 +1,165 @@
+#![deny(warnings)]
+/**
+ * Copyright (c) 2020 Bubble, Inc.  All rights reserved.
+ * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
+ */
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Error;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use log::{trace, info, warn, error};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::route_manager::NextHop;
+use crate::util::{now_secs, write_private_file};
+
+pub const ROUTE_LEDGER_FILE: &'static str = "routes.json";
+
+/// A static route the flexrouter created
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct LedgerEntry {
+    pub destination: IpAddr,
+    pub gateway: NextHop,
+    /// seconds since the epoch
+    pub created: u64,
+    /// the hostnames whose connections used this route
+    pub hostnames: Vec<String>
+}
+
+/// Every static route the flexrouter has created and not yet removed, saved to a state file. The
+/// ledger itself never writes: its owner saves a snapshot, off the lock. An entry is saved before its
+/// route is added, so after a crash the ledger still lists every route the previous run may have left
+/// behind, and cleanup never touches anyone else's routes. Other changes may be saved a little later.
+pub struct RouteLedger {
+    path: Option<PathBuf>,
+    entries: HashMap<IpAddr, LedgerEntry>,
+    // counts changes, so a snapshot can tell whether it is out of date
+    version: u64
+}
+
+/// The ledger as it was at one version, ready to be written to its state file
+pub struct LedgerSnapshot {
+    path: PathBuf,
+    entries: Vec<LedgerEntry>,
+    version: u64
+}
+
+impl LedgerSnapshot {
+    pub fn version (&self) -> u64 { self.version }
+
+    /// Write the snapshot to the state file. This blocks, so call it from a blocking thread
+    pub fn write (&self) -> Result<(), Error> {
+        let data = serde_json::to_string_pretty(&self.entries).map_err(Error::from)?;
+        write_private_file(&self.path, data.as_bytes())?;
+        trace!("LedgerSnapshot.write: saved {} routes to {}", self.entries.len(), self.path.display());
+        Ok(())
+    }
+
+    pub fn path (&self) -> &Path { &self.path }
+}
+
+impl RouteLedger {
+    /// A ledger that is never saved, for tests
+    pub fn in_memory () -> RouteLedger {
+        RouteLedger { path: None, entries: HashMap::new(), version: 0 }
+    }
+
+    /// Load the ledger saved in state_dir by a previous run, if there is one
+    pub fn load (state_dir : &str) -> RouteLedger {
+        let path = Path::new(state_dir).join(ROUTE_LEDGER_FILE);
+        let mut entries = HashMap::new();
+        if path.exists() {
+            let parsed = fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|data| serde_json::from_str::<Vec<LedgerEntry>>(data.as_str()).map_err(|e| e.to_string()));
+            if parsed.is_err() {
+                // keep the unreadable file around for inspection, and start over
+                error!("RouteLedger.load: error reading {}, routes it lists will not be cleaned up: {}", path.display(), parsed.err().unwrap());
+                let rename_result = fs::rename(&path, path.with_extension("corrupt"));
+                if rename_result.is_err() {
+                    warn!("RouteLedger.load: error moving aside {}: {:?}", path.display(), rename_result.err().unwrap());
+                }
+            } else {
+                for entry in parsed.unwrap() {
+                    entries.insert(entry.destination, entry);
+                }
+                info!("RouteLedger.load: loaded {} routes from {}", entries.len(), path.display());
+            }
+        }
+        RouteLedger { path: Some(path), entries, version: 0 }
+    }
+
+    /// Bumped by every change
+    pub fn version (&self) -> u64 { self.version }
+
+    /// The entries to save, unless this ledger is never saved
+    pub fn snapshot (&self) -> Option<LedgerSnapshot> {
+        let path = self.path.as_ref()?;
+        let mut entries = self.entries();
+        entries.sort_by_key(|e| (e.created, e.destination));
+        Some(LedgerSnapshot { path: path.clone(), entries, version: self.version })
+    }
+
+    pub fn entries (&self) -> Vec<LedgerEntry> {
+        self.entries.values().cloned().collect()
+    }
+
+    pub fn contains (&self, destination : &IpAddr) -> bool {
+        self.entries.contains_key(destination)
+    }
+
+    /// Where a route we created goes, if we created one to destination
+    pub fn gateway_of (&self, destination : &IpAddr) -> Option<NextHop> {
+        self.entries.get(destination).map(|entry| entry.gateway.clone())
+    }
+
+    /// Record a route we are about to create
+    pub fn record (&mut self, destination : IpAddr, gateway : NextHop, hostname : &str) {
+        self.entries.insert(destination, LedgerEntry {
+            destination,
+            gateway,
+            created: now_secs(),
+            hostnames: vec![String::from(hostname)]
+        });
+        self.version += 1;
+    }
+
+    /// Note another hostname using a route we created. Returns true if it was not noted before
+    pub fn add_hostname (&mut self, destination : &IpAddr, hostname : &str) -> bool {
+        let added = match self.entries.get_mut(destination) {
+            Some(entry) if !entry.hostnames.iter().any(|h| h == hostname) => {
+                entry.hostnames.push(String::from(hostname));
+                true
+            }
+            _ => false
+        };
+        if added {
+            self.version += 1;
+        }
+        added
+    }
+
+    /// Note that a route we created now goes through another gateway
+    pub fn set_gateway (&mut self, destination : &IpAddr, gateway : NextHop) {
+        let changed = match self.entries.get_mut(destination) {
+            Some(entry) if entry.gateway != gateway => {
+                entry.gateway = gateway;
+                true
+            }
+            _ => false
+        };
+        if changed {
+            self.version += 1;
+        }
+    }
+
+    pub fn forget (&mut self, destination : &IpAddr) {
+        if self.entries.remove(destination).is_some() {
+            self.version += 1;
+        }
+    }
+}
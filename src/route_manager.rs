@@ -0,0 +1,911 @@
+#![deny(warnings)]
+/**
+ * Copyright (c) 2020 Bubble, Inc.  All rights reserved.
+ * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
+ */
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::process::{exit, Command, Stdio};
+use std::sync::Arc;
+
+use futures::Stream;
+
+use log::{trace, info, warn, error};
+
+use serde_derive::{Deserialize, Serialize};
+
+use tokio::task::spawn_blocking;
+
+use whoami::{platform, Platform};
+
+use crate::egress::{EgressBinding, local_address_toward};
+use crate::net::{IpFamily, is_link_local_v6};
+
+pub type RouteFuture<'a, T> = Pin<Box<dyn Future<Output = std::io::Result<T>> + Send + 'a>>;
+
+/// Yields an item whenever the routing table may have changed
+pub type RouteChanges = Pin<Box<dyn Stream<Item = ()> + Send>>;
+
+/// Where a route sends traffic: to a gateway address, or straight out of an interface. Point-to-point
+/// uplinks (PPP, cellular modems, some tethering) have no gateway address, only a device.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum NextHop {
+    Gateway (IpAddr),
+    Device (String)
+}
+
+impl NextHop {
+    /// True if a route to dest can use this next hop
+    pub fn can_reach (&self, dest : &IpAddr) -> bool {
+        match self {
+            NextHop::Gateway(gateway) => gateway.is_ipv4() == dest.is_ipv4(),
+            NextHop::Device(_) => true
+        }
+    }
+}
+
+impl fmt::Display for NextHop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NextHop::Gateway(gateway) => write!(f, "{}", gateway),
+            NextHop::Device(device) => write!(f, "dev {}", device)
+        }
+    }
+}
+
+// saved as "192.0.2.1" or "dev ppp0"
+impl TryFrom<String> for NextHop {
+    type Error = String;
+
+    fn try_from(value : String) -> Result<NextHop, String> {
+        if let Some(device) = value.strip_prefix("dev ") {
+            let device = device.trim();
+            if device.is_empty() || device.contains(char::is_whitespace) {
+                return Err(format!("invalid device: {}", value));
+            }
+            return Ok(NextHop::Device(String::from(device)));
+        }
+        value.trim().parse::<IpAddr>()
+            .map(NextHop::Gateway)
+            .map_err(|_| format!("invalid next hop: {}", value))
+    }
+}
+
+impl From<NextHop> for String {
+    fn from(next_hop : NextHop) -> String {
+        next_hop.to_string()
+    }
+}
+
+/// Host routes in the system routing table. The flexrouter adds one for each address it connects to,
+/// pointing at the LAN gateway, so proxied traffic leaves through the gateway and not the VPN.
+pub trait RouteManager: Send + Sync {
+    /// Where the default route for family sends traffic
+    fn default_gateway (&self, family : IpFamily) -> RouteFuture<'_, NextHop>;
+
+    /// True if there is a host route to dest
+    fn route_exists (&self, dest : IpAddr) -> RouteFuture<'_, bool>;
+
+    fn add_route (&self, dest : IpAddr, gateway : NextHop) -> RouteFuture<'_, ()>;
+
+    /// Remove the host route to dest. Succeeds if there was no such route
+    fn remove_route (&self, dest : IpAddr) -> RouteFuture<'_, ()>;
+
+    /// Destinations of all host routes through gateway
+    fn host_routes_via (&self, gateway : NextHop) -> RouteFuture<'_, Vec<IpAddr>>;
+
+    /// Notifications of routing table changes, if the platform has them. Without them, callers
+    /// interested in the default gateway have to poll it
+    fn route_changes (&self) -> Option<RouteChanges> { None }
+
+    /// What to bind outbound sockets to so they leave through gateway, for EgressMode::Bind
+    fn egress_binding (&self, gateway : NextHop) -> RouteFuture<'_, EgressBinding>;
+}
+
+/// The route manager for this platform: netlink on Linux, the route/netstat commands elsewhere
+pub fn default_route_manager () -> Arc<dyn RouteManager> {
+    #[cfg(target_os = "linux")] {
+        match netlink::NetlinkRouteManager::new() {
+            Ok(manager) => return Arc::new(manager),
+            Err(e) => warn!("default_route_manager: error opening netlink socket, falling back to ip command: {:?}", e)
+        }
+    }
+    Arc::new(CommandRouteManager {})
+}
+
+fn unsupported_family (dest : &IpAddr, gateway : &NextHop) -> Error {
+    Error::new(ErrorKind::InvalidInput, format!("cannot route {} via {}: address families differ", dest, gateway))
+}
+
+/// Routes kept in memory only, for tests: nothing touches the system routing table, and
+/// sockets in EgressMode::Bind are bound to the loopback addresses
+pub struct MemoryRouteManager {
+    gateway: std::sync::Mutex<NextHop>,
+    gateway_v6: std::sync::Mutex<Option<NextHop>>,
+    routes: std::sync::Mutex<HashMap<IpAddr, NextHop>>
+}
+
+impl MemoryRouteManager {
+    pub fn new (gateway : NextHop) -> MemoryRouteManager {
+        MemoryRouteManager {
+            gateway: std::sync::Mutex::new(gateway),
+            gateway_v6: std::sync::Mutex::new(None),
+            routes: std::sync::Mutex::new(HashMap::new())
+        }
+    }
+
+    /// Change the default gateway, as when the device moves to another network
+    pub fn set_default_gateway (&self, gateway : NextHop) {
+        *self.gateway.lock().unwrap() = gateway;
+    }
+
+    /// Change the IPv6 default gateway. Without one, IPv6 uses the default gateway if it can
+    pub fn set_default_gateway_v6 (&self, gateway : Option<NextHop>) {
+        *self.gateway_v6.lock().unwrap() = gateway;
+    }
+
+    /// Current routes, destination -> gateway
+    pub fn routes (&self) -> HashMap<IpAddr, NextHop> {
+        self.routes.lock().unwrap().clone()
+    }
+}
+
+impl RouteManager for MemoryRouteManager {
+    fn default_gateway (&self, family : IpFamily) -> RouteFuture<'_, NextHop> {
+        Box::pin(async move {
+            if family == IpFamily::V6 {
+                if let Some(gateway) = self.gateway_v6.lock().unwrap().clone() {
+                    return Ok(gateway);
+                }
+            }
+            let gateway = self.gateway.lock().unwrap().clone();
+            let reaches_family = match &gateway {
+                NextHop::Gateway(via) => IpFamily::of(via) == family,
+                NextHop::Device(_) => true
+            };
+            if reaches_family {
+                Ok(gateway)
+            } else {
+                Err(Error::new(ErrorKind::NotFound, format!("no {} default route", family)))
+            }
+        })
+    }
+
+    fn route_exists (&self, dest : IpAddr) -> RouteFuture<'_, bool> {
+        Box::pin(async move { Ok(self.routes.lock().unwrap().contains_key(&dest)) })
+    }
+
+    fn add_route (&self, dest : IpAddr, gateway : NextHop) -> RouteFuture<'_, ()> {
+        Box::pin(async move {
+            if !gateway.can_reach(&dest) {
+                return Err(unsupported_family(&dest, &gateway));
+            }
+            let mut routes = self.routes.lock().unwrap();
+            if routes.contains_key(&dest) {
+                return Err(Error::new(ErrorKind::AlreadyExists, format!("route to {} exists", dest)));
+            }
+            routes.insert(dest, gateway);
+            Ok(())
+        })
+    }
+
+    fn remove_route (&self, dest : IpAddr) -> RouteFuture<'_, ()> {
+        Box::pin(async move {
+            self.routes.lock().unwrap().remove(&dest);
+            Ok(())
+        })
+    }
+
+    fn host_routes_via (&self, gateway : NextHop) -> RouteFuture<'_, Vec<IpAddr>> {
+        Box::pin(async move {
+            Ok(self.routes.lock().unwrap().iter()
+                .filter(|(_, via)| **via == gateway)
+                .map(|(dest, _)| *dest)
+                .collect())
+        })
+    }
+
+    fn egress_binding (&self, _gateway : NextHop) -> RouteFuture<'_, EgressBinding> {
+        Box::pin(async move {
+            Ok(EgressBinding::Addresses(vec!["127.0.0.1".parse().unwrap(), "::1".parse().unwrap()]))
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod netlink {
+    use std::io::{Error, ErrorKind};
+    use std::net::IpAddr;
+
+    use futures::stream::{StreamExt, TryStreamExt};
+
+    use log::{trace, info, warn};
+
+    use rtnetlink::{new_connection, Handle, IpVersion};
+    use rtnetlink::constants::{RTMGRP_IPV4_ROUTE, RTMGRP_IPV6_ROUTE};
+    use rtnetlink::packet::{LinkMessage, RouteMessage};
+    use rtnetlink::packet::constants::{RT_SCOPE_LINK, RT_TABLE_MAIN};
+    use rtnetlink::packet::link::nlas::Nla;
+    use rtnetlink::sys::SocketAddr;
+
+    use crate::egress::EgressBinding;
+    use crate::net::{IpFamily, is_link_local_v6};
+
+    use super::{NextHop, RouteChanges, RouteFuture, RouteManager, unsupported_family};
+
+    /// Talks to the kernel routing table over a netlink socket: no fork/exec, no sudo.
+    /// Needs CAP_NET_ADMIN, which the flexrouter has when running as root.
+    pub struct NetlinkRouteManager {
+        handle: Handle
+    }
+
+    fn netlink_error (e : rtnetlink::Error) -> Error {
+        Error::new(ErrorKind::Other, format!("netlink: {}", e))
+    }
+
+    fn host_prefix_len (ip : &IpAddr) -> u8 {
+        if ip.is_ipv4() { 32 } else { 128 }
+    }
+
+    impl NetlinkRouteManager {
+        pub fn new () -> std::io::Result<NetlinkRouteManager> {
+            let (connection, handle, _) = new_connection()?;
+            tokio::spawn(connection);
+            Ok(NetlinkRouteManager { handle })
+        }
+
+        async fn main_routes (&self, version : IpVersion) -> std::io::Result<Vec<RouteMessage>> {
+            let routes: Vec<RouteMessage> = self.handle.route().get(version).execute().try_collect().await.map_err(netlink_error)?;
+            Ok(routes.into_iter().filter(|r| r.header.table == RT_TABLE_MAIN).collect())
+        }
+
+        async fn link_index (&self, device : &str) -> std::io::Result<u32> {
+            let links: Vec<LinkMessage> = self.handle.link().get().set_name_filter(String::from(device)).execute().try_collect().await.map_err(netlink_error)?;
+            match links.first() {
+                Some(link) => Ok(link.header.index),
+                None => Err(Error::new(ErrorKind::NotFound, format!("no such device: {}", device)))
+            }
+        }
+
+        async fn link_name (&self, index : u32) -> std::io::Result<String> {
+            let links: Vec<LinkMessage> = self.handle.link().get().match_index(index).execute().try_collect().await.map_err(netlink_error)?;
+            links.iter().flat_map(|link| link.nlas.iter())
+                .find_map(|nla| if let Nla::IfName(name) = nla { Some(name.clone()) } else { None })
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("no name for device {}", index)))
+        }
+
+        // the device that routes through the gateway via go out of
+        async fn link_index_via (&self, via : IpAddr) -> std::io::Result<u32> {
+            let version = if via.is_ipv4() { IpVersion::V4 } else { IpVersion::V6 };
+            let index = self.main_routes(version).await?.into_iter()
+                .filter(|r| r.gateway() == Some(via))
+                .find_map(|r| r.output_interface());
+            index.ok_or_else(|| Error::new(ErrorKind::NotFound, format!("no route via {}", via)))
+        }
+
+        async fn host_routes_to (&self, dest : IpAddr) -> std::io::Result<Vec<RouteMessage>> {
+            let version = if dest.is_ipv4() { IpVersion::V4 } else { IpVersion::V6 };
+            Ok(self.main_routes(version).await?.into_iter()
+                .filter(|r| r.destination_prefix() == Some((dest, host_prefix_len(&dest))))
+                .collect())
+        }
+    }
+
+    impl RouteManager for NetlinkRouteManager {
+        fn default_gateway (&self, family : IpFamily) -> RouteFuture<'_, NextHop> {
+            Box::pin(async move {
+                let version = match family { IpFamily::V4 => IpVersion::V4, IpFamily::V6 => IpVersion::V6 };
+                let default_routes: Vec<RouteMessage> = self.main_routes(version).await?.into_iter()
+                    .filter(|r| r.header.destination_prefix_length == 0)
+                    .collect();
+                for default_route in default_routes {
+                    let gateway = match (default_route.gateway(), default_route.output_interface()) {
+                        (Some(gateway), _) => NextHop::Gateway(gateway),
+                        // a point-to-point link: no next hop address, traffic just goes out the device
+                        (None, Some(index)) => NextHop::Device(self.link_name(index).await?),
+                        (None, None) => continue
+                    };
+                    trace!("NetlinkRouteManager.default_gateway: found {} gateway: {}", family, gateway);
+                    return Ok(gateway);
+                }
+                Err(Error::new(ErrorKind::NotFound, format!("no {} default route", family)))
+            })
+        }
+
+        fn route_exists (&self, dest : IpAddr) -> RouteFuture<'_, bool> {
+            Box::pin(async move { Ok(!self.host_routes_to(dest).await?.is_empty()) })
+        }
+
+        fn add_route (&self, dest : IpAddr, gateway : NextHop) -> RouteFuture<'_, ()> {
+            Box::pin(async move {
+                info!("NetlinkRouteManager.add_route: adding: gateway={}, ip={}", gateway, dest);
+                match (&gateway, dest) {
+                    (NextHop::Gateway(IpAddr::V4(via)), IpAddr::V4(dest)) => {
+                        self.handle.route().add_v4().destination_prefix(dest, 32).gateway(*via)
+                            .execute().await.map_err(netlink_error)
+                    }
+                    (NextHop::Gateway(IpAddr::V6(via)), IpAddr::V6(dest)) => {
+                        let request = self.handle.route().add_v6().destination_prefix(dest, 128).gateway(*via);
+                        // IPv6 gateways are usually link-local, which only means something on a given device
+                        if is_link_local_v6(&IpAddr::V6(*via)) {
+                            let index = self.link_index_via(IpAddr::V6(*via)).await?;
+                            request.output_interface(index).execute().await.map_err(netlink_error)
+                        } else {
+                            request.execute().await.map_err(netlink_error)
+                        }
+                    }
+                    (NextHop::Device(device), IpAddr::V4(dest)) => {
+                        let index = self.link_index(device).await?;
+                        self.handle.route().add_v4().destination_prefix(dest, 32).output_interface(index).scope(RT_SCOPE_LINK)
+                            .execute().await.map_err(netlink_error)
+                    }
+                    (NextHop::Device(device), IpAddr::V6(dest)) => {
+                        let index = self.link_index(device).await?;
+                        self.handle.route().add_v6().destination_prefix(dest, 128).output_interface(index).scope(RT_SCOPE_LINK)
+                            .execute().await.map_err(netlink_error)
+                    }
+                    _ => Err(unsupported_family(&dest, &gateway))
+                }
+            })
+        }
+
+        fn remove_route (&self, dest : IpAddr) -> RouteFuture<'_, ()> {
+            Box::pin(async move {
+                for route in self.host_routes_to(dest).await? {
+                    info!("NetlinkRouteManager.remove_route: removing ip={}", dest);
+                    self.handle.route().del(route).execute().await.map_err(netlink_error)?;
+                }
+                Ok(())
+            })
+        }
+
+        fn host_routes_via (&self, gateway : NextHop) -> RouteFuture<'_, Vec<IpAddr>> {
+            Box::pin(async move {
+                let mut routes = Vec::new();
+                match gateway {
+                    NextHop::Gateway(via) => {
+                        let version = if via.is_ipv4() { IpVersion::V4 } else { IpVersion::V6 };
+                        routes.extend(self.main_routes(version).await?.into_iter()
+                            .filter(|r| r.gateway() == Some(via)));
+                    }
+                    NextHop::Device(device) => {
+                        let index = self.link_index(&device).await?;
+                        for version in [IpVersion::V4, IpVersion::V6].iter() {
+                            routes.extend(self.main_routes(version.clone()).await?.into_iter()
+                                .filter(|r| r.gateway().is_none() && r.output_interface() == Some(index)));
+                        }
+                    }
+                }
+                Ok(routes.into_iter()
+                    .filter_map(|r| r.destination_prefix())
+                    .filter(|(dest, len)| *len == host_prefix_len(dest))
+                    .map(|(dest, _)| dest)
+                    .collect())
+            })
+        }
+
+        fn route_changes (&self) -> Option<RouteChanges> {
+            // a second connection, subscribed to the kernel's route change broadcasts
+            let connection = new_connection();
+            if connection.is_err() {
+                warn!("NetlinkRouteManager.route_changes: error opening netlink socket: {:?}", connection.err().unwrap());
+                return None;
+            }
+            let (mut connection, _, messages) = connection.unwrap();
+            let bind_result = connection.socket_mut().bind(&SocketAddr::new(0, RTMGRP_IPV4_ROUTE | RTMGRP_IPV6_ROUTE));
+            if bind_result.is_err() {
+                warn!("NetlinkRouteManager.route_changes: error subscribing to route changes: {:?}", bind_result.err().unwrap());
+                return None;
+            }
+            tokio::spawn(connection);
+            Some(Box::pin(messages.map(|_| ())))
+        }
+
+        fn egress_binding (&self, gateway : NextHop) -> RouteFuture<'_, EgressBinding> {
+            Box::pin(async move {
+                match gateway {
+                    NextHop::Device(device) => Ok(EgressBinding::Device(device)),
+                    NextHop::Gateway(via) => {
+                        let index = self.link_index_via(via).await?;
+                        Ok(EgressBinding::Device(self.link_name(index).await?))
+                    }
+                }
+            })
+        }
+    }
+}
+
+/// Runs the platform's route/netstat commands. Each command runs on the blocking thread pool.
+pub struct CommandRouteManager {}
+
+fn run_blocking<T: Send + 'static, F: FnOnce() -> std::io::Result<T> + Send + 'static> (f : F) -> RouteFuture<'static, T> {
+    Box::pin(async move {
+        match spawn_blocking(f).await {
+            Ok(result) => result,
+            Err(e) => Err(Error::new(ErrorKind::Other, format!("route command task failed: {:?}", e)))
+        }
+    })
+}
+
+impl RouteManager for CommandRouteManager {
+    fn default_gateway (&self, family : IpFamily) -> RouteFuture<'_, NextHop> {
+        run_blocking(move || ip_gateway(family))
+    }
+
+    fn route_exists (&self, dest : IpAddr) -> RouteFuture<'_, bool> {
+        run_blocking(move || Ok(static_route_exists(&dest.to_string())))
+    }
+
+    fn add_route (&self, dest : IpAddr, gateway : NextHop) -> RouteFuture<'_, ()> {
+        run_blocking(move || {
+            if create_static_route(&gateway, &dest.to_string()) {
+                Ok(())
+            } else {
+                Err(Error::new(ErrorKind::Other, format!("error creating route to {}", dest)))
+            }
+        })
+    }
+
+    fn remove_route (&self, dest : IpAddr) -> RouteFuture<'_, ()> {
+        run_blocking(move || {
+            if remove_static_route(&dest.to_string()) {
+                Ok(())
+            } else {
+                Err(Error::new(ErrorKind::Other, format!("error removing route to {}", dest)))
+            }
+        })
+    }
+
+    fn host_routes_via (&self, gateway : NextHop) -> RouteFuture<'_, Vec<IpAddr>> {
+        run_blocking(move || Ok(list_static_routes(&gateway).into_iter()
+            .filter_map(|dest| dest.parse::<IpAddr>().ok())
+            .collect()))
+    }
+
+    fn egress_binding (&self, gateway : NextHop) -> RouteFuture<'_, EgressBinding> {
+        run_blocking(move || {
+            let platform: Platform = platform();
+            match (platform, gateway) {
+                (Platform::Linux, NextHop::Gateway(via)) => Ok(EgressBinding::Device(device_toward(&via)?)),
+                (Platform::Linux, NextHop::Device(device)) => Ok(EgressBinding::Device(device)),
+                (_, NextHop::Gateway(via)) => Ok(EgressBinding::Addresses(vec![local_address_toward(&via)?])),
+                (platform, NextHop::Device(device)) => {
+                    Err(Error::new(ErrorKind::Other, format!("cannot bind to device {} on {:?}", device, platform)))
+                }
+            }
+        })
+    }
+}
+
+fn ip_gateway(family : IpFamily) -> std::io::Result<NextHop> {
+    let platform : Platform = platform();
+    let gateway = match platform {
+        Platform::Windows if family == IpFamily::V6 => {
+            return Err(Error::new(ErrorKind::Other, "IPv6 routes are not supported on Windows"));
+        }
+        Platform::Windows => {
+            let output = Command::new("C:\\Windows\\System32\\cmd.exe")
+                .stdin(Stdio::null())
+                .arg("/c")
+                .arg("route").arg("print").arg("0.0.0.0")
+                .arg("|").arg("findstr").arg("/L").arg("/C:0.0.0.0")
+                .output().unwrap().stdout;
+            let data = String::from_utf8(output).unwrap();
+            let mut parts = data.split_ascii_whitespace();
+            parts.next();
+            parts.next();
+            match parts.next() {
+                Some("On-link") => return Err(Error::new(ErrorKind::Other, "point-to-point default routes are not supported on Windows")),
+                Some(gateway) => parse_gateway(gateway)?,
+                None => None
+            }
+        }
+        Platform::MacOS => {
+            // Destination Gateway Flags Netif: the gateway is link#N on point-to-point links,
+            // and IPv6 link-local gateways carry their scope, as in fe80::1%en0
+            let output = Command::new("/bin/sh")
+                .stdin(Stdio::null())
+                .arg("-c")
+                .arg(format!("netstat -rn -f {} | grep '^default'", if family == IpFamily::V4 { "inet" } else { "inet6" }))
+                .output().unwrap().stdout;
+            let data = String::from_utf8(output).unwrap();
+            let defaults: Vec<Vec<&str>> = data.lines().map(|line| line.split_ascii_whitespace().collect()).collect();
+            let via_address = defaults.iter()
+                .find_map(|parts| parts.get(1).and_then(|gateway| gateway.split('%').next().unwrap().parse::<IpAddr>().ok()))
+                .map(NextHop::Gateway);
+            via_address.or_else(|| defaults.iter()
+                .find(|parts| parts.len() > 3 && parts[1].starts_with("link#"))
+                .map(|parts| NextHop::Device(String::from(parts[3]))))
+        }
+        Platform::Linux => {
+            // "default via 192.168.1.1 dev wlan0 ..." or, on point-to-point links, "default dev ppp0 ..."
+            let output = Command::new("/bin/sh")
+                .stdin(Stdio::null())
+                .arg("-c")
+                .arg(format!("ip {} route show default | head -n 1", ip_family_flag(family)))
+                .output().unwrap().stdout;
+            let data = String::from_utf8(output).unwrap();
+            let parts: Vec<&str> = data.split_ascii_whitespace().collect();
+            let value_of = |key : &str| parts.iter().position(|p| *p == key).and_then(|i| parts.get(i + 1));
+            match (value_of("via"), value_of("dev")) {
+                (Some(gateway), _) => parse_gateway(gateway)?,
+                (None, Some(device)) => Some(NextHop::Device(String::from(*device))),
+                (None, None) => None
+            }
+        }
+        _ => {
+            error!("ip_gateway: unsupported platform: {:?}", platform);
+            exit(2);
+        }
+    };
+    if gateway.is_none() {
+        // not fatal: there is no default route while moving between networks, or on single-stack networks
+        return Err(Error::new(ErrorKind::NotFound, format!("no {} default route", family)));
+    }
+    let gateway = gateway.unwrap();
+    trace!("ip_gateway: found {} gateway: {}", family, gateway);
+    Ok(gateway)
+}
+
+fn ip_family_flag(family : IpFamily) -> &'static str {
+    if family == IpFamily::V4 { "-4" } else { "-6" }
+}
+
+fn ip_string_family(ip_string : &str) -> IpFamily {
+    if ip_string.contains(':') { IpFamily::V6 } else { IpFamily::V4 }
+}
+
+// The device an IPv6 link-local gateway is on, from the default route through it. Routes through
+// such a gateway must name the device, since the same address may be in use on every link
+fn link_local_device(gateway : &IpAddr) -> Option<String> {
+    let platform: Platform = platform();
+    let command = match platform {
+        Platform::MacOS => "netstat -rn -f inet6 | grep '^default'",
+        Platform::Linux => "ip -6 route show default",
+        _ => return None
+    };
+    let output = Command::new("/bin/sh")
+        .stdin(Stdio::null())
+        .arg("-c")
+        .arg(command)
+        .output().unwrap().stdout;
+    let data = String::from_utf8(output).unwrap();
+    let gateway_string = gateway.to_string();
+    for line in data.lines() {
+        let parts: Vec<&str> = line.split_ascii_whitespace().collect();
+        if let Platform::MacOS = platform {
+            // default fe80::1%en0 UGcg en0
+            if let Some((address, scope)) = parts.get(1).and_then(|gateway| gateway.split_once('%')) {
+                if address == gateway_string {
+                    return Some(String::from(scope));
+                }
+            }
+        } else {
+            // default via fe80::1 dev eth0 proto ra metric 1024 pref medium
+            let value_of = |key : &str| parts.iter().position(|p| *p == key).and_then(|i| parts.get(i + 1));
+            if value_of("via") == Some(&gateway_string.as_str()) {
+                if let Some(device) = value_of("dev") {
+                    return Some(String::from(*device));
+                }
+            }
+        }
+    }
+    None
+}
+
+// the device "ip route get" says traffic to ip leaves through
+fn device_toward(ip : &IpAddr) -> std::io::Result<String> {
+    let output = Command::new("/bin/sh")
+        .stdin(Stdio::null())
+        .arg("-c")
+        .arg(format!("ip route get {}", ip))
+        .output().unwrap().stdout;
+    let data = String::from_utf8(output).unwrap();
+    let parts: Vec<&str> = data.split_ascii_whitespace().collect();
+    match parts.iter().position(|p| *p == "dev").and_then(|i| parts.get(i + 1)) {
+        Some(device) => Ok(String::from(*device)),
+        None => Err(Error::new(ErrorKind::NotFound, format!("no route to {}", ip)))
+    }
+}
+
+fn parse_gateway(gateway : &str) -> std::io::Result<Option<NextHop>> {
+    match gateway.trim().parse::<IpAddr>() {
+        Ok(gateway) => Ok(Some(NextHop::Gateway(gateway))),
+        Err(_) => Err(Error::new(ErrorKind::InvalidData, format!("invalid gateway: {}", gateway)))
+    }
+}
+
+fn static_route_exists(ip_string: &String) -> bool {
+    trace!("static_route_exists: checking ip={:?}", ip_string);
+    let platform : Platform = platform();
+    let output = match platform {
+        Platform::Windows => {
+            let raw_out = Command::new("route")
+                .stdin(Stdio::null())
+                .arg("print")
+                .arg(ip_string)
+                .output().unwrap().stdout;
+            let raw_string_out = String::from_utf8(raw_out);
+            if raw_string_out.is_ok() {
+                let mut found_line = Vec::new();
+                let raw_string = raw_string_out.unwrap();
+                for line in raw_string.lines().into_iter() {
+                    if line.contains("Network Destination") {
+                        found_line = line.as_bytes().to_vec();
+                        break;
+                    }
+                }
+                found_line
+            } else {
+                let err = raw_string_out.err();
+                if err.is_some() {
+                    trace!("static_route_exists: route command failed: {:?}", err.unwrap());
+                } else {
+                    error!("static_route_exists: route command failed, unknown error");
+                }
+                Vec::new()
+            }
+        }
+        Platform::MacOS => {
+            Command::new("/bin/sh")
+                .stdin(Stdio::null())
+                .arg("-c")
+                .arg(format!("netstat -rn | egrep -m 1 \"^{}\"", ip_string))
+                .output().unwrap().stdout
+        }
+        Platform::Linux => {
+            Command::new("/bin/sh")
+                .stdin(Stdio::null())
+                .arg("-c")
+                .arg(format!("ip {} route show | egrep -m 1 \"^{} \" | cut -d' ' -f3", ip_family_flag(ip_string_family(ip_string)), ip_string))
+                .output().unwrap().stdout
+        }
+        _ => {
+            error!("static_route_exists: unsupported platform: {:?}", platform);
+            exit(2);
+        }
+    };
+    let data = String::from_utf8(output).unwrap();
+    let mut parts = data.split_ascii_whitespace();
+    let first_part = parts.next();
+    first_part.is_some() && first_part.unwrap().len() > 0
+}
+
+fn create_static_route(gateway: &NextHop, ip_string: &String) -> bool {
+    info!("create_static_route: creating: gateway={}, ip={}", gateway, ip_string);
+    let platform: Platform = platform();
+    let output = match (&platform, gateway) {
+        (Platform::Windows, NextHop::Gateway(gateway)) if gateway.is_ipv6() => {
+            error!("create_static_route: cannot route {} via {}: IPv6 routes are not supported on Windows", ip_string, gateway);
+            return false;
+        }
+        (Platform::Windows, NextHop::Gateway(gateway)) => {
+            Command::new("route")
+                .stdin(Stdio::null())
+                .arg("add").arg(ip_string).arg(gateway.to_string())
+                .output().unwrap().stderr
+        }
+        (Platform::Windows, NextHop::Device(device)) => {
+            error!("create_static_route: cannot route {} via device {}: point-to-point routes are not supported on Windows", ip_string, device);
+            return false;
+        }
+        (Platform::MacOS, NextHop::Gateway(gateway)) => {
+            // fe80::1%en0: a link-local gateway only means something together with its device
+            let via = match link_local_device(gateway) {
+                Some(device) if is_link_local_v6(gateway) => format!("{}%{}", gateway, device),
+                _ => gateway.to_string()
+            };
+            Command::new("/bin/sh")
+                .stdin(Stdio::null())
+                .arg("-c")
+                .arg(format!("sudo route add {} -host {} {}", mac_family_flag(ip_string), ip_string, via))
+                .output().unwrap().stderr
+        }
+        (Platform::MacOS, NextHop::Device(device)) => {
+            Command::new("/bin/sh")
+                .stdin(Stdio::null())
+                .arg("-c")
+                .arg(format!("sudo route add {} -host {} -interface {}", mac_family_flag(ip_string), ip_string, device))
+                .output().unwrap().stderr
+        }
+        (Platform::Linux, NextHop::Gateway(gateway)) => {
+            let via = match link_local_device(gateway) {
+                Some(device) if is_link_local_v6(gateway) => format!("{} dev {}", gateway, device),
+                _ => gateway.to_string()
+            };
+            Command::new("/bin/sh")
+                .stdin(Stdio::null())
+                .arg("-c")
+                .arg(format!("sudo ip route add {} via {}", ip_string, via))
+                .output().unwrap().stderr
+        }
+        (Platform::Linux, NextHop::Device(device)) => {
+            Command::new("/bin/sh")
+                .stdin(Stdio::null())
+                .arg("-c")
+                .arg(format!("sudo ip route add {} dev {}", ip_string, device))
+                .output().unwrap().stderr
+        }
+        _ => {
+            error!("create_static_route: unsupported platform: {:?}", platform);
+            exit(2);
+        }
+    };
+    let data = String::from_utf8(output).unwrap();
+    let mut parts = data.split_ascii_whitespace();
+    let first_part = parts.next();
+    let ok = first_part.is_none() || first_part.unwrap().len() == 0;
+    if !ok {
+        error!("create_static_route: error creating route to {}: {}", ip_string, data);
+    }
+    ok
+}
+
+fn remove_static_route(ip_string: &String) -> bool {
+    if !static_route_exists(ip_string) {
+        info!("remove_static_route: route does not exist for ip={}", ip_string);
+        return true;
+    }
+    info!("remove_static_route: removing ip={}", ip_string);
+    let platform: Platform = platform();
+    let output = match platform {
+        Platform::Windows => {
+            Command::new("route")
+                .stdin(Stdio::null())
+                .arg("delete").arg(ip_string)
+                .output().unwrap().stderr
+        }
+        Platform::MacOS => {
+            Command::new("/bin/sh")
+                .stdin(Stdio::null())
+                .arg("-c")
+                .arg(format!("sudo route -n delete {} -host {}", mac_family_flag(ip_string), ip_string))
+                .output().unwrap().stderr
+        } Platform::Linux => {
+            Command::new("/bin/sh")
+                .stdin(Stdio::null())
+                .arg("-c")
+                .arg(format!("sudo ip route del {}", ip_string))
+                .output().unwrap().stderr
+        }
+        _ => {
+            error!("remove_static_route: unsupported platform: {:?}", platform);
+            exit(2);
+        }
+    };
+    let data = String::from_utf8(output).unwrap();
+    let mut parts = data.split_ascii_whitespace();
+    let first_part = parts.next();
+    let ok = first_part.is_none() || first_part.unwrap().len() == 0;
+    if !ok {
+        error!("remove_static_route: error removing route to {}: {}", ip_string, data);
+    }
+    ok
+}
+
+fn mac_family_flag(ip_string : &str) -> &'static str {
+    if ip_string_family(ip_string) == IpFamily::V4 { "-inet" } else { "-inet6" }
+}
+
+// rust complains about "unused mut" in "mut parts = data.split_ascii_whitespace()"
+// but removing the "mut" causes a compilation failure
+#[allow(unused_mut)]
+fn list_static_routes(next_hop: &NextHop) -> Vec<String> {
+    trace!("list_static_routes: finding static routes with gateway {}", next_hop);
+    let gateway = match next_hop {
+        NextHop::Gateway(gateway) => gateway.to_string(),
+        NextHop::Device(device) => return list_static_routes_on_device(device)
+    };
+    let platform: Platform = platform();
+    let output = match platform {
+        Platform::Windows => {
+            let raw_out = Command::new("route")
+                .stdin(Stdio::null())
+                .arg("print")
+                .output().unwrap().stdout;
+            let raw_string_out = String::from_utf8(raw_out);
+            let mut found_lines = Vec::new();
+            if raw_string_out.is_ok() {
+                let raw_string = raw_string_out.unwrap();
+                for line in raw_string.lines().into_iter() {
+                    if line.contains(gateway.as_str()) && !line.contains("0.0.0.0") {
+                        let mut parts = line.split_ascii_whitespace();
+                        let first_part = parts.next().unwrap();
+                        if !first_part.ends_with(".255") {
+                            found_lines.append(&mut line.as_bytes().to_vec());
+                            found_lines.push(b'\n');
+                        }
+                    }
+                }
+            } else {
+                let err = raw_string_out.err();
+                if err.is_some() {
+                    trace!("list_static_routes: route command failed: {:?}", err.unwrap());
+                } else {
+                    error!("list_static_routes: route command failed, unknown error");
+                }
+            }
+            found_lines
+        }
+        Platform::MacOS => {
+            Command::new("/bin/sh")
+                .stdin(Stdio::null())
+                .arg("-c")
+                .arg(format!("netstat -rn | grep UGHS | grep {}", gateway))
+                .output().unwrap().stdout
+        } Platform::Linux => {
+            Command::new("/bin/sh")
+                .stdin(Stdio::null())
+                .arg("-c")
+                .arg(format!("sudo ip {} route show | grep \" via {} \"", ip_family_flag(ip_string_family(gateway.as_str())), gateway))
+                .output().unwrap().stdout
+        }
+        _ => {
+            error!("list_static_routes: unsupported platform: {:?}", platform);
+            exit(2);
+        }
+    };
+    let data = String::from_utf8(output).unwrap();
+    let mut lines = data.lines();
+    let mut routes = Vec::new();
+    for line in lines {
+        let mut parts = line.split_ascii_whitespace();
+        let first_part = parts.next();
+        // host routes only: a bare address, not a network like 10.0.0.0/8 or "default"
+        if first_part.is_some() && first_part.unwrap().parse::<IpAddr>().is_ok() {
+            routes.push(String::from(first_part.unwrap()));
+        }
+    }
+    routes
+}
+
+// host routes with no gateway address, straight out of a point-to-point device
+fn list_static_routes_on_device(device: &String) -> Vec<String> {
+    let platform: Platform = platform();
+    let output = match platform {
+        Platform::MacOS => {
+            Command::new("/bin/sh")
+                .stdin(Stdio::null())
+                .arg("-c")
+                .arg("netstat -rn")
+                .output().unwrap().stdout
+        }
+        Platform::Linux => {
+            Command::new("/bin/sh")
+                .stdin(Stdio::null())
+                .arg("-c")
+                .arg(format!("sudo ip -4 route show dev {0}; sudo ip -6 route show dev {0}", device))
+                .output().unwrap().stdout
+        }
+        _ => {
+            error!("list_static_routes_on_device: unsupported platform: {:?}", platform);
+            return Vec::new();
+        }
+    };
+    let data = String::from_utf8(output).unwrap();
+    let mut routes = Vec::new();
+    for line in data.lines() {
+        let parts: Vec<&str> = line.split_ascii_whitespace().collect();
+        if parts.is_empty() || parts[0].parse::<IpAddr>().is_err() {
+            continue;
+        }
+        let on_device = if let Platform::MacOS = platform {
+            // Destination Gateway Flags Netif
+            parts.len() > 3 && parts[1].starts_with("link#") && parts[2].contains('H') && parts[3] == device.as_str()
+        } else {
+            // only lists routes on the device, without naming it: "198.51.100.1 scope link"
+            !parts.contains(&"via")
+        };
+        if on_device {
+            routes.push(String::from(parts[0]));
+        }
+    }
+    routes
+}
@@ -0,0 +1,683 @@
+#![deny(warnings)]
+/**
+ * Copyright (c) 2020 Bubble, Inc.  All rights reserved.
+ * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
+ */
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{FutureExt, StreamExt};
+
+use log::{trace, debug, info, warn, error};
+
+use tokio::net::TcpStream;
+use tokio::sync::{watch, Mutex, Notify};
+use tokio::time::{delay_for, timeout};
+
+use crate::egress::{connect_bound, EgressBinding, EgressMode};
+use crate::net::IpFamily;
+use crate::route_ledger::RouteLedger;
+use crate::route_manager::{NextHop, RouteManager};
+use crate::uplinks::{Uplink, Uplinks};
+
+/// Limits on how many static routes we keep, and for how long
+#[derive(Debug, Clone, Copy)]
+pub struct RouteLimits {
+    /// routes not used for this long are removed
+    pub idle_ttl: Duration,
+    /// when there are more routes than this, the least recently used are removed
+    pub max_routes: usize
+}
+
+pub const DEFAULT_ROUTE_IDLE_TTL: u64 = 3600;
+pub const DEFAULT_MAX_ROUTES: usize = 2000;
+
+pub const DEFAULT_GATEWAY_CHECK_INTERVAL: u64 = 10;
+
+// how long an uplink health check may take to connect before the uplink counts as down
+const UPLINK_CHECK_TIMEOUT_SECS: u64 = 5;
+
+// never check for idle routes less often than this
+const MAX_EXPIRY_INTERVAL_SECS: u64 = 60;
+
+// a route change comes as a burst of notifications; wait this long for the rest before looking
+const ROUTE_CHANGE_SETTLE_MILLIS: u64 = 500;
+
+// changes to the ledger that need not be saved right away are gathered for this long, then saved at once
+const LEDGER_SAVE_DELAY_MILLIS: u64 = 1000;
+
+impl Default for RouteLimits {
+    fn default () -> RouteLimits {
+        RouteLimits { idle_ttl: Duration::from_secs(DEFAULT_ROUTE_IDLE_TTL), max_routes: DEFAULT_MAX_ROUTES }
+    }
+}
+
+struct RouteUsage {
+    last_used: Instant,
+    open_tunnels: usize
+}
+
+struct RouteEntry {
+    // true once the route is known to exist; the lock serializes route changes for this address
+    routed: Mutex<bool>,
+    usage: std::sync::Mutex<RouteUsage>
+}
+
+impl RouteEntry {
+    fn new () -> RouteEntry {
+        RouteEntry {
+            routed: Mutex::new(false),
+            usage: std::sync::Mutex::new(RouteUsage { last_used: Instant::now(), open_tunnels: 0 })
+        }
+    }
+
+    fn touch (&self) {
+        self.usage.lock().unwrap().last_used = Instant::now();
+    }
+
+    // a route can be removed if no tunnel uses it and, when a cutoff is given, it was last used before it
+    fn removable (&self, idle_before : Option<Instant>) -> bool {
+        let usage = self.usage.lock().unwrap();
+        usage.open_tunnels == 0 && (idle_before.is_none() || usage.last_used <= idle_before.unwrap())
+    }
+}
+
+/// Held for as long as a tunnel is open through a route, so the route is never removed under it
+pub struct TunnelGuard {
+    entry: Arc<RouteEntry>
+}
+
+impl Drop for TunnelGuard {
+    fn drop (&mut self) {
+        let mut usage = self.entry.usage.lock().unwrap();
+        usage.open_tunnels -= 1;
+        usage.last_used = Instant::now();
+    }
+}
+
+/// Static routes the flexrouter has created. Proxied connections consult this first, so an address
+/// we already routed costs a map lookup instead of a trip to the routing table. Each address has its
+/// own lock, so concurrent connections to one address wait for a single route to be added instead of
+/// racing to add it. Every route we add is recorded in the ledger, and only those are ever removed:
+/// when idle for longer than the idle TTL, or when there are too many and they were used least
+/// recently. Routes with open tunnels are never removed. When the default gateway changes, as when
+/// the device moves to another network, the routes we created are moved over to the new gateway.
+/// On dual-stack networks, routes to IPv6 destinations go through the IPv6 default gateway.
+/// In EgressMode::Bind no routes are created at all: each socket is bound to the physical interface.
+/// With several uplinks, each connection goes out through the uplink chosen for its destination,
+/// and a route is moved to another uplink when a later connection picks a different one.
+pub struct RouteRegistry {
+    manager: Arc<dyn RouteManager>,
+    // the current gateway; only changed while holding the ledger lock
+    gateway_sender: watch::Sender<NextHop>,
+    gateway: watch::Receiver<NextHop>,
+    // where the IPv6 default route goes, if there is one
+    gateway_v6: std::sync::Mutex<Option<NextHop>>,
+    ledger: std::sync::Mutex<RouteLedger>,
+    // the ledger version last saved; held while saving, so saves never overtake each other
+    ledger_saved: Mutex<u64>,
+    // woken when the ledger changed and should be saved soon
+    ledger_changed: Notify,
+    limits: RouteLimits,
+    egress_mode: EgressMode,
+    uplinks: Uplinks,
+    // what sockets going to each next hop are bound to, looked up again after the gateway changes
+    bindings: std::sync::Mutex<HashMap<NextHop, EgressBinding>>,
+    routes: Mutex<HashMap<IpAddr, Arc<RouteEntry>>>
+}
+
+impl RouteRegistry {
+    pub fn new (manager : Arc<dyn RouteManager>,
+                gateway : NextHop,
+                ledger : RouteLedger,
+                limits : RouteLimits,
+                egress_mode : EgressMode,
+                uplinks : Uplinks) -> RouteRegistry {
+        let (gateway_sender, gateway) = watch::channel(gateway);
+        RouteRegistry {
+            manager,
+            gateway_sender,
+            gateway,
+            gateway_v6: std::sync::Mutex::new(None),
+            ledger: std::sync::Mutex::new(ledger),
+            ledger_saved: Mutex::new(0),
+            ledger_changed: Notify::new(),
+            limits,
+            egress_mode,
+            uplinks,
+            bindings: std::sync::Mutex::new(HashMap::new()),
+            routes: Mutex::new(HashMap::new())
+        }
+    }
+
+    /// The default gateway: the IPv4 one, unless the network is IPv6-only
+    pub fn gateway (&self) -> NextHop { self.gateway.borrow().clone() }
+
+    pub fn gateway_v6 (&self) -> Option<NextHop> { self.gateway_v6.lock().unwrap().clone() }
+
+    /// The default gateway for traffic to dest, if there is one for its address family
+    pub fn gateway_for (&self, dest : &IpAddr) -> Option<NextHop> {
+        if dest.is_ipv6() {
+            if let Some(gateway) = self.gateway_v6() {
+                return Some(gateway);
+            }
+        }
+        let gateway = self.gateway();
+        if gateway.can_reach(dest) { Some(gateway) } else { None }
+    }
+
+    pub fn egress_mode (&self) -> EgressMode { self.egress_mode }
+
+    pub fn uplinks (&self) -> &Uplinks { &self.uplinks }
+
+    /// Where traffic to dest through uplink goes: its own next hop, or the current default gateway
+    /// for the family of dest
+    pub fn next_hop (&self, uplink : &Uplink, dest : &IpAddr) -> Option<NextHop> {
+        match &uplink.next_hop {
+            Some(next_hop) => Some(next_hop.clone()),
+            None => self.gateway_for(dest)
+        }
+    }
+
+    /// Receives the new gateway each time it changes
+    pub fn subscribe_gateway (&self) -> watch::Receiver<NextHop> { self.gateway.clone() }
+
+    async fn entry (&self, ip : &IpAddr) -> Arc<RouteEntry> {
+        let mut routes = self.routes.lock().await;
+        routes.entry(*ip).or_insert_with(|| Arc::new(RouteEntry::new())).clone()
+    }
+
+    // false if the entry was removed from the map while we waited for its lock
+    async fn is_current (&self, ip : &IpAddr, entry : &Arc<RouteEntry>) -> bool {
+        match self.routes.lock().await.get(ip) {
+            Some(current) => Arc::ptr_eq(current, entry),
+            None => false
+        }
+    }
+
+    /// Make sure traffic to ip leaves through gateway. A route we created through another gateway is
+    /// moved, unless it carries open tunnels. Returns false if the route could not be created, in
+    /// which case the caller MUST NOT connect: the connection would go out through the VPN
+    pub async fn ensure_route (&self, ip : &IpAddr, hostname : &str, gateway : NextHop) -> bool {
+        let added = loop {
+            let entry = self.entry(ip).await;
+            let mut routed = entry.routed.lock().await;
+            if !self.is_current(ip, &entry).await {
+                continue;
+            }
+            entry.touch();
+            if *routed {
+                let current = self.ledger.lock().unwrap().gateway_of(ip);
+                match current {
+                    Some(current) if current != gateway => {
+                        if !entry.removable(None) {
+                            debug!("RouteRegistry.ensure_route: route to {} carries open tunnels, staying on {} instead of {}", ip, current, gateway);
+                        } else if !self.reroute_locked(ip, gateway, &mut routed).await {
+                            return false;
+                        }
+                    }
+                    _ => trace!("RouteRegistry.ensure_route: route to {} already exists", ip)
+                }
+                if self.ledger.lock().unwrap().add_hostname(ip, hostname) {
+                    self.ledger_changed.notify();
+                }
+                return true;
+            }
+            let exists = self.manager.route_exists(*ip).await;
+            if exists.is_err() {
+                error!("RouteRegistry.ensure_route: error checking route to {}: {:?}", ip, exists.err().unwrap());
+                return false;
+            }
+            if exists.unwrap() {
+                // either ours from before a restart, or someone else's, which we use but never remove
+                if self.ledger.lock().unwrap().add_hostname(ip, hostname) {
+                    self.ledger_changed.notify();
+                }
+                *routed = true;
+                break false;
+            }
+            self.ledger.lock().unwrap().record(*ip, gateway.clone(), hostname);
+            self.save_ledger().await;
+            let added = self.manager.add_route(*ip, gateway).await;
+            if added.is_err() {
+                error!("RouteRegistry.ensure_route: error creating static route to {}: {:?}", ip, added.err().unwrap());
+                self.ledger.lock().unwrap().forget(ip);
+                self.ledger_changed.notify();
+                return false;
+            }
+            *routed = true;
+            break true;
+        };
+        if added {
+            self.enforce_max_routes(ip).await;
+        }
+        true
+    }
+
+    /// Connect to addr so the connection leaves through an uplink and not the VPN: through a host
+    /// route in EgressMode::Routes, or from a socket bound to the physical interface in EgressMode::Bind.
+    /// The uplink is the one requested by the bubble or chosen by the uplink rules, if it is healthy.
+    /// Never falls back to an unbound connection, which would go out through the VPN.
+    pub async fn connect (&self, addr : SocketAddr, hostname : &str, requested_uplink : Option<&str>) -> std::io::Result<TcpStream> {
+        let uplink = self.uplinks.select(hostname, &addr.ip(), requested_uplink);
+        let gateway = self.next_hop(&uplink, &addr.ip());
+        if gateway.is_none() {
+            return Err(Error::new(ErrorKind::AddrNotAvailable, format!("no {} gateway to reach {} through", IpFamily::of(&addr.ip()), addr.ip())));
+        }
+        let gateway = gateway.unwrap();
+        trace!("RouteRegistry.connect: connecting to {} through uplink {} ({})", addr, uplink.name, gateway);
+        match self.egress_mode {
+            EgressMode::Routes => {
+                if !self.ensure_route(&addr.ip(), hostname, gateway).await {
+                    return Err(Error::new(ErrorKind::Other, format!("error creating static route to {}", addr.ip())));
+                }
+                TcpStream::connect(addr).await
+            }
+            EgressMode::Bind => connect_bound(&self.egress_binding(gateway).await?, addr).await
+        }
+    }
+
+    async fn egress_binding (&self, gateway : NextHop) -> std::io::Result<EgressBinding> {
+        if let Some(binding) = self.bindings.lock().unwrap().get(&gateway) {
+            return Ok(binding.clone());
+        }
+        let default_gateway = self.gateway();
+        let found = self.manager.egress_binding(gateway.clone()).await;
+        if found.is_err() {
+            let err = found.err().unwrap();
+            error!("RouteRegistry.egress_binding: error finding interface for gateway {}: {:?}", gateway, err);
+            return Err(err);
+        }
+        let binding = found.unwrap();
+        info!("RouteRegistry.egress_binding: binding connections via {} to {}", gateway, binding);
+        // only keep it if the gateway did not change while we were looking
+        if self.gateway() == default_gateway {
+            self.bindings.lock().unwrap().insert(gateway, binding.clone());
+        }
+        Ok(binding)
+    }
+
+    /// Check every uplink once, by connecting to the check target through it
+    pub async fn check_uplinks (&self) {
+        let target = self.uplinks.check_target;
+        for uplink in self.uplinks.uplinks() {
+            let gateway = self.next_hop(uplink, &target.ip());
+            if gateway.is_none() {
+                self.uplinks.set_health(uplink.name.as_str(), Err(format!("no {} gateway to reach {} through", IpFamily::of(&target.ip()), target)));
+                continue;
+            }
+            let gateway = gateway.unwrap();
+            let result = match self.egress_binding(gateway.clone()).await {
+                Ok(binding) => match timeout(Duration::from_secs(UPLINK_CHECK_TIMEOUT_SECS), connect_bound(&binding, target)).await {
+                    Ok(Ok(_)) => Ok(()),
+                    Ok(Err(e)) => Err(format!("error connecting to {} via {}: {}", target, gateway, e)),
+                    Err(_) => Err(format!("timed out connecting to {} via {}", target, gateway))
+                },
+                Err(e) => Err(format!("no interface for {}: {}", gateway, e))
+            };
+            self.uplinks.set_health(uplink.name.as_str(), result);
+        }
+    }
+
+    /// Check the uplinks forever, every check_interval seconds
+    pub async fn watch_uplinks (self : Arc<Self>) {
+        let interval = Duration::from_secs(self.uplinks.check_interval);
+        info!("RouteRegistry.watch_uplinks: checking {} uplinks every {:?}", self.uplinks.uplinks().len(), interval);
+        loop {
+            self.check_uplinks().await;
+            delay_for(interval).await;
+        }
+    }
+
+    /// Note that connections to these addresses are still being made
+    pub async fn touch (&self, ips : &[IpAddr]) {
+        let routes = self.routes.lock().await;
+        for ip in ips {
+            if let Some(entry) = routes.get(ip) {
+                entry.touch();
+            }
+        }
+    }
+
+    /// Mark the route to ip as carrying a tunnel until the returned guard is dropped
+    pub async fn open_tunnel (&self, ip : &IpAddr) -> TunnelGuard {
+        let entry = if self.egress_mode == EgressMode::Routes {
+            self.entry(ip).await
+        } else {
+            // no routes to protect, and nothing to expire the entry later
+            Arc::new(RouteEntry::new())
+        };
+        {
+            let mut usage = entry.usage.lock().unwrap();
+            usage.open_tunnels += 1;
+            usage.last_used = Instant::now();
+        }
+        TunnelGuard { entry }
+    }
+
+    /// Remove the route to ip, if we created it
+    pub async fn remove_route (&self, ip : &IpAddr) -> bool {
+        self.remove_route_if(ip, |_| true).await.unwrap_or(false)
+    }
+
+    // Remove the route to ip if we created it and the condition holds once we have its lock.
+    // Returns None if the condition did not hold, otherwise whether removal succeeded.
+    async fn remove_route_if<F: Fn(&RouteEntry) -> bool> (&self, ip : &IpAddr, condition : F) -> Option<bool> {
+        let entry = self.entry(ip).await;
+        let mut routed = entry.routed.lock().await;
+        if !condition(&entry) {
+            return None;
+        }
+        if !self.ledger.lock().unwrap().contains(ip) {
+            info!("RouteRegistry.remove_route: did not create a route to {}, leaving it alone", ip);
+        } else {
+            let result = self.manager.remove_route(*ip).await;
+            if result.is_err() {
+                error!("RouteRegistry.remove_route: error removing route to {}: {:?}", ip, result.err().unwrap());
+                return Some(false);
+            }
+            self.ledger.lock().unwrap().forget(ip);
+            self.ledger_changed.notify();
+        }
+        *routed = false;
+        self.routes.lock().await.remove(ip);
+        Some(true)
+    }
+
+    // Stop tracking ip, whose route someone else created, if the condition holds once we have its
+    // lock. The route itself is left alone; the next connection to ip looks it up again
+    async fn forget_unowned_if<F: Fn(&RouteEntry) -> bool> (&self, ip : &IpAddr, condition : F) -> bool {
+        let entry = match self.routes.lock().await.get(ip) {
+            Some(entry) => entry.clone(),
+            None => return false
+        };
+        let mut routed = entry.routed.lock().await;
+        if !self.is_current(ip, &entry).await || self.ledger.lock().unwrap().contains(ip) || !condition(&entry) {
+            return false;
+        }
+        *routed = false;
+        self.routes.lock().await.remove(ip);
+        true
+    }
+
+    // addresses of routes in use, least recently used first, with when each was last used.
+    // ours selects the routes we created, otherwise the ones we only use
+    async fn routes_by_last_use (&self, ours : bool) -> Vec<(IpAddr, Instant)> {
+        let routes = self.routes.lock().await;
+        let ledger = self.ledger.lock().unwrap();
+        let mut found: Vec<(IpAddr, Instant)> = routes.iter()
+            .filter(|(ip, _)| ledger.contains(ip) == ours)
+            .map(|(ip, entry)| (*ip, entry.usage.lock().unwrap().last_used))
+            .collect();
+        found.sort_by_key(|(_, last_used)| *last_used);
+        found
+    }
+
+    // Evict least recently used routes until we are back under the limit, never the one just added
+    async fn enforce_max_routes (&self, just_added : &IpAddr) {
+        let by_last_use = self.routes_by_last_use(true).await;
+        if by_last_use.len() <= self.limits.max_routes {
+            return;
+        }
+        let mut excess = by_last_use.len() - self.limits.max_routes;
+        for (ip, _) in by_last_use {
+            if excess == 0 {
+                break;
+            }
+            if ip == *just_added {
+                continue;
+            }
+            if let Some(true) = self.remove_route_if(&ip, |entry| entry.removable(None)).await {
+                info!("RouteRegistry.enforce_max_routes: evicted least recently used route to {}", ip);
+                excess -= 1;
+            }
+        }
+        if excess > 0 {
+            warn!("RouteRegistry.enforce_max_routes: {} routes over the limit of {}, all in use", excess, self.limits.max_routes);
+        }
+    }
+
+    /// Remove routes not used within the idle TTL. Routes we did not create are left in place, but
+    /// are no longer tracked
+    pub async fn expire_idle_routes (&self) {
+        let now = Instant::now();
+        let cutoff = match now.checked_sub(self.limits.idle_ttl) {
+            Some(cutoff) => cutoff,
+            None => return // nothing can have been idle that long yet
+        };
+        for (ip, last_used) in self.routes_by_last_use(true).await {
+            if last_used > cutoff {
+                break;
+            }
+            if let Some(true) = self.remove_route_if(&ip, |entry| entry.removable(Some(cutoff))).await {
+                info!("RouteRegistry.expire_idle_routes: removed route to {}, idle for {:?}", ip, now - last_used);
+            }
+        }
+        for (ip, last_used) in self.routes_by_last_use(false).await {
+            if last_used > cutoff {
+                break;
+            }
+            if self.forget_unowned_if(&ip, |entry| entry.removable(Some(cutoff))).await {
+                debug!("RouteRegistry.expire_idle_routes: stopped tracking route to {} we did not create, idle for {:?}", ip, now - last_used);
+            }
+        }
+    }
+
+    /// Check for idle routes forever, at an interval that suits the idle TTL
+    pub async fn run_expiry (self : Arc<Self>) {
+        let interval = Duration::from_secs((self.limits.idle_ttl.as_secs() / 2).clamp(1, MAX_EXPIRY_INTERVAL_SECS));
+        loop {
+            delay_for(interval).await;
+            self.expire_idle_routes().await;
+        }
+    }
+
+    /// Save the ledger if it changed since it was last saved. The snapshot is taken under the ledger
+    /// lock, but written on a blocking thread after the lock is released
+    pub async fn save_ledger (&self) {
+        let mut saved = self.ledger_saved.lock().await;
+        let snapshot = {
+            let ledger = self.ledger.lock().unwrap();
+            if ledger.version() == *saved {
+                return;
+            }
+            ledger.snapshot()
+        };
+        let snapshot = match snapshot {
+            Some(snapshot) => snapshot,
+            None => return // an in-memory ledger
+        };
+        let version = snapshot.version();
+        let path = snapshot.path().to_path_buf();
+        match tokio::task::spawn_blocking(move || snapshot.write()).await {
+            Ok(Ok(())) => *saved = version,
+            Ok(Err(e)) => error!("RouteRegistry.save_ledger: error writing {}: {:?}", path.display(), e),
+            Err(e) => error!("RouteRegistry.save_ledger: error writing {}: {:?}", path.display(), e)
+        }
+    }
+
+    /// Save the ledger forever, shortly after each change, so a burst of changes is written once
+    pub async fn run_ledger_writer (self : Arc<Self>) {
+        loop {
+            self.ledger_changed.notified().await;
+            delay_for(Duration::from_millis(LEDGER_SAVE_DELAY_MILLIS)).await;
+            self.save_ledger().await;
+        }
+    }
+
+    /// Check the default gateway whenever the routing table changes, or every poll_interval on
+    /// platforms that do not say when it changes
+    pub async fn watch_gateway (self : Arc<Self>, poll_interval : Duration) {
+        let mut changes = self.manager.route_changes();
+        if changes.is_none() {
+            info!("RouteRegistry.watch_gateway: checking default gateway every {:?}", poll_interval);
+        }
+        loop {
+            let stopped = match changes.as_mut() {
+                Some(changes) => {
+                    // keep polling too, in case a notification is missed
+                    match timeout(poll_interval, changes.next()).await {
+                        Ok(Some(_)) => {
+                            delay_for(Duration::from_millis(ROUTE_CHANGE_SETTLE_MILLIS)).await;
+                            while let Some(Some(_)) = changes.next().now_or_never() {}
+                            false
+                        }
+                        Ok(None) => true,
+                        Err(_) => false
+                    }
+                }
+                None => {
+                    delay_for(poll_interval).await;
+                    false
+                }
+            };
+            if stopped {
+                warn!("RouteRegistry.watch_gateway: route change notifications stopped, checking default gateway every {:?}", poll_interval);
+                changes = None;
+            }
+            self.check_gateway().await;
+        }
+    }
+
+    /// Look up the default gateways and, if they changed, move our routes to them. Returns true if
+    /// either changed. Only a change of the default gateway is broadcast to subscribers
+    pub async fn check_gateway (&self) -> bool {
+        let found = self.manager.default_gateway(IpFamily::V4).await;
+        let found_v6 = self.manager.default_gateway(IpFamily::V6).await.ok();
+        let new_gateway = match (found, &found_v6) {
+            (Ok(gateway), _) => gateway,
+            (Err(_), Some(gateway_v6)) => gateway_v6.clone(), // an IPv6-only network
+            (Err(err), None) => {
+                // normal while moving between networks; keep the routes until there is a new gateway
+                debug!("RouteRegistry.check_gateway: no default gateway: {:?}", err);
+                return false;
+            }
+        };
+        let (old_gateway, old_gateway_v6, stale) = {
+            let ledger = self.ledger.lock().unwrap();
+            let old_gateway = self.gateway();
+            let old_gateway_v6 = self.gateway_v6();
+            if new_gateway != old_gateway {
+                let _ = self.gateway_sender.broadcast(new_gateway.clone());
+            }
+            if found_v6 != old_gateway_v6 {
+                *self.gateway_v6.lock().unwrap() = found_v6.clone();
+            }
+            if new_gateway != old_gateway || found_v6 != old_gateway_v6 {
+                self.bindings.lock().unwrap().clear();
+            }
+            // routes through uplinks with their own next hop stay where they are, and routes with
+            // no gateway of their family left stay put until there is one
+            let pinned = self.uplinks.pinned_next_hops();
+            let stale: Vec<(IpAddr, NextHop)> = ledger.entries().into_iter()
+                .filter(|e| !pinned.contains(&e.gateway))
+                .filter_map(|e| match self.gateway_for(&e.destination) {
+                    Some(gateway) if gateway != e.gateway => Some((e.destination, gateway)),
+                    _ => None
+                })
+                .collect();
+            (old_gateway, old_gateway_v6, stale)
+        };
+        let changed = new_gateway != old_gateway || found_v6 != old_gateway_v6;
+        if new_gateway != old_gateway {
+            info!("RouteRegistry.check_gateway: default gateway changed from {} to {}", old_gateway, new_gateway);
+        }
+        if found_v6 != old_gateway_v6 {
+            let describe = |gateway : &Option<NextHop>| gateway.as_ref().map(|g| g.to_string()).unwrap_or_else(|| String::from("none"));
+            info!("RouteRegistry.check_gateway: IPv6 default gateway changed from {} to {}", describe(&old_gateway_v6), describe(&found_v6));
+        }
+        if !stale.is_empty() {
+            if changed {
+                info!("RouteRegistry.check_gateway: moving {} static routes", stale.len());
+            } else {
+                info!("RouteRegistry.check_gateway: retrying move of {} static routes", stale.len());
+            }
+        }
+        for (ip, gateway) in stale {
+            self.reroute(&ip, gateway).await;
+        }
+        changed
+    }
+
+    // Point a route we created at gateway. If the new route cannot be added, the address is
+    // forgotten, so the next connection to it tries again from scratch
+    async fn reroute (&self, ip : &IpAddr, gateway : NextHop) -> bool {
+        let entry = self.entry(ip).await;
+        let mut routed = entry.routed.lock().await;
+        if !self.ledger.lock().unwrap().contains(ip) {
+            return true; // removed while we waited for the lock
+        }
+        self.reroute_locked(ip, gateway, &mut routed).await
+    }
+
+    // reroute, with the lock on the address already held
+    async fn reroute_locked (&self, ip : &IpAddr, gateway : NextHop, routed : &mut bool) -> bool {
+        let removed = self.manager.remove_route(*ip).await;
+        if removed.is_err() {
+            error!("RouteRegistry.reroute: error removing old route to {}: {:?}", ip, removed.err().unwrap());
+            return false;
+        }
+        self.ledger.lock().unwrap().set_gateway(ip, gateway.clone());
+        self.ledger_changed.notify();
+        let added = self.manager.add_route(*ip, gateway.clone()).await;
+        if added.is_err() {
+            error!("RouteRegistry.reroute: error creating static route to {} via {}: {:?}", ip, gateway, added.err().unwrap());
+            self.ledger.lock().unwrap().forget(ip);
+            self.ledger_changed.notify();
+            *routed = false;
+            self.routes.lock().await.remove(ip);
+            return false;
+        }
+        trace!("RouteRegistry.reroute: route to {} now via {}", ip, gateway);
+        *routed = true;
+        true
+    }
+
+    /// Remove every route in the ledger: at startup, for routes a previous run left behind, and on
+    /// unregister and shutdown. A route is only removed if it still goes through the gateway we
+    /// created it with; if it has been replaced, it is no longer ours.
+    pub async fn flush (&self) -> bool {
+        let entries = self.ledger.lock().unwrap().entries();
+        info!("RouteRegistry.flush: flushing {} static routes...", entries.len());
+        let mut all_ok = true;
+        let gateways: HashSet<NextHop> = entries.iter().map(|e| e.gateway.clone()).collect();
+        let mut current_by_gateway: HashMap<NextHop, HashSet<IpAddr>> = HashMap::new();
+        for gateway in gateways {
+            let found = self.manager.host_routes_via(gateway.clone()).await;
+            if found.is_err() {
+                error!("RouteRegistry.flush: error listing static routes via {}: {:?}", gateway, found.err().unwrap());
+                all_ok = false;
+            } else {
+                current_by_gateway.insert(gateway, found.unwrap().into_iter().collect());
+            }
+        }
+        for entry in entries {
+            match current_by_gateway.get(&entry.gateway) {
+                None => {} // could not list routes via this gateway, try again next time
+                Some(current) if current.contains(&entry.destination) => {
+                    info!("RouteRegistry.flush: flushing route: {} (created for {:?})", entry.destination, entry.hostnames);
+                    if !self.remove_route(&entry.destination).await {
+                        all_ok = false;
+                    }
+                }
+                Some(_) => {
+                    warn!("RouteRegistry.flush: route to {} via {} is already gone", entry.destination, entry.gateway);
+                    self.ledger.lock().unwrap().forget(&entry.destination);
+                    self.routes.lock().await.remove(&entry.destination);
+                }
+            }
+        }
+        // flush runs at shutdown, so save now rather than leave it to the background writer
+        self.save_ledger().await;
+        if all_ok {
+            trace!("RouteRegistry.flush: all static routes flushed");
+        } else {
+            error!("RouteRegistry.flush: error flushing static routes");
+        }
+        all_ok
+    }
+}
@@ -0,0 +1,292 @@
+#![deny(warnings)]
+/**
+ * Copyright (c) 2020 Bubble, Inc.  All rights reserved.
+ * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
+ */
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use log::{debug, error, info, trace};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use trust_dns_resolver::TokioAsyncResolver;
+
+use crate::connector::connect_routed;
+use crate::dns_cache::ResolverCache;
+use crate::ping::credential_matches;
+use crate::policy::Policy;
+use crate::proxy::{pipe, route_host, RouteError};
+use crate::routes::RouteRegistry;
+
+const SOCKS_VERSION: u8 = 0x05;
+
+const SOCKS_AUTH_NONE: u8 = 0x00;
+const SOCKS_AUTH_PASSWORD: u8 = 0x02;
+const SOCKS_AUTH_NO_ACCEPTABLE: u8 = 0xff;
+
+// RFC 1929 username/password sub-negotiation
+const SOCKS_PASSWORD_VERSION: u8 = 0x01;
+const SOCKS_PASSWORD_SUCCESS: u8 = 0x00;
+const SOCKS_PASSWORD_FAILURE: u8 = 0x01;
+
+const SOCKS_CMD_CONNECT: u8 = 0x01;
+
+const SOCKS_ATYP_IPV4: u8 = 0x01;
+const SOCKS_ATYP_DOMAIN: u8 = 0x03;
+const SOCKS_ATYP_IPV6: u8 = 0x04;
+
+const SOCKS_REPLY_SUCCEEDED: u8 = 0x00;
+const SOCKS_REPLY_GENERAL_FAILURE: u8 = 0x01;
+const SOCKS_REPLY_NOT_ALLOWED: u8 = 0x02;
+const SOCKS_REPLY_HOST_UNREACHABLE: u8 = 0x04;
+const SOCKS_REPLY_CONNECTION_REFUSED: u8 = 0x05;
+const SOCKS_REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const SOCKS_REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+pub async fn start_socks(
+    socks_port: u16,
+    routes: Arc<RouteRegistry>,
+    resolver: Arc<TokioAsyncResolver>,
+    resolver_cache: ResolverCache,
+    policy: Arc<Policy>,
+    proxy_auth: Option<Arc<String>>,
+) {
+    let socks_local_ip: IpAddr = "127.0.0.1".parse().unwrap();
+    let addr = SocketAddr::from((socks_local_ip, socks_port));
+    let listener_result = std::net::TcpListener::bind(addr).and_then(|l| {
+        l.set_nonblocking(true)?;
+        TcpListener::from_std(l)
+    });
+    if listener_result.is_err() {
+        error!(
+            "start_socks: error binding {}: {:?}",
+            addr,
+            listener_result.err().unwrap()
+        );
+        return;
+    }
+    let mut listener = listener_result.unwrap();
+    info!("start_socks: SOCKS5 proxy listening on {}", addr);
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                trace!("start_socks: accepted connection from {}", peer);
+                let routes = routes.clone();
+                let resolver = resolver.clone();
+                let resolver_cache = resolver_cache.clone();
+                let policy = policy.clone();
+                let proxy_auth = proxy_auth.clone();
+                tokio::spawn(async move {
+                    if let Err(e) =
+                        socks(stream, routes, resolver, resolver_cache, policy, proxy_auth).await
+                    {
+                        debug!(
+                            "start_socks: connection from {} ended with error: {}",
+                            peer, e
+                        );
+                    }
+                });
+            }
+            Err(e) => error!("start_socks: error accepting connection: {}", e),
+        }
+    }
+}
+
+async fn socks(
+    mut stream: TcpStream,
+    routes: Arc<RouteRegistry>,
+    resolver: Arc<TokioAsyncResolver>,
+    resolver_cache: ResolverCache,
+    policy: Arc<Policy>,
+    proxy_auth: Option<Arc<String>>,
+) -> std::io::Result<()> {
+    // greeting: VER NMETHODS METHODS...
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    if header[0] != SOCKS_VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported SOCKS version: {}", header[0]),
+        ));
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    if let Some(auth) = proxy_auth {
+        if !methods.contains(&SOCKS_AUTH_PASSWORD) {
+            stream
+                .write_all(&[SOCKS_VERSION, SOCKS_AUTH_NO_ACCEPTABLE])
+                .await?;
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "client does not support username/password SOCKS auth",
+            ));
+        }
+        stream
+            .write_all(&[SOCKS_VERSION, SOCKS_AUTH_PASSWORD])
+            .await?;
+        authenticate(&mut stream, auth).await?;
+    } else {
+        if !methods.contains(&SOCKS_AUTH_NONE) {
+            stream
+                .write_all(&[SOCKS_VERSION, SOCKS_AUTH_NO_ACCEPTABLE])
+                .await?;
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "client does not support unauthenticated SOCKS",
+            ));
+        }
+        stream.write_all(&[SOCKS_VERSION, SOCKS_AUTH_NONE]).await?;
+    }
+
+    // request: VER CMD RSV ATYP DST.ADDR DST.PORT
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request).await?;
+    if request[0] != SOCKS_VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported SOCKS version: {}", request[0]),
+        ));
+    }
+    let host = match request[3] {
+        SOCKS_ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            stream.read_exact(&mut octets).await?;
+            Ipv4Addr::from(octets).to_string()
+        }
+        SOCKS_ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            stream.read_exact(&mut octets).await?;
+            Ipv6Addr::from(octets).to_string()
+        }
+        SOCKS_ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            let mut name = vec![0u8; len[0] as usize];
+            stream.read_exact(&mut name).await?;
+            match String::from_utf8(name) {
+                Ok(name) => name,
+                Err(_) => {
+                    reply(&mut stream, SOCKS_REPLY_GENERAL_FAILURE, None).await?;
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "domain name was not valid UTF-8",
+                    ));
+                }
+            }
+        }
+        atyp => {
+            reply(&mut stream, SOCKS_REPLY_ADDRESS_TYPE_NOT_SUPPORTED, None).await?;
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported address type: {}", atyp),
+            ));
+        }
+    };
+    let mut port = [0u8; 2];
+    stream.read_exact(&mut port).await?;
+    let port = u16::from_be_bytes(port);
+
+    if request[1] != SOCKS_CMD_CONNECT {
+        reply(&mut stream, SOCKS_REPLY_COMMAND_NOT_SUPPORTED, None).await?;
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported command: {}", request[1]),
+        ));
+    }
+
+    trace!(
+        "socks: received CONNECT for {}:{}, resolving...",
+        host,
+        port
+    );
+    let route_result = route_host(host.as_str(), port, &resolver, resolver_cache, &policy).await;
+    if route_result.is_err() {
+        let err = route_result.err().unwrap();
+        let code = match err {
+            RouteError::PolicyViolation(_) => SOCKS_REPLY_NOT_ALLOWED,
+            _ => SOCKS_REPLY_HOST_UNREACHABLE,
+        };
+        reply(&mut stream, code, None).await?;
+        return Err(Error::other(err.to_string().trim().to_string()));
+    }
+    let addrs = route_result.unwrap();
+
+    // connect first, so the client gets a meaningful reply code if the destination is down.
+    // SOCKS5 has no headers, so the uplink always comes from the uplink rules
+    let server = connect_routed(host.as_str(), addrs, port, routes.clone(), None).await;
+    if server.is_err() {
+        let err = server.err().unwrap();
+        let code = match err.kind() {
+            ErrorKind::ConnectionRefused => SOCKS_REPLY_CONNECTION_REFUSED,
+            _ => SOCKS_REPLY_HOST_UNREACHABLE,
+        };
+        reply(&mut stream, code, None).await?;
+        return Err(err);
+    }
+    let server = server.unwrap();
+    reply(&mut stream, SOCKS_REPLY_SUCCEEDED, server.local_addr().ok()).await?;
+    debug!(
+        "socks: tunneling {}:{} via {:?}",
+        host,
+        port,
+        server.peer_addr()
+    );
+    let _route_in_use = routes.open_tunnel(&server.peer_addr()?.ip()).await;
+    pipe(stream, server).await
+}
+
+// VER ULEN UNAME PLEN PASSWD; like the HTTP proxy, only the password is checked
+async fn authenticate(stream: &mut TcpStream, expected: Arc<String>) -> std::io::Result<()> {
+    let mut version = [0u8; 2];
+    stream.read_exact(&mut version).await?;
+    if version[0] != SOCKS_PASSWORD_VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported SOCKS auth version: {}", version[0]),
+        ));
+    }
+    let mut username = vec![0u8; version[1] as usize];
+    stream.read_exact(&mut username).await?;
+    let mut len = [0u8; 1];
+    stream.read_exact(&mut len).await?;
+    let mut password = vec![0u8; len[0] as usize];
+    stream.read_exact(&mut password).await?;
+    if credential_matches(&password, expected.as_bytes()) {
+        stream
+            .write_all(&[SOCKS_PASSWORD_VERSION, SOCKS_PASSWORD_SUCCESS])
+            .await
+    } else {
+        error!("socks: invalid proxy credentials");
+        stream
+            .write_all(&[SOCKS_PASSWORD_VERSION, SOCKS_PASSWORD_FAILURE])
+            .await?;
+        Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "invalid proxy credentials",
+        ))
+    }
+}
+
+async fn reply(stream: &mut TcpStream, code: u8, bound: Option<SocketAddr>) -> std::io::Result<()> {
+    let mut response = vec![SOCKS_VERSION, code, 0x00];
+    match bound {
+        Some(SocketAddr::V6(addr)) => {
+            response.push(SOCKS_ATYP_IPV6);
+            response.extend_from_slice(&addr.ip().octets());
+            response.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        Some(SocketAddr::V4(addr)) => {
+            response.push(SOCKS_ATYP_IPV4);
+            response.extend_from_slice(&addr.ip().octets());
+            response.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        None => {
+            response.push(SOCKS_ATYP_IPV4);
+            response.extend_from_slice(&[0u8; 6]);
+        }
+    }
+    stream.write_all(&response).await
+}
@@ -4,447 +4,633 @@
  * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
  */
 
+use std::fmt;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
 use std::path::Path;
-use std::process::exit;
-use std::process::{Command, Stdio, Child};
-use std::io::Error;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
 
-use futures::future::{abortable, AbortHandle};
+use log::{debug, info, warn, error, trace};
 
-use log::{debug, info, error, trace};
+#[cfg(unix)]
+use ssh2::BlockDirections;
+use ssh2::{Channel, ErrorCode, Listener, Session};
 
-use reqwest;
-use reqwest::header::{HeaderMap, HeaderValue};
-use reqwest::StatusCode as ReqwestStatusCode;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::Duration;
 
-use tokio::time::{interval_at, Instant, Duration};
-use tokio::sync::Mutex;
+const SSH_PORT: u16 = 22;
+const SSH_USER: &'static str = "bubble-flex";
+const SSH_CONNECT_TIMEOUT: u64 = 20;
+const SSH_KEEPALIVE_INTERVAL: u32 = 10;
+const SSH_REMOTE_BIND_HOST: &'static str = "localhost";
+#[cfg(not(unix))]
+const SSH_IDLE_SLEEP_MILLIS: u64 = 5;
+const SSH_BUFFER_SIZE: usize = 16 * 1024;
 
-use whoami::{platform, Platform};
+// the libssh2 error codes we tell apart
+const LIBSSH2_ERROR_FILE: i32 = -16;
+const LIBSSH2_ERROR_AUTHENTICATION_FAILED: i32 = -18;
+const LIBSSH2_ERROR_PUBLICKEY_UNVERIFIED: i32 = -19;
+const LIBSSH2_ERROR_REQUEST_DENIED: i32 = -32;
 
-use crate::util::{HEADER_BUBBLE_SESSION, write_string_to_file, now_micros};
+static NEXT_TUNNEL_ID: AtomicU64 = AtomicU64::new(1);
 
-const SSH_WINDOWS: &'static str = "C:\\Windows\\System32\\OpenSSH\\ssh.exe";
-const SSH_WINDOWS_CYGWIN: &'static str = "C:\\cygwin64\\bin\\ssh.exe";
-const SSH_MACOS: &'static str = "/usr/bin/ssh";
-const SSH_LINUX: &'static str = "/usr/bin/ssh";
+/// Where connections arriving through the tunnel are delivered: the proxy's TCP port on 127.0.0.1,
+/// or its Unix domain socket, which (unlike a loopback port) other local accounts cannot reach
+#[derive(Debug, Clone)]
+pub enum TunnelTarget {
+    Port (u16),
+    Socket (String)
+}
+
+impl std::fmt::Display for TunnelTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TunnelTarget::Port(port) => write!(f, "127.0.0.1:{}", port),
+            TunnelTarget::Socket(path) => write!(f, "unix:{}", path)
+        }
+    }
+}
 
-pub fn ssh_command() -> &'static str {
-    let platform: Platform = platform();
-    return match platform {
-        Platform::Windows => {
-            if Path::new(SSH_WINDOWS).exists() {
-                SSH_WINDOWS
-            } else if Path::new(SSH_WINDOWS_CYGWIN).exists() {
-                SSH_WINDOWS_CYGWIN
-            } else {
-                error!("ssh_command: ssh executable was not found in either {} or {}", SSH_WINDOWS, SSH_WINDOWS_CYGWIN);
-                exit(2)
+impl TunnelTarget {
+    fn connect(&self) -> Result<LocalStream, Error> {
+        match self {
+            TunnelTarget::Port(port) => {
+                let stream = TcpStream::connect(("127.0.0.1", *port))?;
+                stream.set_nonblocking(true)?;
+                Ok(LocalStream::Tcp(stream))
             }
-        },
-        Platform::MacOS => {
-            if Path::new(SSH_MACOS).exists() {
-                SSH_MACOS
-            } else {
-                error!("ssh_command: ssh executable was not found: {}", SSH_MACOS);
-                exit(2)
+            #[cfg(unix)]
+            TunnelTarget::Socket(path) => {
+                let stream = UnixStream::connect(path)?;
+                stream.set_nonblocking(true)?;
+                Ok(LocalStream::Unix(stream))
             }
-        },
-        Platform::Linux => {
-            if Path::new(SSH_LINUX).exists() {
-                SSH_LINUX
-            } else {
-                error!("ssh_command: ssh executable was not found: {}", SSH_LINUX);
-                exit(2)
+            #[cfg(not(unix))]
+            TunnelTarget::Socket(path) => {
+                Err(Error::new(ErrorKind::Other, format!("unix sockets are not supported on this platform: {}", path)))
             }
-        },
-        _ => {
-            error!("ssh_command: unsupported platform: {:?}", platform);
-            exit(2);
         }
     }
 }
 
+enum LocalStream {
+    Tcp (TcpStream),
+    #[cfg(unix)]
+    Unix (UnixStream)
+}
+
+impl LocalStream {
+    fn shutdown(&self, how : Shutdown) -> Result<(), Error> {
+        match self {
+            LocalStream::Tcp(s) => s.shutdown(how),
+            #[cfg(unix)]
+            LocalStream::Unix(s) => s.shutdown(how)
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for LocalStream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            LocalStream::Tcp(s) => s.as_raw_fd(),
+            LocalStream::Unix(s) => s.as_raw_fd()
+        }
+    }
+}
+
+impl Read for LocalStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            LocalStream::Tcp(s) => s.read(buf),
+            #[cfg(unix)]
+            LocalStream::Unix(s) => s.read(buf)
+        }
+    }
+}
+
+impl Write for LocalStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match self {
+            LocalStream::Tcp(s) => s.write(buf),
+            #[cfg(unix)]
+            LocalStream::Unix(s) => s.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match self {
+            LocalStream::Tcp(s) => s.flush(),
+            #[cfg(unix)]
+            LocalStream::Unix(s) => s.flush()
+        }
+    }
+}
+
+/// Why a tunnel could not be opened
 #[derive(Debug)]
-pub struct SshContainer {
-    pub child: Option<Mutex<Child>>,
-    pub ip: Option<Arc<String>>,
-    pub port: Option<u16>,
-    pub proxy_port: Option<u16>,
-    pub bubble: Option<Arc<String>>,
-    pub session: Option<Arc<String>>,
-    pub host_key: Option<String>,
-    pub priv_key: Option<Arc<String>>,
-    pub checker_done_flag: u128,
-    pub checker_abort_handle: Option<Arc<Mutex<AbortHandle>>>
+pub enum TunnelError {
+    /// nothing answered on the bubble's ssh port
+    Unreachable (Error),
+    /// the bubble presented a host key other than the one it registered
+    HostKeyMismatch { presented: String },
+    /// our ssh key could not be read
+    KeyFile (String),
+    /// the bubble did not accept our ssh key
+    AuthRefused (String),
+    /// the bubble would not listen on the tunnel port, usually because something there already has it bound
+    PortInUse (u16),
+    /// we were let in, but remote port forwarding failed for another reason
+    ForwardingFailed (String),
+    /// any other ssh failure, such as a failed handshake
+    Ssh (Error)
 }
 
-impl SshContainer {
-    pub fn new () -> SshContainer {
-        SshContainer {
-            child: None,
-            ip: None,
-            port: None,
-            proxy_port: None,
-            bubble: None,
-            session: None,
-            host_key: None,
-            priv_key: None,
-            checker_done_flag: 0,
-            checker_abort_handle: None
+impl fmt::Display for TunnelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TunnelError::Unreachable(e) => write!(f, "bubble ssh port is unreachable: {}", e),
+            TunnelError::HostKeyMismatch { presented } => write!(f, "bubble host key did not match registered host key, bubble presented {}", presented),
+            TunnelError::KeyFile(e) => write!(f, "error reading ssh key: {}", e),
+            TunnelError::AuthRefused(e) => write!(f, "bubble refused our ssh key: {}", e),
+            TunnelError::PortInUse(port) => write!(f, "bubble could not listen on port {}, it is probably already bound", port),
+            TunnelError::ForwardingFailed(e) => write!(f, "remote port forwarding failed: {}", e),
+            TunnelError::Ssh(e) => write!(f, "ssh error: {}", e)
         }
     }
 }
 
-pub async fn spawn_ssh (ssh_container : Arc<Mutex<SshContainer>>,
-                        ip : Arc<String>,
-                        port : u16,
-                        proxy_port : u16,
-                        bubble : Arc<String>,
-                        session : Arc<String>,
-                        host_key : String,
-                        priv_key : Arc<String>,
-                        check_ssh_interval : u64) -> Result<bool, Option<Error>> {
-    let mut guard = ssh_container.lock().await;
-    if (*guard).child.is_some() {
-        info!("spawn_ssh: ssh tunnel exists, not respawning");
-        Ok(true)
-    } else {
-        let tunnel = format!("{}:127.0.0.1:{}", port, proxy_port);
-        let target = format!("bubble-flex@{}", bubble);
-        let host_file = host_file();
-        let host_file_result = write_string_to_file(host_file, host_key.clone().to_string());
-        if host_file_result.is_err() {
-            let err = host_file_result.err();
-            if err.is_some() {
-                let err = err.unwrap();
-                if err.is_some() {
-                    Err(Some(err.unwrap()))
-                } else {
-                    Err(None)
-                }
-            } else {
-                Err(None)
-            }
-        } else {
-            let mut command = Command::new(ssh_command());
-            build_ssh_command(&mut command,tunnel, target, host_file.to_string(), priv_key.clone());
-            let result = command.spawn();
-            let child;
-            if result.is_ok() {
-                child = result.unwrap();
-                (*guard).child = Some(Mutex::new(child));
-                (*guard).ip = Some(ip.clone());
-                (*guard).port = Some(port);
-                (*guard).proxy_port = Some(proxy_port);
-                (*guard).bubble = Some(bubble.clone());
-                (*guard).session = Some(session.clone());
-                (*guard).host_key = Some(host_key.clone());
-                (*guard).priv_key = Some(priv_key.clone());
-                let check_host = bubble.clone();
-                let check_ip = ip.clone();
-                let check_session = session.clone();
-                trace!("spawn_ssh: starting abortable checker");
-                let task = tokio::spawn(check_ssh(ssh_container.clone(), check_host, check_ip, check_session, check_ssh_interval));
-                let (_fut, abort_handle) = abortable(task);
-                (*guard).checker_abort_handle = Some(Arc::new(Mutex::new(abort_handle)));
-                Ok(true)
-            } else {
-                let err = result.err();
-                if err.is_none() {
-                    Err(None)
-                } else {
-                    Err(Some(err.unwrap()))
+impl std::error::Error for TunnelError {}
+
+/// The step of opening a tunnel at which libssh2 reported an error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunnelStage {
+    /// logging in to the bubble with our ssh key
+    Authenticate,
+    /// asking the bubble to listen on the tunnel port
+    Forward
+}
+
+/// The TunnelError for an error libssh2 returned at stage. priv_key and port are only used to describe it
+pub fn classify_ssh_error(stage : TunnelStage, e : ssh2::Error, priv_key : &str, port : u16) -> TunnelError {
+    let code = match e.code() {
+        ErrorCode::Session(code) => Some(code),
+        _ => None
+    };
+    match (stage, code) {
+        (TunnelStage::Authenticate, Some(LIBSSH2_ERROR_FILE)) => TunnelError::KeyFile(format!("{}: {}", priv_key, e.message())),
+        (TunnelStage::Authenticate, Some(LIBSSH2_ERROR_AUTHENTICATION_FAILED))
+        | (TunnelStage::Authenticate, Some(LIBSSH2_ERROR_PUBLICKEY_UNVERIFIED)) => TunnelError::AuthRefused(String::from(e.message())),
+        (TunnelStage::Authenticate, _) => TunnelError::Ssh(Error::from(e)),
+        (TunnelStage::Forward, Some(LIBSSH2_ERROR_REQUEST_DENIED)) => TunnelError::PortInUse(port),
+        (TunnelStage::Forward, _) => TunnelError::ForwardingFailed(String::from(e.message()))
+    }
+}
+
+/// How log lines name the tunnel of the registration of VPN address ip
+pub fn tunnel_label(ip : &str, bubble : &str, port : u16) -> String {
+    format!("{} via {}:{}", ip, bubble, port)
+}
+
+/// A reverse tunnel running inside this process. The bubble listens on `port` and
+/// every connection it accepts there is forwarded to our proxy's TunnelTarget.
+/// The forwarding loop runs on its own thread, since libssh2 is a blocking library.
+#[derive(Debug)]
+pub struct SshTunnel {
+    id: u64,
+    stop: Arc<AtomicBool>,
+    // wakes the forwarding thread from waiting on its sockets
+    #[cfg(unix)]
+    waker: UnixStream,
+    thread: Option<thread::JoinHandle<()>>
+}
+
+/// Sent when a tunnel's forwarding thread ends, whether it was stopped or the connection failed
+#[derive(Debug, Clone)]
+pub struct TunnelExit {
+    pub id: u64,
+    pub reason: String
+}
+
+impl SshTunnel {
+    pub fn id(&self) -> u64 { self.id }
+
+    pub fn stop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        #[cfg(unix)]
+        let _ = (&self.waker).write(&[1]);
+        // join the forwarding thread off the async runtime, so no finished thread is left unjoined
+        if let Some(thread) = self.thread.take() {
+            let id = self.id;
+            let join = move || {
+                if thread.join().is_err() {
+                    error!("SshTunnel.stop: forwarding thread of tunnel {} panicked", id);
                 }
+            };
+            match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => { runtime.spawn_blocking(join); }
+                Err(_) => join()
             }
         }
     }
 }
 
-pub async fn respawn_ssh (ssh_container : Arc<Mutex<SshContainer>>,
-                          ip : Arc<String>,
-                          port : u16,
-                          proxy_port : u16,
+impl Drop for SshTunnel {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Open the tunnel for the registration of VPN address ip. Every log line about the tunnel names
+/// the registration, so a failure can be traced to the device and bubble it belongs to
+pub async fn open_tunnel (ip : Arc<String>,
                           bubble : Arc<String>,
-                          session : Arc<String>,
+                          port : u16,
+                          proxy_target : TunnelTarget,
                           host_key : String,
                           priv_key : Arc<String>,
-                          checker_abort_handler : Arc<Mutex<AbortHandle>>) -> Result<bool, Option<Error>> {
-    let mut guard = ssh_container.lock().await;
-    if (*guard).child.is_some() {
-        info!("respawn_ssh: ssh tunnel exists, not respawning");
-        Ok(true)
-    } else {
-        let tunnel = format!("{}:127.0.0.1:{}", port, proxy_port);
-        let target = format!("bubble-flex@{}", bubble);
-        let host_file = host_file();
-        let host_file_result = write_string_to_file(host_file, host_key.clone().to_string());
-        if host_file_result.is_err() {
-            let err = host_file_result.err();
-            if err.is_some() {
-                let err = err.unwrap();
-                if err.is_some() {
-                    Err(Some(err.unwrap()))
-                } else {
-                    Err(None)
-                }
-            } else {
-                Err(None)
-            }
-        } else {
-            let mut command = Command::new(ssh_command());
-            build_ssh_command(&mut command,tunnel, target, host_file.to_string(), priv_key.clone());
-            let result = command.spawn();
-            let child;
-            if result.is_ok() {
-                child = result.unwrap();
-                (*guard) = SshContainer {
-                    child: Some(Mutex::new(child)),
-                    ip: Some(ip.clone()),
-                    port: Some(port),
-                    proxy_port: Some(proxy_port),
-                    bubble: Some(bubble.clone()),
-                    session: Some(session.clone()),
-                    host_key: Some(host_key.clone()),
-                    priv_key: Some(priv_key.clone()),
-                    checker_abort_handle: Some(checker_abort_handler),
-                    checker_done_flag: now_micros()
-                };
-                Ok(true)
-            } else {
-                let err = result.err();
-                if err.is_none() {
-                    Err(None)
-                } else {
-                    Err(Some(err.unwrap()))
-                }
+                          exits : UnboundedSender<TunnelExit>) -> Result<SshTunnel, TunnelError> {
+    let label = Arc::new(tunnel_label(ip.as_str(), bubble.as_str(), port));
+    let connect_label = label.clone();
+    let connect_result = tokio::task::spawn_blocking(
+        move || connect_tunnel(connect_label.as_str(), bubble.as_str(), port, host_key.as_str(), priv_key.as_str())
+    ).await;
+    let (session, listener) = match connect_result {
+        Ok(Ok(connected)) => connected,
+        Ok(Err(e)) => {
+            error!("open_tunnel: [{}] error establishing tunnel: {}", label, e);
+            return Err(e);
+        }
+        Err(join_err) => {
+            error!("open_tunnel: [{}] error establishing tunnel: {:?}", label, join_err);
+            return Err(TunnelError::Ssh(Error::new(ErrorKind::Interrupted, join_err)));
+        }
+    };
+
+    let id = NEXT_TUNNEL_ID.fetch_add(1, Ordering::SeqCst);
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread_stop = stop.clone();
+    #[cfg(unix)]
+    let (waker, thread_waker) = UnixStream::pair().and_then(|(a, b)| {
+        a.set_nonblocking(true)?;
+        b.set_nonblocking(true)?;
+        Ok((a, b))
+    }).map_err(TunnelError::Ssh)?;
+    let thread = thread::Builder::new()
+        .name(format!("ssh-tunnel-{}", port))
+        .spawn(move || {
+            let reason = forward_tunnel(session, listener, label, proxy_target, thread_stop,
+                                        #[cfg(unix)] thread_waker);
+            // nobody listening means nobody cares anymore
+            let _ = exits.send(TunnelExit { id, reason });
+        }).map_err(TunnelError::Ssh)?;
+    Ok(SshTunnel {
+        id,
+        stop,
+        #[cfg(unix)]
+        waker,
+        thread: Some(thread)
+    })
+}
+
+fn connect_tunnel(label : &str,
+                  bubble : &str,
+                  port : u16,
+                  host_key : &str,
+                  priv_key : &str) -> Result<(Session, Listener), TunnelError> {
+    let timeout = Duration::from_secs(SSH_CONNECT_TIMEOUT);
+    let mut last_err = Error::new(ErrorKind::NotFound, format!("no address found for {}", bubble));
+    let mut tcp = None;
+    for addr in (bubble, SSH_PORT).to_socket_addrs().map_err(TunnelError::Unreachable)? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => { tcp = Some(stream); break; }
+            Err(e) => {
+                debug!("connect_tunnel: [{}] error connecting to {}: {}", label, addr, e);
+                last_err = e;
             }
         }
     }
+    let tcp = tcp.ok_or(last_err).map_err(TunnelError::Unreachable)?;
+    trace!("connect_tunnel: [{}] connected to {}:{}", label, bubble, SSH_PORT);
+
+    let mut session = Session::new().map_err(|e| TunnelError::Ssh(Error::from(e)))?;
+    session.set_timeout(timeout.as_millis() as u32);
+    session.set_tcp_stream(tcp);
+    session.handshake().map_err(|e| TunnelError::Ssh(Error::from(e)))?;
+    if let Some(banner) = session.banner() {
+        debug!("connect_tunnel: [{}] bubble ssh server is {}", label, banner);
+    }
+    verify_host_key(label, &session, host_key)?;
+    trace!("connect_tunnel: [{}] host key verified", label);
+
+    let pub_key = format!("{}.pub", priv_key);
+    if let Err(e) = session.userauth_pubkey_file(SSH_USER, Some(Path::new(pub_key.as_str())), Path::new(priv_key), None) {
+        debug!("connect_tunnel: [{}] authentication as {} failed: {}", label, SSH_USER, e);
+        return Err(classify_ssh_error(TunnelStage::Authenticate, e, priv_key, port));
+    }
+    if !session.authenticated() {
+        return Err(TunnelError::AuthRefused(format!("authentication as {} was refused", SSH_USER)));
+    }
+    trace!("connect_tunnel: [{}] authenticated as {}", label, SSH_USER);
+
+    let (listener, bound_port) = match session.channel_forward_listen(port, Some(SSH_REMOTE_BIND_HOST), None) {
+        Ok(forwarded) => forwarded,
+        Err(e) => {
+            debug!("connect_tunnel: [{}] remote port forwarding failed: {}", label, e);
+            return Err(classify_ssh_error(TunnelStage::Forward, e, priv_key, port));
+        }
+    };
+    if bound_port != port {
+        debug!("connect_tunnel: [{}] requested remote port {} but bubble bound {}", label, port, bound_port);
+        return Err(TunnelError::PortInUse(port));
+    }
+    session.set_keepalive(true, SSH_KEEPALIVE_INTERVAL);
+    info!("connect_tunnel: [{}] tunnel established, forwarding to proxy", label);
+    Ok((session, listener))
 }
 
-fn build_ssh_command<'a>(command : &mut Command, tunnel: String, target: String, host_file : String, priv_key : Arc<String>) {
-    let user_known_hosts = format!("UserKnownHostsFile={}", host_file);
-    let server_keepalive = format!("ServerAliveInterval=10");
-    command
-        .stdin(Stdio::null())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .arg("-i")
-        .arg(priv_key.as_str())
-        .arg("-o")
-        .arg(user_known_hosts)
-        .arg("-o")
-        .arg(server_keepalive)
-        .arg("-Nn")
-        .arg("-R")
-        .arg(tunnel)
-        .arg(target);
+fn verify_host_key(label : &str, session : &Session, host_key : &str) -> Result<(), TunnelError> {
+    let (key, key_type) = match session.host_key() {
+        Some(found) => found,
+        None => return Err(TunnelError::Ssh(Error::new(ErrorKind::InvalidData, "bubble did not present a host key")))
+    };
+    let presented = base64::encode(key);
+    // host_key is a known_hosts line: [hostnames] keytype base64-key [comment]
+    if host_key.split_ascii_whitespace().any(|part| part == presented) {
+        Ok(())
+    } else {
+        error!("verify_host_key: [{}] host key mismatch, bubble presented {:?} key {}", label, key_type, presented);
+        Err(TunnelError::HostKeyMismatch { presented })
+    }
 }
 
-const CHECK_SSH_START_DELAY : u64 = 10;
-const MAX_CHECK_ERRORS_BEFORE_RESTART : u8 = 3;
-const CHECK_SSH_HTTP_TIMEOUT: u64 = 10;
-
-async fn check_ssh (ssh_container : Arc<Mutex<SshContainer>>,
-                    bubble : Arc<String>,
-                    ip : Arc<String>,
-                    session : Arc<String>,
-                    check_ssh_interval : u64) -> bool {
-    let mut checker = interval_at(Instant::now().checked_add(Duration::new(CHECK_SSH_START_DELAY, 0)).unwrap(), Duration::new(check_ssh_interval, 0));
-    let check_url = format!("https://{}:1443/api/me/flexRouters/{}/status", bubble.clone(), ip.clone());
-    let mut headers = HeaderMap::new();
-    headers.insert(HEADER_BUBBLE_SESSION,   HeaderValue::from_str(session.to_string().as_str()).unwrap());
-    let client = reqwest::Client::builder()
-        .timeout(Duration::from_secs(CHECK_SSH_HTTP_TIMEOUT))
-        .default_headers(headers)
-        .build().unwrap();
-    let mut error_count : u8 = 0;
-    let mut conn_ok : bool = false;
-    let mut deleted : bool = false;
-    let start_time = now_micros();
-
-    let ip;
-    let port;
-    let proxy_port;
-    let session;
-    let bubble;
-    let host_key;
-    let priv_key;
-    let checker_abort_handle;
-    trace!("check_ssh: locking ssh_container to copy values");
-    {
-        let guard = ssh_container.lock().await;
-        {
-            ip = (*guard).ip.clone().unwrap();
-            port = (*guard).port.unwrap().clone();
-            proxy_port = (*guard).proxy_port.unwrap().clone();
-            session = (*guard).session.clone().unwrap();
-            bubble = (*guard).bubble.clone().unwrap();
-            host_key = (*guard).host_key.clone().unwrap();
-            priv_key = (*guard).priv_key.clone().unwrap();
-            checker_abort_handle = (*guard).checker_abort_handle.clone().unwrap();
+/// One end of a forwarded connection: the ssh channel from the bubble, or the stream to our proxy
+pub trait PumpEnd: Read + Write {
+    /// Called when a read returned no bytes: true if the peer will send nothing more
+    fn at_eof(&self) -> bool;
+    /// Tell the peer we will send nothing more
+    fn close_write(&mut self) -> Result<(), Error>;
+    /// True if data or an EOF has already been received and is waiting to be read
+    fn has_buffered(&self) -> bool { false }
+}
+
+impl PumpEnd for Channel {
+    // a channel in non-blocking mode also reads 0 bytes when nothing has arrived yet
+    fn at_eof(&self) -> bool { self.eof() }
+    fn close_write(&mut self) -> Result<(), Error> { self.send_eof().map_err(Error::from) }
+    // libssh2 queues whatever arrives for any channel while it reads for another
+    fn has_buffered(&self) -> bool { self.read_window().available > 0 || self.eof() }
+}
+
+impl PumpEnd for TcpStream {
+    fn at_eof(&self) -> bool { true }
+    fn close_write(&mut self) -> Result<(), Error> { self.shutdown(Shutdown::Write) }
+}
+
+impl PumpEnd for LocalStream {
+    fn at_eof(&self) -> bool { true }
+    fn close_write(&mut self) -> Result<(), Error> { self.shutdown(Shutdown::Write) }
+}
+
+/// Moves bytes both ways between the bubble and the proxy ends of a forwarded connection, without
+/// ever blocking. Both ends must be in non-blocking mode
+pub struct Pump<B: PumpEnd, P: PumpEnd> {
+    bubble: B,
+    proxy: P,
+    to_proxy: Vec<u8>,
+    to_bubble: Vec<u8>,
+    bubble_eof: bool,
+    proxy_eof: bool,
+    proxy_shutdown: bool,
+    eof_sent: bool
+}
+
+impl<B: PumpEnd, P: PumpEnd> Pump<B, P> {
+    pub fn new (bubble : B, proxy : P) -> Pump<B, P> {
+        Pump {
+            bubble,
+            proxy,
+            to_proxy: Vec::new(),
+            to_bubble: Vec::new(),
+            bubble_eof: false,
+            proxy_eof: false,
+            proxy_shutdown: false,
+            eof_sent: false
         }
     }
-    trace!("check_ssh: copied ssh_container values, awaiting first tick");
-    loop {
-        checker.tick().await;
 
-        trace!("check_ssh: locking ssh_container to examine checker_done_flag");
-        {
-            let guard = ssh_container.lock().await;
-            {
-                if (*guard).checker_done_flag > start_time {
-                    trace!("check_ssh: checker_done_flag activated, returning");
-                    return true;
-                }
-                trace!("check_ssh: checker_done_flag not activated, continuing");
+    /// True once both ends have sent EOF and everything they sent has been delivered
+    pub fn is_done(&self) -> bool {
+        self.bubble_eof && self.proxy_eof && self.to_proxy.is_empty() && self.to_bubble.is_empty()
+    }
+
+    /// Whether the next pump waits to read from, and to write to, the proxy end
+    pub fn proxy_interest(&self) -> (bool, bool) {
+        (self.to_bubble.is_empty() && !self.proxy_eof, !self.to_proxy.is_empty())
+    }
+
+    /// True if the bubble end has already received something the next pump can move
+    pub fn bubble_ready(&self) -> bool {
+        self.to_proxy.is_empty() && !self.bubble_eof && self.bubble.has_buffered()
+    }
+
+    /// Move whatever data is ready in either direction, returns true if any bytes moved
+    pub fn pump(&mut self, buf : &mut [u8]) -> Result<bool, Error> {
+        let mut progress = false;
+
+        if self.to_proxy.is_empty() && !self.bubble_eof {
+            match self.bubble.read(buf) {
+                Ok(0) => { self.bubble_eof = self.bubble.at_eof(); }
+                Ok(n) => { self.to_proxy.extend_from_slice(&buf[..n]); progress = true; }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e)
+            }
+        }
+        if !self.to_proxy.is_empty() {
+            match self.proxy.write(&self.to_proxy) {
+                Ok(n) => { self.to_proxy.drain(..n); progress = true; }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e)
             }
         }
+        if self.bubble_eof && self.to_proxy.is_empty() && !self.proxy_shutdown {
+            let _ = self.proxy.close_write();
+            self.proxy_shutdown = true;
+        }
 
-        trace!("check_ssh: checking status via {}", check_url);
-        let check_result = client.get(check_url.as_str()).send().await;
-        match check_result {
-            Err(e) => {
-                error!("check_ssh: error checking status via {}: {:?}", check_url, e);
-                conn_ok = false;
-            },
-            Ok(response) => {
-                let status_code = response.status();
-                let body_bytes = &response.bytes().await.unwrap();
-                let body = String::from_utf8(body_bytes.to_vec()).unwrap();
-                let server_status = body.replace(|c: char| c == '\"', "");
-                trace!("check_ssh: tunnel status for {} returned status={:?}, body={}", check_url, &status_code, body);
-                match status_code {
-                    ReqwestStatusCode::OK => {
-                        match server_status.as_str() {
-                            "none" => {
-                                info!("check_ssh: checked tunnel status via {}: tunnel status not yet available", check_url);
-                                error_count = error_count + 1;
-                            }
-                            "active" => {
-                                if error_count > 0 || !conn_ok {
-                                    info!("check_ssh: tunnel status via {}: tunnel status is OK", check_url);
-                                } else {
-                                    trace!("check_ssh: tunnel status via {}: tunnel status is OK", check_url);
-                                }
-                                error_count = 0;
-                                conn_ok = true;
-                            }
-                            "unreachable" => {
-                                debug!("check_ssh: tunnel status via {}: tunnel is unreachable", check_url);
-                                error_count = error_count + 1;
-                            }
-                            "deleted" => {
-                                debug!("check_ssh: tunnel status via {}: tunnel was deleted, stopping tunnel", check_url);
-                                deleted = true;
-                            }
-                            _ => {
-                                error!("check_ssh: error checking tunnel status via {}: unknown tunnel status={}", check_url, server_status);
-                                error_count = error_count + 1;
-                            }
-                        }
-                    },
-                    _ => {
-                        error!("check_ssh: error checking tunnel status via {}: status={:?} body={}", check_url, &status_code, body);
-                        conn_ok = false;
-                    }
-                }
-                if deleted {
-                    info!("check_ssh: tunnel deleted, stopping ssh and checker");
-                    stop_ssh_and_checker(ssh_container.clone()).await;
-                    return false;
-
-                } else if error_count >= MAX_CHECK_ERRORS_BEFORE_RESTART {
-                    conn_ok = false;
-                    info!("check_ssh: tunnel had too many errors, restarting ssh tunnel");
-                    trace!("check_ssh: calling stop_ssh_retain_checker");
-                    stop_ssh_retain_checker(ssh_container.clone()).await;
-                    trace!("check_ssh: stop_ssh returned, starting new ssh tunnel");
-                    let ssh_result = respawn_ssh(ssh_container.clone(),
-                                                 ip.clone(),
-                                                 port,
-                                                 proxy_port,
-                                                 bubble.clone(),
-                                                 session.clone(),
-                                                 host_key.clone(),
-                                                 priv_key.clone(),
-                                                 checker_abort_handle.clone()).await;
-                    if ssh_result.is_err() {
-                        let err = ssh_result.err();
-                        if err.is_none() {
-                            error!("check_ssh: error spawning ssh");
-                        } else {
-                            error!("check_ssh: error spawning ssh: {:?}", err.unwrap());
-                        }
-                    } else {
-                        info!("check_ssh: successfully respawned ssh tunnel");
-                    }
-                    error_count = 0;
-                }
+        if self.to_bubble.is_empty() && !self.proxy_eof {
+            match self.proxy.read(buf) {
+                Ok(0) => { self.proxy_eof = self.proxy.at_eof(); }
+                Ok(n) => { self.to_bubble.extend_from_slice(&buf[..n]); progress = true; }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e)
+            }
+        }
+        if !self.to_bubble.is_empty() {
+            match self.bubble.write(&self.to_bubble) {
+                Ok(n) => { self.to_bubble.drain(..n); progress = true; }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e)
             }
         }
+        if self.proxy_eof && self.to_bubble.is_empty() && !self.eof_sent {
+            match self.bubble.close_write() {
+                Ok(_) => { self.eof_sent = true; }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(progress)
     }
 }
 
-const HOST_FILE_WINDOWS: &'static str = "C:\\Windows\\Temp\\bubble_flex_host_key";
-const HOST_FILE_MACOS: &'static str = "/tmp/bubble_flex_host_key";
-const HOST_FILE_LINUX: &'static str = "/tmp/bubble_flex_host_key";
-
-pub fn host_file() -> &'static str {
-    let platform: Platform = platform();
-    return match platform {
-        Platform::Windows => HOST_FILE_WINDOWS,
-        Platform::MacOS => HOST_FILE_MACOS,
-        Platform::Linux => HOST_FILE_LINUX,
-        _ => {
-            error!("host_file: unsupported platform: {:?}", platform);
-            exit(2);
-        }
+type ForwardedConnection = Pump<Channel, LocalStream>;
+
+// block until the bubble's session, the proxy end of a connection or the stop waker has
+// something for us, or until timeout. the session socket is only polled for writing when
+// libssh2 says it is waiting to write
+#[cfg(unix)]
+fn wait_for_ready(session : &Session,
+                  waker : &UnixStream,
+                  connections : &[ForwardedConnection],
+                  timeout : Duration) -> Result<(), Error> {
+    let session_events = match session.block_directions() {
+        BlockDirections::Outbound | BlockDirections::Both => libc::POLLIN | libc::POLLOUT,
+        _ => libc::POLLIN
+    };
+    let mut fds = vec![
+        libc::pollfd { fd: session.as_raw_fd(), events: session_events, revents: 0 },
+        libc::pollfd { fd: waker.as_raw_fd(), events: libc::POLLIN, revents: 0 }
+    ];
+    for conn in connections {
+        let events = match conn.proxy_interest() {
+            (true, true) => libc::POLLIN | libc::POLLOUT,
+            (true, false) => libc::POLLIN,
+            (false, true) => libc::POLLOUT,
+            (false, false) => continue
+        };
+        fds.push(libc::pollfd { fd: conn.proxy.as_raw_fd(), events, revents: 0 });
     }
+    let timeout_millis = timeout.as_millis().min(i32::MAX as u128) as libc::c_int;
+    let rc = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout_millis) };
+    if rc < 0 {
+        let e = Error::last_os_error();
+        return if e.kind() == ErrorKind::Interrupted { Ok(()) } else { Err(e) };
+    }
+    if fds[1].revents != 0 {
+        let mut drain = [0u8; 16];
+        let _ = (&*waker).read(&mut drain);
+    }
+    Ok(())
 }
 
-pub async fn stop_ssh_retain_checker (ssh_container : Arc<Mutex<SshContainer>>) {
-    stop_ssh(ssh_container, false).await
-}
+fn forward_tunnel(session : Session,
+                  mut listener : Listener,
+                  label : Arc<String>,
+                  proxy_target : TunnelTarget,
+                  stop : Arc<AtomicBool>,
+                  #[cfg(unix)] waker : UnixStream) -> String {
+    session.set_blocking(false);
+    let mut reason = String::from("stopped");
+    let mut connections: Vec<ForwardedConnection> = Vec::new();
+    let mut buf = vec![0u8; SSH_BUFFER_SIZE];
+    let mut next_keepalive = std::time::Instant::now();
 
-pub async fn stop_ssh_and_checker (ssh_container : Arc<Mutex<SshContainer>>) {
-    stop_ssh(ssh_container, true).await
-}
+    while !stop.load(Ordering::SeqCst) {
+        let mut progress = false;
 
-pub async fn stop_ssh (ssh_container : Arc<Mutex<SshContainer>>, stop_checker : bool) {
-    let mut guard = ssh_container.lock().await;
-    if stop_checker {
-        if (*guard).checker_abort_handle.is_some() {
-            {
-                trace!("stop_ssh: aborting checker");
-                let checker_guard = (*guard).checker_abort_handle.as_mut().unwrap().lock().await;
-                checker_guard.abort();
-                trace!("stop_ssh: aborted checker");
+        let mut i = 0;
+        while i < connections.len() {
+            let result = connections[i].pump(&mut buf);
+            match result {
+                Ok(moved) => {
+                    progress = progress || moved;
+                    if connections[i].is_done() {
+                        let mut conn = connections.swap_remove(i);
+                        let _ = conn.bubble.close();
+                        continue;
+                    }
+                }
+                Err(e) => {
+                    debug!("forward_tunnel: [{}] closing forwarded connection: {}", label, e);
+                    let mut conn = connections.swap_remove(i);
+                    let _ = conn.bubble.close();
+                    continue;
+                }
             }
-            (*guard).checker_abort_handle = None;
+            i = i + 1;
         }
-        trace!("stop_ssh: setting checker_done_flag = true");
-        (*guard).checker_done_flag = now_micros();
-        trace!("stop_ssh: set checker_done_flag = true");
-    }
-    if (*guard).child.is_some() {
-        {
-            let mut child_guard = (*guard).child.as_mut().unwrap().lock().await;
-            trace!("stop_ssh: killing child process");
-            let kill_result = (*child_guard).kill();
-            if kill_result.is_err() {
-                let err = kill_result.err();
-                if err.is_some() {
-                    error!("stop_ssh: error killing process: {:?}", err.unwrap());
-                } else {
-                    error!("stop_ssh: error killing process");
+
+        if std::time::Instant::now() >= next_keepalive {
+            match session.keepalive_send() {
+                Ok(seconds) => {
+                    next_keepalive = std::time::Instant::now() + Duration::from_secs(seconds.max(1) as u64);
+                }
+                Err(e) => {
+                    let e = Error::from(e);
+                    if e.kind() != ErrorKind::WouldBlock {
+                        error!("forward_tunnel: [{}] keepalive failed: {}", label, e);
+                        reason = format!("keepalive failed: {}", e);
+                        break;
+                    }
+                    // the send buffer is full: try again in a second rather than polling with no timeout
+                    next_keepalive = std::time::Instant::now() + Duration::from_secs(1);
                 }
-            } else {
-                debug!("stop_ssh: killed child ssh process");
             }
         }
-        (*guard).child = None;
+
+        // accept last: every read above may have queued a new connection inside libssh2,
+        // where polling the socket would not see it
+        match listener.accept() {
+            Ok(channel) => {
+                progress = true;
+                match proxy_target.connect() {
+                    Ok(stream) => {
+                        trace!("forward_tunnel: [{}] accepted connection, forwarding to proxy", label);
+                        connections.push(ForwardedConnection::new(channel, stream));
+                    }
+                    Err(e) => error!("forward_tunnel: [{}] error connecting to proxy at {}: {}", label, proxy_target, e)
+                }
+            }
+            Err(e) => {
+                let e = Error::from(e);
+                if e.kind() != ErrorKind::WouldBlock {
+                    error!("forward_tunnel: [{}] tunnel failed: {}", label, e);
+                    reason = format!("tunnel failed: {}", e);
+                    break;
+                }
+            }
+        }
+
+        if progress || connections.iter().any(|conn| conn.bubble_ready()) || stop.load(Ordering::SeqCst) {
+            continue;
+        }
+        #[cfg(unix)]
+        {
+            let timeout = next_keepalive.saturating_duration_since(std::time::Instant::now());
+            if let Err(e) = wait_for_ready(&session, &waker, &connections, timeout) {
+                error!("forward_tunnel: [{}] error waiting for tunnel sockets: {}", label, e);
+                reason = format!("poll failed: {}", e);
+                break;
+            }
+        }
+        #[cfg(not(unix))]
+        thread::sleep(Duration::from_millis(SSH_IDLE_SLEEP_MILLIS));
+    }
+    stop.store(true, Ordering::SeqCst);
+    let _ = session.disconnect(None, "bubble-flexrouter tunnel closed", None);
+    if connections.is_empty() {
+        info!("forward_tunnel: [{}] tunnel closed", label);
+    } else {
+        warn!("forward_tunnel: [{}] tunnel closed with {} open connections", label, connections.len());
     }
-}
\ No newline at end of file
+    reason
+}
@@ -0,0 +1,588 @@
+#![deny(warnings)]
+/**
+ * Copyright (c) 2020 Bubble, Inc.  All rights reserved.
+ * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
+ */
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Arc;
+
+use futures::future::{abortable, AbortHandle};
+
+use log::{debug, info, error, trace};
+
+use serde_derive::Serialize;
+
+use tokio::sync::Mutex;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::{delay_until, interval_at, Duration, Instant};
+
+use crate::backoff::{Backoff, BackoffConfig};
+use crate::bubble_client::{BubbleClient, BubbleError, BubbleRegistration, TunnelStatus};
+use crate::host_keys::{is_valid_bubble_name, HostKeyCheck, HostKeyStore};
+use crate::ssh::{open_tunnel, SshTunnel, TunnelError, TunnelExit, TunnelTarget};
+use crate::util::now_secs;
+
+const CHECK_SSH_START_DELAY : u64 = 10;
+const MAX_CHECK_ERRORS_BEFORE_RESTART : u8 = 3;
+const MAX_TRANSITIONS_KEPT : usize = 20;
+
+/// Where the reverse tunnel to the bubble is in its life
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelState {
+    /// no registration, no tunnel
+    Unregistered,
+    /// registering with the bubble
+    Registering,
+    /// registered, opening the tunnel the bubble asked for
+    Connecting,
+    /// tunnel open and the bubble reports it active
+    Active,
+    /// tunnel open, but the bubble cannot confirm it works
+    Degraded,
+    /// tunnel closed, waiting to open it again
+    Reconnecting,
+    /// too many attempts to open the tunnel failed; waiting for the bubble to be reachable again
+    #[serde(rename = "waiting_for_network")]
+    WaitingForNetwork,
+    /// the bubble removed our registration
+    Deleted
+}
+
+impl TunnelState {
+    pub fn can_transition_to (&self, next : TunnelState) -> bool {
+        use TunnelState::*;
+        match (self, next) {
+            (Unregistered, Registering) => true,
+            (Registering, Connecting) | (Registering, Unregistered) => true,
+            (Connecting, Active) | (Connecting, Reconnecting) | (Connecting, Unregistered) => true,
+            (Active, Degraded) | (Degraded, Active) => true,
+            (Active, _) | (Degraded, _) => matches!(next, Reconnecting | Deleted | Registering | Unregistered),
+            (Reconnecting, _) => matches!(next, Connecting | WaitingForNetwork | Deleted | Registering | Unregistered),
+            (WaitingForNetwork, _) => matches!(next, Reconnecting | Deleted | Registering | Unregistered),
+            (Deleted, Registering) | (Deleted, Unregistered) => true,
+            _ => false
+        }
+    }
+}
+
+impl fmt::Display for TunnelState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TunnelState::Unregistered => "unregistered",
+            TunnelState::Registering => "registering",
+            TunnelState::Connecting => "connecting",
+            TunnelState::Active => "active",
+            TunnelState::Degraded => "degraded",
+            TunnelState::Reconnecting => "reconnecting",
+            TunnelState::WaitingForNetwork => "waiting_for_network",
+            TunnelState::Deleted => "deleted"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TunnelTransition {
+    pub from: TunnelState,
+    pub to: TunnelState,
+    pub reason: String,
+    pub time: u64
+}
+
+/// The tunnel's state and how it got there, for the admin API
+#[derive(Debug, Clone, Serialize)]
+pub struct TunnelReport {
+    pub state: TunnelState,
+    pub since: u64,
+    pub bubble: Option<String>,
+    pub port: Option<u16>,
+    pub transitions: Vec<TunnelTransition>
+}
+
+#[derive(Debug)]
+pub enum RegisterError {
+    /// the bubble refused or did not answer our registration
+    Bubble (BubbleError),
+    /// the bubble accepted our registration, but the tunnel it asked for could not be opened
+    Tunnel (TunnelError),
+    /// the bubble's host key is not the one pinned at our first registration with it
+    HostKeyChanged { bubble: String, pinned: String, offered: String }
+}
+
+impl fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegisterError::Bubble(e) => write!(f, "error registering with bubble: {}", e),
+            RegisterError::Tunnel(e) => write!(f, "error opening tunnel: {}", e),
+            RegisterError::HostKeyChanged { bubble, pinned, offered } => write!(f, "host key of {} changed since it was pinned: pinned {}, offered {}", bubble, pinned, offered)
+        }
+    }
+}
+
+impl std::error::Error for RegisterError {}
+
+// everything needed to open the tunnel again
+#[derive(Clone)]
+struct TunnelSpec {
+    ip: Arc<String>,
+    port: u16,
+    proxy_target: TunnelTarget,
+    bubble_client: Arc<BubbleClient>,
+    host_key: String,
+    priv_key: Arc<String>
+}
+
+// owned by whoever holds the operation lock
+struct TunnelInner {
+    // bumped on every register and unregister, so a checker from an earlier registration
+    // that was waiting for the lock knows to give up
+    generation: u64,
+    spec: Option<TunnelSpec>,
+    tunnel: Option<SshTunnel>,
+    checker: Option<AbortHandle>,
+    // every tunnel of the current registration reports here when its forwarding thread ends
+    exits: Option<UnboundedSender<TunnelExit>>
+}
+
+struct TunnelHistory {
+    state: TunnelState,
+    since: u64,
+    bubble: Option<String>,
+    port: Option<u16>,
+    transitions: VecDeque<TunnelTransition>
+}
+
+/// Owns the tunnel to the bubble, its checker task and its state. Register, unregister and the
+/// checker's reconnects each hold the operation lock for their whole duration, so they never interleave.
+pub struct TunnelManager {
+    inner: Mutex<TunnelInner>,
+    history: std::sync::Mutex<TunnelHistory>,
+    check_ssh_interval: u64,
+    backoff: BackoffConfig,
+    host_keys: HostKeyStore
+}
+
+// what came of an attempt to reopen the tunnel
+enum Reopen {
+    Opened,
+    Failed,
+    // the registration was replaced or removed
+    Skipped
+}
+
+impl TunnelManager {
+    pub fn new (check_ssh_interval : u64, backoff : BackoffConfig, host_keys : HostKeyStore) -> Arc<TunnelManager> {
+        Arc::new(TunnelManager {
+            inner: Mutex::new(TunnelInner { generation: 0, spec: None, tunnel: None, checker: None, exits: None }),
+            history: std::sync::Mutex::new(TunnelHistory {
+                state: TunnelState::Unregistered,
+                since: now_secs(),
+                bubble: None,
+                port: None,
+                transitions: VecDeque::new()
+            }),
+            check_ssh_interval,
+            backoff,
+            host_keys
+        })
+    }
+
+    pub fn state (&self) -> TunnelState {
+        self.history.lock().unwrap().state
+    }
+
+    /// Never waits for a register or reconnect in progress
+    pub fn report (&self) -> TunnelReport {
+        let history = self.history.lock().unwrap();
+        TunnelReport {
+            state: history.state,
+            since: history.since,
+            bubble: history.bubble.clone(),
+            port: history.port,
+            transitions: history.transitions.iter().cloned().collect()
+        }
+    }
+
+    // move to next if that is a legal transition; every transition is logged and kept for the admin API
+    fn transition (&self, next : TunnelState, reason : &str) -> bool {
+        let mut history = self.history.lock().unwrap();
+        let current = history.state;
+        if !current.can_transition_to(next) {
+            error!("TunnelManager.transition: illegal transition {} -> {} ({})", current, next, reason);
+            return false;
+        }
+        info!("TunnelManager.transition: {} -> {} ({})", current, next, reason);
+        let now = now_secs();
+        history.state = next;
+        history.since = now;
+        history.transitions.push_back(TunnelTransition { from: current, to: next, reason: String::from(reason), time: now });
+        if history.transitions.len() > MAX_TRANSITIONS_KEPT {
+            history.transitions.pop_front();
+        }
+        true
+    }
+
+    fn set_endpoint (&self, bubble : Option<String>, port : Option<u16>) {
+        let mut history = self.history.lock().unwrap();
+        history.bubble = bubble;
+        history.port = port;
+    }
+
+    // close the tunnel and stop the checker, leaving the state alone
+    fn shut_down (inner : &mut TunnelInner) {
+        inner.exits = None;
+        if let Some(checker) = inner.checker.take() {
+            trace!("TunnelManager.shut_down: aborting checker");
+            checker.abort();
+        }
+        if let Some(mut tunnel) = inner.tunnel.take() {
+            tunnel.stop();
+            debug!("TunnelManager.shut_down: stopped ssh tunnel");
+        }
+    }
+
+    /// Register with the bubble and open the tunnel it asks for, replacing any current registration
+    pub async fn register (self : &Arc<Self>,
+                           registration : &BubbleRegistration,
+                           bubble_client : Arc<BubbleClient>,
+                           proxy_target : TunnelTarget,
+                           priv_key : Arc<String>) -> Result<(), RegisterError> {
+        // the bubble's host key is pinned in a file named after it
+        if !is_valid_bubble_name(bubble_client.bubble()) {
+            return Err(RegisterError::Bubble(BubbleError::InvalidRequest(format!("not a valid bubble hostname: {}", bubble_client.bubble()))));
+        }
+        let mut inner = self.inner.lock().await;
+        inner.generation += 1;
+        TunnelManager::shut_down(&mut inner);
+        inner.spec = None;
+        self.set_endpoint(Some(String::from(bubble_client.bubble())), None);
+        self.transition(TunnelState::Registering, format!("registering with {}", bubble_client.bubble()).as_str());
+
+        let reg_response = match bubble_client.register(registration).await {
+            Ok(reg_response) => reg_response,
+            Err(e) => {
+                self.set_endpoint(None, None);
+                self.transition(TunnelState::Unregistered, format!("registration failed: {}", e).as_str());
+                return Err(RegisterError::Bubble(e));
+            }
+        };
+        trace!("TunnelManager.register: registered, bubble responded: {:?}", reg_response);
+        let bubble = bubble_client.bubble();
+        match self.host_keys.check(bubble, reg_response.host_key.as_str()) {
+            Ok(HostKeyCheck::Pinned) => info!("TunnelManager.register: first registration with {}, pinned its host key", bubble),
+            Ok(HostKeyCheck::Unchanged) => trace!("TunnelManager.register: host key of {} matches pinned key", bubble),
+            Err(e) => {
+                error!("TunnelManager.register: error checking host key of {}, not opening tunnel: {}", bubble, e);
+                withdraw(&bubble_client, registration.ip.as_str()).await;
+                self.set_endpoint(None, None);
+                self.transition(TunnelState::Unregistered, format!("error checking host key: {}", e).as_str());
+                return Err(RegisterError::Tunnel(TunnelError::Ssh(e)));
+            }
+            Ok(HostKeyCheck::Changed { pinned }) => {
+                error!("TunnelManager.register: HOST KEY OF {} HAS CHANGED, not opening tunnel. pinned: {} offered: {}", bubble, pinned, reg_response.host_key);
+                // the bubble accepted our registration, but we will not use it
+                withdraw(&bubble_client, registration.ip.as_str()).await;
+                self.set_endpoint(None, None);
+                self.transition(TunnelState::Unregistered, format!("host key of {} changed since it was pinned", bubble).as_str());
+                return Err(RegisterError::HostKeyChanged {
+                    bubble: String::from(bubble),
+                    pinned,
+                    offered: reg_response.host_key
+                });
+            }
+        }
+        let spec = TunnelSpec {
+            ip: Arc::new(registration.ip.clone()),
+            port: reg_response.port,
+            proxy_target,
+            bubble_client,
+            host_key: reg_response.host_key,
+            priv_key
+        };
+        self.set_endpoint(Some(String::from(spec.bubble_client.bubble())), Some(spec.port));
+        self.transition(TunnelState::Connecting, format!("opening tunnel on port {}", spec.port).as_str());
+        let (exits, exit_receiver) = unbounded_channel();
+        match TunnelManager::open(&spec, exits.clone()).await {
+            Ok(tunnel) => {
+                inner.tunnel = Some(tunnel);
+                inner.spec = Some(spec);
+                inner.exits = Some(exits);
+                self.transition(TunnelState::Active, "tunnel opened");
+                let (checker, abort_handle) = abortable(check_tunnel(self.clone(), inner.generation, exit_receiver));
+                tokio::spawn(checker);
+                inner.checker = Some(abort_handle);
+                Ok(())
+            }
+            Err(e) => {
+                // without a tunnel the registration is useless to the bubble
+                withdraw(&spec.bubble_client, spec.ip.as_str()).await;
+                self.set_endpoint(None, None);
+                self.transition(TunnelState::Unregistered, format!("error opening tunnel: {}", e).as_str());
+                Err(RegisterError::Tunnel(e))
+            }
+        }
+    }
+
+    /// Close the tunnel and stop checking it
+    pub async fn unregister (&self, reason : &str) {
+        let mut inner = self.inner.lock().await;
+        inner.generation += 1;
+        TunnelManager::shut_down(&mut inner);
+        inner.spec = None;
+        self.set_endpoint(None, None);
+        if self.state() != TunnelState::Unregistered {
+            self.transition(TunnelState::Unregistered, reason);
+        }
+    }
+
+    async fn open (spec : &TunnelSpec, exits : UnboundedSender<TunnelExit>) -> Result<SshTunnel, TunnelError> {
+        open_tunnel(spec.ip.clone(),
+                    Arc::new(String::from(spec.bubble_client.bubble())),
+                    spec.port,
+                    spec.proxy_target.clone(),
+                    spec.host_key.clone(),
+                    spec.priv_key.clone(),
+                    exits).await
+    }
+
+    // close the tunnel so it can be reopened. returns false if generation is from an earlier
+    // registration, or if the tunnel is no longer the one with id exited
+    async fn close (&self, generation : u64, exited : Option<u64>, reason : &str) -> bool {
+        let mut inner = self.inner.lock().await;
+        if inner.generation != generation || inner.spec.is_none() || inner.exits.is_none() {
+            debug!("TunnelManager.close: registration was replaced, not closing");
+            return false;
+        }
+        if let Some(exited_id) = exited {
+            if inner.tunnel.as_ref().map(|tunnel| tunnel.id()) != Some(exited_id) {
+                trace!("TunnelManager.close: tunnel {} was already replaced, not closing", exited_id);
+                return false;
+            }
+        }
+        if let Some(mut tunnel) = inner.tunnel.take() {
+            tunnel.stop();
+        }
+        if self.state() != TunnelState::Reconnecting {
+            self.transition(TunnelState::Reconnecting, reason);
+        }
+        true
+    }
+
+    // open the tunnel closed by close
+    async fn reopen (&self, generation : u64) -> Reopen {
+        let mut inner = self.inner.lock().await;
+        if inner.generation != generation || inner.spec.is_none() || inner.exits.is_none() || inner.tunnel.is_some() {
+            debug!("TunnelManager.reopen: registration was replaced, not reopening");
+            return Reopen::Skipped;
+        }
+        if self.state() == TunnelState::WaitingForNetwork {
+            self.transition(TunnelState::Reconnecting, "bubble is reachable again");
+        }
+        let spec = inner.spec.clone().unwrap();
+        let exits = inner.exits.clone().unwrap();
+        self.transition(TunnelState::Connecting, format!("opening tunnel on port {}", spec.port).as_str());
+        match TunnelManager::open(&spec, exits).await {
+            Ok(tunnel) => {
+                inner.tunnel = Some(tunnel);
+                self.transition(TunnelState::Active, "tunnel reopened");
+                Reopen::Opened
+            }
+            Err(e) => {
+                self.transition(TunnelState::Reconnecting, format!("error opening tunnel: {}", e).as_str());
+                Reopen::Failed
+            }
+        }
+    }
+
+    // stop retrying on a timer until the bubble can be reached again
+    async fn wait_for_network (&self, generation : u64, reason : &str) {
+        let inner = self.inner.lock().await;
+        if inner.generation == generation && self.state() == TunnelState::Reconnecting {
+            self.transition(TunnelState::WaitingForNetwork, reason);
+        }
+    }
+
+    // the bubble removed our registration: close the tunnel. called by the checker, which then returns
+    async fn deleted (&self, generation : u64) {
+        let mut inner = self.inner.lock().await;
+        if inner.generation != generation {
+            return;
+        }
+        // dropping the handle without aborting: the checker calling us is about to return
+        inner.checker = None;
+        TunnelManager::shut_down(&mut inner);
+        inner.spec = None;
+        self.set_endpoint(None, None);
+        self.transition(TunnelState::Deleted, "bubble deleted the registration");
+    }
+
+    // record what the bubble says about a tunnel we believe is open
+    fn report_health (&self, healthy : bool, reason : &str) {
+        match (self.state(), healthy) {
+            (TunnelState::Active, false) => { self.transition(TunnelState::Degraded, reason); }
+            (TunnelState::Degraded, true) => { self.transition(TunnelState::Active, reason); }
+            _ => {}
+        }
+    }
+}
+
+// delete a registration the bubble accepted but we cannot use, so the bubble does not keep a stale flex router
+async fn withdraw (bubble_client : &BubbleClient, ip : &str) {
+    match bubble_client.delete(ip).await {
+        Ok(_) => info!("withdraw: deleted registration of {} from {}", ip, bubble_client.bubble()),
+        Err(e) => error!("withdraw: error deleting registration of {} from {}: {}", ip, bubble_client.bubble(), e)
+    }
+}
+
+// what woke the checker
+enum CheckEvent {
+    Exited (TunnelExit),
+    Retry,
+    Tick
+}
+
+// what the checker does next
+enum CheckAction {
+    Nothing,
+    Reopen,
+    BackOff
+}
+
+// Supervise the tunnel: reopen it when its forwarding thread ends on its own or when the bubble keeps
+// failing to reach it. Repeated failures back off exponentially; after too many, stop retrying on a
+// timer and wait for the bubble to answer status checks again
+async fn check_tunnel (manager : Arc<TunnelManager>, generation : u64, mut exits : UnboundedReceiver<TunnelExit>) {
+    let (bubble_client, ip) = match &manager.inner.lock().await.spec {
+        Some(spec) => (spec.bubble_client.clone(), spec.ip.clone()),
+        None => return
+    };
+    let bubble = bubble_client.bubble();
+    let check_ssh_interval = manager.check_ssh_interval;
+    let mut checker = interval_at(Instant::now().checked_add(Duration::new(CHECK_SSH_START_DELAY, 0)).unwrap(), Duration::new(check_ssh_interval, 0));
+    let mut error_count : u8 = 0;
+    let mut backoff = Backoff::new(manager.backoff);
+    backoff.record_connected(Instant::now());
+    let mut retry_at : Option<Instant> = None;
+    let mut waiting = false;
+    loop {
+        let event = tokio::select! {
+            exit = exits.recv() => match exit {
+                Some(exit) => CheckEvent::Exited(exit),
+                // the registration was replaced
+                None => return
+            },
+            _ = delay_until(retry_at.unwrap_or_else(Instant::now)), if retry_at.is_some() => CheckEvent::Retry,
+            _ = checker.tick() => CheckEvent::Tick
+        };
+
+        let action = match event {
+            CheckEvent::Exited(exit) => {
+                let reason = format!("tunnel closed: {}", exit.reason);
+                if manager.close(generation, Some(exit.id), reason.as_str()).await {
+                    after_close(&mut backoff)
+                } else {
+                    CheckAction::Nothing
+                }
+            }
+            CheckEvent::Retry => {
+                retry_at = None;
+                CheckAction::Reopen
+            }
+            CheckEvent::Tick => {
+                trace!("check_tunnel: checking tunnel status for {} via {}", ip, bubble);
+                match bubble_client.status(ip.as_str()).await {
+                    Err(e) => {
+                        error!("check_tunnel: error checking tunnel status via {}: {}", bubble, e);
+                        manager.report_health(false, "error checking tunnel status");
+                        continue;
+                    }
+                    Ok(TunnelStatus::Deleted) => {
+                        info!("check_tunnel: tunnel status via {}: tunnel was deleted, stopping tunnel and checker", bubble);
+                        manager.deleted(generation).await;
+                        return;
+                    }
+                    Ok(_) if waiting => {
+                        info!("check_tunnel: {} is reachable again, reopening tunnel", bubble);
+                        CheckAction::Reopen
+                    }
+                    // the tunnel is closed and a reopen is already scheduled
+                    Ok(_) if retry_at.is_some() => continue,
+                    Ok(TunnelStatus::None) => {
+                        info!("check_tunnel: checked tunnel status via {}: tunnel status not yet available", bubble);
+                        error_count += 1;
+                        CheckAction::Nothing
+                    }
+                    Ok(TunnelStatus::Active) => {
+                        trace!("check_tunnel: tunnel status via {}: tunnel status is OK", bubble);
+                        manager.report_health(true, "bubble reports tunnel active");
+                        backoff.record_healthy(Instant::now());
+                        error_count = 0;
+                        CheckAction::Nothing
+                    }
+                    Ok(TunnelStatus::Unreachable) => {
+                        debug!("check_tunnel: tunnel status via {}: tunnel is unreachable", bubble);
+                        manager.report_health(false, "bubble reports tunnel unreachable");
+                        error_count += 1;
+                        CheckAction::Nothing
+                    }
+                    Ok(TunnelStatus::Unknown(status)) => {
+                        error!("check_tunnel: error checking tunnel status via {}: unknown tunnel status={}", bubble, status);
+                        manager.report_health(false, "unknown tunnel status");
+                        error_count += 1;
+                        CheckAction::Nothing
+                    }
+                }
+            }
+        };
+        let action = match action {
+            CheckAction::Nothing if error_count >= MAX_CHECK_ERRORS_BEFORE_RESTART => {
+                info!("check_tunnel: tunnel had too many errors, reopening tunnel");
+                error_count = 0;
+                if manager.close(generation, None, "too many failed status checks").await {
+                    after_close(&mut backoff)
+                } else {
+                    CheckAction::Nothing
+                }
+            }
+            action => action
+        };
+
+        let failed = match action {
+            CheckAction::Nothing => false,
+            CheckAction::BackOff => true,
+            CheckAction::Reopen => match manager.reopen(generation).await {
+                Reopen::Opened => {
+                    info!("check_tunnel: reopened tunnel to {}", bubble);
+                    backoff.record_connected(Instant::now());
+                    waiting = false;
+                    error_count = 0;
+                    false
+                }
+                Reopen::Failed => true,
+                Reopen::Skipped => return
+            }
+        };
+        if failed {
+            match backoff.record_failure() {
+                Some(delay) => {
+                    info!("check_tunnel: reopening tunnel to {} in {:?} after {} consecutive failures", bubble, delay, backoff.failures());
+                    retry_at = Some(Instant::now() + delay);
+                }
+                None => {
+                    error!("check_tunnel: giving up on reopening tunnel to {} after {} consecutive failures, waiting for network", bubble, backoff.failures());
+                    waiting = true;
+                    manager.wait_for_network(generation, format!("{} consecutive failures", backoff.failures()).as_str()).await;
+                }
+            }
+        }
+    }
+}
+
+// a tunnel that stayed up long enough is reopened right away; one that keeps closing backs off
+fn after_close (backoff : &mut Backoff) -> CheckAction {
+    backoff.record_healthy(Instant::now());
+    if backoff.failures() == 0 { CheckAction::Reopen } else { CheckAction::BackOff }
+}
@@ -0,0 +1,236 @@
+#![deny(warnings)]
+/**
+ * Copyright (c) 2020 Bubble, Inc.  All rights reserved.
+ * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
+ */
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::process::exit;
+
+use ipnet::IpNet;
+
+use log::{debug, info, warn, error};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::policy::{domain_matches, normalize_domain};
+use crate::route_manager::NextHop;
+use crate::util::now_secs;
+
+/// The name of the only uplink when no uplinks file is given
+pub const DEFAULT_UPLINK: &'static str = "default";
+
+pub const DEFAULT_UPLINK_CHECK_TARGET: &'static str = "1.1.1.1:53";
+pub const DEFAULT_UPLINK_CHECK_INTERVAL: u64 = 10;
+
+/// The uplinks proxied traffic may leave through, loaded from a JSON file like:
+///
+/// ```json
+/// {
+///   "uplinks": [
+///     { "name": "wifi" },
+///     { "name": "lte", "next_hop": "dev wwan0" },
+///     { "name": "office", "next_hop": "192.168.8.1" }
+///   ],
+///   "rules": [
+///     { "uplink": "lte", "domains": [".streaming.example"] },
+///     { "uplink": "office", "cidrs": ["203.0.113.0/24"] }
+///   ],
+///   "check_target": "1.1.1.1:53",
+///   "check_interval": 10
+/// }
+/// ```
+///
+/// An uplink without a `next_hop` follows the default gateway. A destination goes out through the
+/// uplink named in the bubble's X-Bubble-Uplink request header, otherwise the uplink of the first
+/// matching rule, otherwise the first uplink listed. If that uplink is down, the next healthy one in
+/// the list is used instead. An uplink is down when a TCP connection to `check_target` through it fails.
+#[derive(Debug, Deserialize)]
+pub struct Uplinks {
+    uplinks: Vec<Uplink>,
+    #[serde(default)]
+    rules: Vec<UplinkRule>,
+    #[serde(default = "default_check_target")]
+    pub check_target: SocketAddr,
+    /// seconds between health checks
+    #[serde(default = "default_check_interval")]
+    pub check_interval: u64,
+    #[serde(skip)]
+    status: std::sync::Mutex<HashMap<String, UplinkStatus>>
+}
+
+fn default_check_target() -> SocketAddr { DEFAULT_UPLINK_CHECK_TARGET.parse().unwrap() }
+fn default_check_interval() -> u64 { DEFAULT_UPLINK_CHECK_INTERVAL }
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct Uplink {
+    pub name: String,
+    /// a gateway address, or "dev NAME" for a point-to-point device. If not set, the default gateway
+    #[serde(default)]
+    pub next_hop: Option<NextHop>
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct UplinkRule {
+    pub uplink: String,
+    /// same forms as in the policy file: exact names, ".suffix" or globs
+    #[serde(default)]
+    pub domains: Vec<String>,
+    #[serde(default)]
+    pub cidrs: Vec<IpNet>
+}
+
+#[derive(Debug, Clone)]
+struct UplinkStatus {
+    healthy: bool,
+    last_check: Option<u64>,
+    error: Option<String>
+}
+
+/// How an uplink did in its last health check, as reported to the bubble
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct UplinkHealth {
+    pub name: String,
+    pub next_hop: Option<NextHop>,
+    pub healthy: bool,
+    /// seconds since the epoch, or None if the uplink has not been checked yet
+    pub last_check: Option<u64>,
+    pub error: Option<String>
+}
+
+impl Uplinks {
+    /// A single uplink following the default gateway, with no rules
+    pub fn single () -> Uplinks {
+        Uplinks {
+            uplinks: vec![Uplink { name: String::from(DEFAULT_UPLINK), next_hop: None }],
+            rules: Vec::new(),
+            check_target: default_check_target(),
+            check_interval: DEFAULT_UPLINK_CHECK_INTERVAL,
+            status: std::sync::Mutex::new(HashMap::new())
+        }
+    }
+
+    pub fn load (path : &str) -> Uplinks {
+        let read_result = fs::read_to_string(path);
+        if read_result.is_err() {
+            error!("Uplinks.load: error reading uplinks file {}: {:?}", path, read_result.err().unwrap());
+            exit(2);
+        }
+        let parse_result = Uplinks::parse(read_result.unwrap().as_str());
+        if parse_result.is_err() {
+            error!("Uplinks.load: error in uplinks file {}: {}", path, parse_result.err().unwrap());
+            exit(2);
+        }
+        let uplinks = parse_result.unwrap();
+        info!("Uplinks.load: loaded {} uplinks and {} rules from {}", uplinks.uplinks.len(), uplinks.rules.len(), path);
+        uplinks
+    }
+
+    /// Parse and validate an uplinks file
+    pub fn parse (json : &str) -> Result<Uplinks, String> {
+        let uplinks = serde_json::from_str::<Uplinks>(json).map_err(|e| e.to_string())?;
+        if uplinks.uplinks.is_empty() {
+            return Err(String::from("no uplinks defined"));
+        }
+        if uplinks.check_interval == 0 {
+            return Err(String::from("check_interval must be at least 1"));
+        }
+        let mut names = HashSet::new();
+        for uplink in &uplinks.uplinks {
+            if !names.insert(uplink.name.as_str()) {
+                return Err(format!("uplink {} is defined more than once", uplink.name));
+            }
+        }
+        for (index, rule) in uplinks.rules.iter().enumerate() {
+            if !names.contains(rule.uplink.as_str()) {
+                return Err(format!("rule #{} refers to unknown uplink {}", index, rule.uplink));
+            }
+        }
+        Ok(uplinks)
+    }
+
+    pub fn uplinks (&self) -> &[Uplink] { &self.uplinks }
+
+    /// The next hops of uplinks that do not follow the default gateway
+    pub fn pinned_next_hops (&self) -> Vec<NextHop> {
+        self.uplinks.iter().filter_map(|u| u.next_hop.clone()).collect()
+    }
+
+    /// The uplink a connection to host at ip should use. requested is the uplink the bubble asked
+    /// for, if any. Unhealthy uplinks are passed over; if all are down, the preferred one is used anyway
+    pub fn select (&self, host : &str, ip : &IpAddr, requested : Option<&str>) -> Uplink {
+        let mut preference: Vec<&Uplink> = Vec::new();
+        if let Some(name) = requested {
+            match self.find(name) {
+                Some(uplink) => preference.push(uplink),
+                None => warn!("Uplinks.select: bubble requested unknown uplink {}, ignoring", name)
+            }
+        }
+        if let Some(rule) = self.rules.iter().find(|rule| rule.matches(host, ip)) {
+            preference.push(self.find(rule.uplink.as_str()).unwrap());
+        }
+        preference.extend(self.uplinks.iter());
+
+        let status = self.status.lock().unwrap();
+        let healthy = preference.iter().find(|uplink| status.get(&uplink.name).map(|s| s.healthy).unwrap_or(true));
+        let chosen = match healthy {
+            Some(uplink) => *uplink,
+            None => preference[0]
+        };
+        if chosen != preference[0] {
+            debug!("Uplinks.select: uplink {} is down, using {} for {} ({})", preference[0].name, chosen.name, host, ip);
+        }
+        chosen.clone()
+    }
+
+    fn find (&self, name : &str) -> Option<&Uplink> {
+        self.uplinks.iter().find(|uplink| uplink.name == name)
+    }
+
+    /// Record the outcome of a health check. Returns true if the uplink went up or down
+    pub fn set_health (&self, name : &str, result : Result<(), String>) -> bool {
+        let mut status = self.status.lock().unwrap();
+        let was_healthy = status.get(name).map(|s| s.healthy).unwrap_or(true);
+        let healthy = result.is_ok();
+        if was_healthy && !healthy {
+            warn!("Uplinks.set_health: uplink {} is down: {}", name, result.as_ref().err().unwrap());
+        } else if !was_healthy && healthy {
+            info!("Uplinks.set_health: uplink {} is up again", name);
+        }
+        status.insert(String::from(name), UplinkStatus { healthy, last_check: Some(now_secs()), error: result.err() });
+        was_healthy != healthy
+    }
+
+    /// The health of every uplink, in the order they are listed. Uplinks not checked yet count as healthy
+    pub fn health (&self) -> Vec<UplinkHealth> {
+        let status = self.status.lock().unwrap();
+        self.uplinks.iter().map(|uplink| {
+            let current = status.get(&uplink.name).cloned()
+                .unwrap_or(UplinkStatus { healthy: true, last_check: None, error: None });
+            UplinkHealth {
+                name: uplink.name.clone(),
+                next_hop: uplink.next_hop.clone(),
+                healthy: current.healthy,
+                last_check: current.last_check,
+                error: current.error
+            }
+        }).collect()
+    }
+}
+
+impl UplinkRule {
+    fn matches (&self, host : &str, ip : &IpAddr) -> bool {
+        if self.domains.is_empty() && self.cidrs.is_empty() {
+            return false;
+        }
+        if !self.domains.is_empty() {
+            let host = normalize_domain(host);
+            if !self.domains.iter().any(|d| domain_matches(normalize_domain(d).as_str(), host.as_str())) {
+                return false;
+            }
+        }
+        self.cidrs.is_empty() || self.cidrs.iter().any(|net| net.contains(ip))
+    }
+}
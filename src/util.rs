@@ -15,7 +15,10 @@ use std::time::{SystemTime, UNIX_EPOCH};
 
 use log::error;
 
+use whoami::{platform, Platform};
+
 pub const HEADER_BUBBLE_SESSION: &'static str = "X-Bubble-Session";
+pub const HEADER_BUBBLE_UPLINK: &'static str = "X-Bubble-Uplink";
 
 pub fn read_required_env_var_argument(arg_name : &str, opt : Option<&str>) -> String {
     if opt.is_none() {
@@ -91,6 +94,108 @@ pub fn write_string_to_file(path: &str, data : String) -> Result<bool, Option<Er
     }
 }
 
+/// Bind a Unix domain socket that only our own user can connect to.
+/// A stale socket left behind by a previous run is replaced; a socket still in use or anything else at path is an error.
+#[cfg(unix)]
+pub async fn bind_private_socket(path: &str) -> tokio::net::UnixListener {
+    match try_bind_private_socket(path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("bind_private_socket: error binding {}: {}", path, e);
+            exit(2);
+        }
+    }
+}
+
+/// Bind the socket in a new directory that only our own user can enter, restrict it to our
+/// own user and only then move it to path, so no other account ever sees it with looser permissions
+#[cfg(unix)]
+pub fn try_bind_private_socket(path: &str) -> std::io::Result<tokio::net::UnixListener> {
+    use std::io::ErrorKind;
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+    let socket_path = Path::new(path);
+    match fs::symlink_metadata(socket_path) {
+        Ok(meta) if !meta.file_type().is_socket() => {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("{} exists and is not a socket", path)));
+        }
+        // a socket someone still listens on belongs to a running instance; only a refused one is stale
+        Ok(_) => match std::os::unix::net::UnixStream::connect(socket_path) {
+            Ok(_) => return Err(Error::new(ErrorKind::AddrInUse, format!("{} is in use by a running process", path))),
+            Err(ref e) if e.kind() == ErrorKind::ConnectionRefused => {}
+            Err(e) => return Err(e)
+        },
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e)
+    }
+    let file_name = socket_path.file_name()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("not a socket path: {}", path)))?;
+    let private_dir = socket_path.with_file_name(format!(".{}.{}", file_name.to_string_lossy(), std::process::id()));
+    let private_path = private_dir.join("socket");
+    // left behind if a previous run with our pid crashed here
+    let _ = fs::remove_file(&private_path);
+    let _ = fs::remove_dir(&private_dir);
+    fs::DirBuilder::new().mode(0o700).create(&private_dir)?;
+    let result = tokio::net::UnixListener::bind(&private_path)
+        .and_then(|listener| {
+            fs::set_permissions(&private_path, fs::Permissions::from_mode(0o600))?;
+            fs::rename(&private_path, socket_path)?;
+            Ok(listener)
+        });
+    if result.is_err() {
+        let _ = fs::remove_file(&private_path);
+    }
+    let _ = fs::remove_dir(&private_dir);
+    result
+}
+
+/// Where state that must survive a restart lives, unless --state-dir says otherwise
+pub fn default_state_dir () -> String {
+    match platform() {
+        Platform::Windows => String::from("C:\\ProgramData\\bubble-flexrouter"),
+        Platform::MacOS => String::from("/Library/Application Support/bubble-flexrouter"),
+        _ => String::from("/var/lib/bubble-flexrouter")
+    }
+}
+
+/// Create a directory, if needed, that only our own user can read
+pub fn ensure_private_dir(path: &str) {
+    let create_result = fs::create_dir_all(path);
+    if create_result.is_err() {
+        error!("ensure_private_dir: error creating directory {}: {:?}", path, create_result.err().unwrap());
+        exit(2);
+    }
+    #[cfg(unix)] {
+        use std::os::unix::fs::PermissionsExt;
+        let perm_result = fs::set_permissions(path, fs::Permissions::from_mode(0o700));
+        if perm_result.is_err() {
+            error!("ensure_private_dir: error setting permissions on {}: {:?}", path, perm_result.err().unwrap());
+            exit(2);
+        }
+    }
+}
+
+/// Replace a file with data that only our own user can read. The data is written to a temporary
+/// file first and renamed into place, so a crash never leaves a half-written file behind.
+pub fn write_private_file(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let temp_path = path.with_extension("tmp");
+    {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)] {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&temp_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+    }
+    fs::rename(&temp_path, path)
+}
+
+pub fn now_secs () -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
 pub fn now_micros () -> u128 {
     SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros()
 }
\ No newline at end of file
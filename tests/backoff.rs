@@ -0,0 +1,77 @@
+#![deny(warnings)]
+/**
+ * Copyright (c) 2020 Bubble, Inc.  All rights reserved.
+ * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
+ */
+
+use tokio::time::{self, Duration, Instant};
+
+use bubble_flexrouter::backoff::{Backoff, BackoffConfig};
+
+fn config () -> BackoffConfig {
+    BackoffConfig {
+        initial_delay: Duration::from_secs(1),
+        max_delay: Duration::from_secs(30),
+        max_failures: 8,
+        healthy_reset: Duration::from_secs(60)
+    }
+}
+
+#[tokio::test]
+async fn delays_grow_exponentially_with_jitter_up_to_the_max() {
+    time::pause();
+    let mut backoff = Backoff::new(config());
+    let start = Instant::now();
+    let mut waited = Duration::from_secs(0);
+    for _ in 0..7 {
+        let delay = backoff.record_failure().unwrap();
+        let ceiling = backoff.max_delay_after(backoff.failures());
+        assert!(delay >= ceiling / 2 && delay <= ceiling, "delay {:?} outside [{:?}, {:?}]", delay, ceiling / 2, ceiling);
+        time::advance(delay).await;
+        waited += delay;
+    }
+    // the paused clock moved only as far as we waited
+    assert_eq!(Instant::now().duration_since(start), waited);
+    let ceilings: Vec<u64> = (1..8).map(|n| backoff.max_delay_after(n).as_secs()).collect();
+    assert_eq!(ceilings, vec![1, 2, 4, 8, 16, 30, 30]);
+    assert!(waited <= Duration::from_secs(1 + 2 + 4 + 8 + 16 + 30 + 30));
+}
+
+#[tokio::test]
+async fn gives_up_after_max_failures() {
+    time::pause();
+    let mut backoff = Backoff::new(config());
+    for _ in 0..7 {
+        let delay = backoff.record_failure().unwrap();
+        time::advance(delay).await;
+    }
+    assert!(!backoff.is_open());
+    assert_eq!(backoff.record_failure(), None);
+    assert!(backoff.is_open());
+    assert_eq!(backoff.record_failure(), None);
+}
+
+#[tokio::test]
+async fn failures_reset_only_after_a_sustained_healthy_period() {
+    time::pause();
+    let mut backoff = Backoff::new(config());
+    backoff.record_failure();
+    backoff.record_failure();
+    backoff.record_connected(Instant::now());
+
+    time::advance(Duration::from_secs(59)).await;
+    backoff.record_healthy(Instant::now());
+    assert_eq!(backoff.failures(), 2);
+
+    // a connection that drops before proving itself healthy keeps counting
+    let delay = backoff.record_failure().unwrap();
+    assert_eq!(backoff.failures(), 3);
+    assert!(delay >= Duration::from_secs(2));
+    time::advance(delay).await;
+    backoff.record_connected(Instant::now());
+
+    time::advance(Duration::from_secs(60)).await;
+    backoff.record_healthy(Instant::now());
+    assert_eq!(backoff.failures(), 0);
+    assert!(backoff.record_failure().unwrap() <= Duration::from_secs(1));
+}
@@ -0,0 +1,56 @@
+#![deny(warnings)]
+/**
+ * Copyright (c) 2020 Bubble, Inc.  All rights reserved.
+ * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
+ */
+
+use std::net::SocketAddr;
+
+mod common;
+
+use common::{registration, start_fake_bubble, SESSION};
+
+use bubble_flexrouter::bubble_client::{BubbleClient, BubbleClientConfig, BubbleError, TunnelStatus};
+
+const HOST_KEY: &str = "bubble ssh-ed25519 AAAA";
+
+fn client (addr : SocketAddr, session : &str) -> BubbleClient {
+    let base_url = format!("http://{}/api", addr);
+    BubbleClient::with_base_url("bubble.example.com", base_url.as_str(), session, &BubbleClientConfig::default()).unwrap()
+}
+
+#[tokio::test]
+async fn register_returns_tunnel_port_and_host_key() {
+    let bubble = client(start_fake_bubble(HOST_KEY).addr, SESSION);
+    let response = bubble.register(&registration("10.19.0.2")).await.unwrap();
+    assert_eq!(response.port, 40123);
+    assert_eq!(response.host_key, HOST_KEY);
+
+    match bubble.register(&registration("10.19.0.3")).await {
+        Err(BubbleError::Status(status, body)) => {
+            assert_eq!(status.as_u16(), 422);
+            assert_eq!(body, "not a flex router ip");
+        }
+        other => panic!("expected an error status, got {:?}", other)
+    }
+}
+
+#[tokio::test]
+async fn status_is_typed() {
+    let bubble = client(start_fake_bubble(HOST_KEY).addr, SESSION);
+    assert_eq!(bubble.status("10.19.0.2").await.unwrap(), TunnelStatus::Active);
+    assert_eq!(bubble.status("10.19.0.3").await.unwrap(), TunnelStatus::Deleted);
+    assert_eq!(TunnelStatus::from("maintenance"), TunnelStatus::Unknown(String::from("maintenance")));
+}
+
+#[tokio::test]
+async fn delete_succeeds_when_already_gone() {
+    let addr = start_fake_bubble(HOST_KEY).addr;
+    let bubble = client(addr, SESSION);
+    bubble.delete("10.19.0.2").await.unwrap();
+    bubble.delete("10.19.0.3").await.unwrap();
+
+    // without the session the bubble rejects the request
+    let stranger = client(addr, "other-session");
+    assert!(matches!(stranger.delete("10.19.0.2").await, Err(BubbleError::Status(_, _))));
+}
@@ -0,0 +1,108 @@
+#![allow(dead_code)]
+/**
+ * Copyright (c) 2020 Bubble, Inc.  All rights reserved.
+ * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
+ */
+
+// fixtures shared by the integration tests. Each test crate uses only some of them
+
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use warp::Filter;
+
+use bubble_flexrouter::bubble_client::BubbleRegistration;
+use bubble_flexrouter::util::now_micros;
+
+pub const SESSION: &str = "test-session";
+
+/// the one flex router ip the fake bubble knows
+pub const FLEX_ROUTER_IP: &str = "10.19.0.2";
+
+pub fn ip (s : &str) -> IpAddr { s.parse().unwrap() }
+
+pub fn temp_state_dir () -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("bubble-flexrouter-test-{}-{}", std::process::id(), now_micros()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn free_port () -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+// a server that echoes back whatever it receives
+pub async fn start_echo_server () -> SocketAddr {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                loop {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => if stream.write_all(&buf[..n]).await.is_err() { break }
+                    }
+                }
+            });
+        }
+    });
+    addr
+}
+
+pub fn registration (ip : &str) -> BubbleRegistration {
+    BubbleRegistration { key: String::from("ssh-ed25519 AAAA"), ip: String::from(ip), auth_token: String::from("token") }
+}
+
+pub struct FakeBubble {
+    pub addr: SocketAddr,
+    deleted: Arc<Mutex<Vec<String>>>
+}
+
+impl FakeBubble {
+    /// every ip the bubble was asked to delete, known or not
+    pub fn deleted (&self) -> Vec<String> { self.deleted.lock().unwrap().clone() }
+}
+
+// a fake bubble API that only answers requests with SESSION. It knows one flex router,
+// FLEX_ROUTER_IP, whose tunnel is active on port 40123 and offers host_key
+pub fn start_fake_bubble (host_key : &'static str) -> FakeBubble {
+    let deleted = Arc::new(Mutex::new(Vec::new()));
+    let session = warp::header::exact("X-Bubble-Session", SESSION);
+    let register = warp::put().and(warp::path!("api" / "me" / "flexRouters"))
+        .and(session)
+        .and(warp::body::json())
+        .map(move |reg : BubbleRegistration| {
+            if reg.ip == FLEX_ROUTER_IP {
+                warp::reply::with_status(format!("{{\"port\": 40123, \"host_key\": \"{}\"}}", host_key), http::StatusCode::OK)
+            } else {
+                warp::reply::with_status(String::from("not a flex router ip"), http::StatusCode::UNPROCESSABLE_ENTITY)
+            }
+        });
+    let status = warp::get().and(warp::path!("api" / "me" / "flexRouters" / String / "status"))
+        .and(session)
+        .map(|ip : String| {
+            if ip == FLEX_ROUTER_IP { "\"active\"" } else { "\"deleted\"" }
+        });
+    let recorded = deleted.clone();
+    let delete = warp::delete().and(warp::path!("api" / "me" / "flexRouters" / String))
+        .and(session)
+        .map(move |ip : String| {
+            recorded.lock().unwrap().push(ip.clone());
+            if ip == FLEX_ROUTER_IP {
+                warp::reply::with_status("{}", http::StatusCode::OK)
+            } else {
+                warp::reply::with_status("not found", http::StatusCode::NOT_FOUND)
+            }
+        });
+    let (addr, server) = warp::serve(register.or(status).or(delete)).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    FakeBubble { addr, deleted }
+}
@@ -0,0 +1,131 @@
+#![deny(warnings)]
+/**
+ * Copyright (c) 2020 Bubble, Inc.  All rights reserved.
+ * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
+ */
+
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use tokio::net::TcpListener;
+use tokio::time::{self, Duration, Instant};
+
+mod common;
+
+use common::ip;
+
+use bubble_flexrouter::connector::connect_routed;
+use bubble_flexrouter::egress::{EgressBinding, EgressMode};
+use bubble_flexrouter::net::IpFamily;
+use bubble_flexrouter::route_ledger::RouteLedger;
+use bubble_flexrouter::route_manager::{MemoryRouteManager, NextHop, RouteFuture, RouteManager};
+use bubble_flexrouter::routes::{RouteRegistry, RouteLimits};
+use bubble_flexrouter::uplinks::Uplinks;
+
+// how the route to one address misbehaves, as if its address family were broken
+#[derive(Clone, Copy)]
+enum Broken {
+    /// looking up the route never finishes
+    Stalls (IpAddr),
+    /// looking up the route fails at once
+    Fails (IpAddr)
+}
+
+// routes kept in memory, except for the broken address. Notes when each address was first tried
+struct BrokenRouteManager {
+    inner: MemoryRouteManager,
+    broken: Broken,
+    start: Instant,
+    tried: Mutex<Vec<(IpAddr, Duration)>>
+}
+
+impl BrokenRouteManager {
+    fn tried (&self) -> Vec<(IpAddr, Duration)> { self.tried.lock().unwrap().clone() }
+}
+
+impl RouteManager for BrokenRouteManager {
+    fn default_gateway (&self, family : IpFamily) -> RouteFuture<'_, NextHop> { self.inner.default_gateway(family) }
+
+    fn route_exists (&self, dest : IpAddr) -> RouteFuture<'_, bool> {
+        self.tried.lock().unwrap().push((dest, Instant::now().duration_since(self.start)));
+        match self.broken {
+            Broken::Stalls(ip) if ip == dest => Box::pin(futures::future::pending()),
+            Broken::Fails(ip) if ip == dest => Box::pin(async move { Err(Error::new(ErrorKind::Other, format!("no route to {}", dest))) }),
+            _ => self.inner.route_exists(dest)
+        }
+    }
+
+    fn add_route (&self, dest : IpAddr, gateway : NextHop) -> RouteFuture<'_, ()> { self.inner.add_route(dest, gateway) }
+
+    fn remove_route (&self, dest : IpAddr) -> RouteFuture<'_, ()> { self.inner.remove_route(dest) }
+
+    fn host_routes_via (&self, gateway : NextHop) -> RouteFuture<'_, Vec<IpAddr>> { self.inner.host_routes_via(gateway) }
+
+    fn egress_binding (&self, gateway : NextHop) -> RouteFuture<'_, EgressBinding> { self.inner.egress_binding(gateway) }
+}
+
+// a paused clock, a listener on 127.0.0.1 and a registry with one broken address. The paused clock
+// jumps to the next timer whenever the runtime is idle, so a 250ms stagger takes no real time
+async fn setup (broken : Broken) -> (Arc<BrokenRouteManager>, Arc<RouteRegistry>, u16) {
+    time::pause();
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let _ = listener.accept().await;
+        }
+    });
+    // a device carries both address families
+    let gateway = NextHop::Device(String::from("lo"));
+    let manager = Arc::new(BrokenRouteManager {
+        inner: MemoryRouteManager::new(gateway.clone()),
+        broken,
+        start: Instant::now(),
+        tried: Mutex::new(Vec::new())
+    });
+    let routes = Arc::new(RouteRegistry::new(manager.clone(), gateway, RouteLedger::in_memory(), RouteLimits::default(), EgressMode::Routes, Uplinks::single()));
+    (manager, routes, port)
+}
+
+#[tokio::test]
+async fn working_first_family_is_used_without_trying_the_second() {
+    let (manager, routes, port) = setup(Broken::Stalls(ip("::1"))).await;
+
+    let stream = connect_routed("dual.example.com", vec![ip("127.0.0.1"), ip("::1")], port, routes, None).await.unwrap();
+    assert_eq!(stream.peer_addr().unwrap(), SocketAddr::new(ip("127.0.0.1"), port));
+    time::advance(Duration::from_secs(1)).await;
+    assert_eq!(manager.tried(), vec![(ip("127.0.0.1"), Duration::from_millis(0))]);
+}
+
+#[tokio::test]
+async fn second_family_is_tried_after_the_attempt_delay() {
+    let (manager, routes, port) = setup(Broken::Stalls(ip("::1"))).await;
+
+    let stream = connect_routed("dual.example.com", vec![ip("::1"), ip("127.0.0.1")], port, routes, None).await.unwrap();
+    assert_eq!(stream.peer_addr().unwrap(), SocketAddr::new(ip("127.0.0.1"), port));
+    let tried = manager.tried();
+    assert_eq!(tried.len(), 2);
+    assert_eq!(tried[0], (ip("::1"), Duration::from_millis(0)));
+    // the timer wheel rounds deadlines up to the next millisecond
+    assert_eq!(tried[1].0, ip("127.0.0.1"));
+    assert!(tried[1].1 >= Duration::from_millis(250) && tried[1].1 < Duration::from_millis(252), "second attempt after {:?}", tried[1].1);
+}
+
+#[tokio::test]
+async fn failed_attempt_starts_the_next_without_waiting() {
+    let (manager, routes, port) = setup(Broken::Fails(ip("::1"))).await;
+
+    let stream = connect_routed("dual.example.com", vec![ip("::1"), ip("127.0.0.1")], port, routes, None).await.unwrap();
+    assert_eq!(stream.peer_addr().unwrap(), SocketAddr::new(ip("127.0.0.1"), port));
+    assert_eq!(manager.tried(), vec![(ip("::1"), Duration::from_millis(0)), (ip("127.0.0.1"), Duration::from_millis(0))]);
+}
+
+#[tokio::test]
+async fn every_address_failing_returns_the_last_error() {
+    let (manager, routes, port) = setup(Broken::Fails(ip("::1"))).await;
+
+    let result = connect_routed("v6only.example.com", vec![ip("::1")], port, routes, None).await;
+    assert!(result.is_err());
+    assert_eq!(manager.tried().len(), 1);
+}
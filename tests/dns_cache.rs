@@ -0,0 +1,173 @@
+#![deny(warnings)]
+/**
+ * Copyright (c) 2020 Bubble, Inc.  All rights reserved.
+ * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
+ */
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use futures::future::join_all;
+
+use tokio::sync::Mutex;
+use tokio::time::{self, delay_for};
+
+use trust_dns_resolver::error::ResolveError;
+
+use bubble_flexrouter::dns_cache::{interleave_families, resolve_with_lookup, DnsCache, DnsCacheConfig, DnsResolveError, LookupFuture, LookupOutcome, ResolverCache};
+use bubble_flexrouter::net::IpPreference;
+
+const LOOKUP_MILLIS: u64 = 500;
+const PARALLEL_LOOKUPS: usize = 20;
+
+fn new_cache () -> ResolverCache {
+    Arc::new(Mutex::new(DnsCache::new(DnsCacheConfig::default())))
+}
+
+// a lookup that takes LOOKUP_MILLIS and counts how often it was started
+fn slow_lookup (calls: Arc<AtomicUsize>) -> impl FnOnce(String) -> LookupFuture {
+    move |_name| {
+        calls.fetch_add(1, Ordering::SeqCst);
+        Box::pin(async move {
+            delay_for(Duration::from_millis(LOOKUP_MILLIS)).await;
+            let addr: IpAddr = "192.0.2.1".parse().unwrap();
+            LookupOutcome::Found(vec![addr], Instant::now() + Duration::from_secs(60))
+        })
+    }
+}
+
+// a lookup that answers at once with outcome, and counts how often it was started
+fn answer (calls: Arc<AtomicUsize>, outcome: LookupOutcome) -> impl FnOnce(String) -> LookupFuture {
+    move |_name| {
+        calls.fetch_add(1, Ordering::SeqCst);
+        Box::pin(async move { outcome })
+    }
+}
+
+fn found (ttl : Duration) -> LookupOutcome {
+    LookupOutcome::Found(vec!["192.0.2.1".parse().unwrap()], Instant::now() + ttl)
+}
+
+#[tokio::test]
+async fn slow_lookups_for_different_names_run_in_parallel() {
+    let cache = new_cache();
+    let calls = Arc::new(AtomicUsize::new(0));
+    let start = Instant::now();
+    let lookups = (0..PARALLEL_LOOKUPS).map(|i| {
+        let cache = cache.clone();
+        let lookup = slow_lookup(calls.clone());
+        tokio::spawn(async move {
+            resolve_with_lookup(format!("host{}.example.com", i).as_str(), cache, lookup).await
+        })
+    });
+    let results = join_all(lookups).await;
+    let elapsed = start.elapsed();
+
+    assert!(results.into_iter().all(|r| r.unwrap().is_ok()));
+    assert_eq!(calls.load(Ordering::SeqCst), PARALLEL_LOOKUPS);
+    assert!(elapsed < Duration::from_millis(LOOKUP_MILLIS * 2),
+            "{} lookups took {:?}, expected about {}ms", PARALLEL_LOOKUPS, elapsed, LOOKUP_MILLIS);
+}
+
+#[tokio::test]
+async fn concurrent_lookups_for_one_name_share_a_query() {
+    let cache = new_cache();
+    let calls = Arc::new(AtomicUsize::new(0));
+    let start = Instant::now();
+    let lookups = (0..PARALLEL_LOOKUPS).map(|_| {
+        let cache = cache.clone();
+        let lookup = slow_lookup(calls.clone());
+        tokio::spawn(async move {
+            resolve_with_lookup("shared.example.com", cache, lookup).await
+        })
+    });
+    let results = join_all(lookups).await;
+    let elapsed = start.elapsed();
+
+    assert!(results.into_iter().all(|r| r.unwrap().is_ok()));
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert!(elapsed < Duration::from_millis(LOOKUP_MILLIS * 2));
+
+    // the answer is now cached, so no further query is made
+    let cached = resolve_with_lookup("shared.example.com", cache, slow_lookup(calls.clone())).await;
+    assert!(cached.is_ok());
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn addresses_alternate_families_starting_with_the_preferred_one() {
+    let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+    let answer = vec![ip("2001:db8::1"), ip("2001:db8::2"), ip("192.0.2.1"), ip("192.0.2.2")];
+    assert_eq!(interleave_families(answer.clone(), IpPreference::Ipv4),
+               vec![ip("192.0.2.1"), ip("2001:db8::1"), ip("192.0.2.2"), ip("2001:db8::2")]);
+    assert_eq!(interleave_families(answer.clone(), IpPreference::Ipv6),
+               vec![ip("2001:db8::1"), ip("192.0.2.1"), ip("2001:db8::2"), ip("192.0.2.2")]);
+    assert_eq!(interleave_families(answer, IpPreference::Auto)[0], ip("2001:db8::1"));
+    // with only the other family, the preference does not matter
+    assert_eq!(interleave_families(vec![ip("2001:db8::1")], IpPreference::Ipv4), vec![ip("2001:db8::1")]);
+}
+
+#[tokio::test]
+async fn answers_are_cached_for_their_ttl_within_bounds() {
+    time::pause();
+    let config = DnsCacheConfig { min_ttl: Duration::from_secs(5), max_ttl: Duration::from_secs(60), ..DnsCacheConfig::default() };
+    let cache: ResolverCache = Arc::new(Mutex::new(DnsCache::new(config)));
+    let calls = Arc::new(AtomicUsize::new(0));
+    let resolve = |host : &'static str, ttl : Duration| resolve_with_lookup(host, cache.clone(), answer(calls.clone(), found(ttl)));
+    let lookups = || calls.load(Ordering::SeqCst);
+
+    assert!(resolve("ttl.example.com", Duration::from_secs(30)).await.is_ok());
+    time::advance(Duration::from_secs(29)).await;
+    assert!(resolve("ttl.example.com", Duration::from_secs(30)).await.is_ok());
+    assert_eq!(lookups(), 1);
+    time::advance(Duration::from_secs(2)).await;
+    assert!(resolve("ttl.example.com", Duration::from_secs(30)).await.is_ok());
+    assert_eq!(lookups(), 2);
+
+    // a zero TTL is raised to min_ttl
+    assert!(resolve("zero.example.com", Duration::from_secs(0)).await.is_ok());
+    time::advance(Duration::from_secs(4)).await;
+    assert!(resolve("zero.example.com", Duration::from_secs(0)).await.is_ok());
+    assert_eq!(lookups(), 3);
+    time::advance(Duration::from_secs(2)).await;
+    assert!(resolve("zero.example.com", Duration::from_secs(0)).await.is_ok());
+    assert_eq!(lookups(), 4);
+
+    // a day-long TTL is cut to max_ttl
+    assert!(resolve("long.example.com", Duration::from_secs(86400)).await.is_ok());
+    time::advance(Duration::from_secs(59)).await;
+    assert!(resolve("long.example.com", Duration::from_secs(86400)).await.is_ok());
+    assert_eq!(lookups(), 5);
+    time::advance(Duration::from_secs(2)).await;
+    assert!(resolve("long.example.com", Duration::from_secs(86400)).await.is_ok());
+    assert_eq!(lookups(), 6);
+}
+
+#[tokio::test]
+async fn names_without_records_are_remembered_but_failures_are_not() {
+    time::pause();
+    let config = DnsCacheConfig { negative_ttl: Duration::from_secs(30), ..DnsCacheConfig::default() };
+    let cache: ResolverCache = Arc::new(Mutex::new(DnsCache::new(config)));
+    let calls = Arc::new(AtomicUsize::new(0));
+    let lookups = || calls.load(Ordering::SeqCst);
+
+    for _ in 0..2 {
+        let result = resolve_with_lookup("missing.example.com", cache.clone(), answer(calls.clone(), LookupOutcome::NoRecords)).await;
+        assert!(matches!(result, Err(DnsResolveError::DnsNoRecordsFound)));
+        time::advance(Duration::from_secs(29)).await;
+    }
+    assert_eq!(lookups(), 1);
+    // 58s after the first lookup, past the negative TTL
+    assert!(resolve_with_lookup("missing.example.com", cache.clone(), answer(calls.clone(), found(Duration::from_secs(60)))).await.is_ok());
+    assert_eq!(lookups(), 2);
+
+    // a lookup that failed outright is retried on the next request
+    for _ in 0..2 {
+        let failed = LookupOutcome::Failed(ResolveError::from("server failure"));
+        let result = resolve_with_lookup("broken.example.com", cache.clone(), answer(calls.clone(), failed)).await;
+        assert!(matches!(result, Err(DnsResolveError::ResolutionFailure(_))));
+    }
+    assert_eq!(lookups(), 4);
+}
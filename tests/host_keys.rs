@@ -0,0 +1,90 @@
+#![deny(warnings)]
+/**
+ * Copyright (c) 2020 Bubble, Inc.  All rights reserved.
+ * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
+ */
+
+use std::fs;
+use std::sync::{Arc, Barrier};
+use std::thread;
+
+mod common;
+
+use common::temp_state_dir;
+
+use bubble_flexrouter::host_keys::{is_valid_bubble_name, HostKeyCheck, HostKeyStore, HOST_KEYS_DIR};
+
+#[test]
+fn host_key_is_pinned_at_first_registration_and_kept_when_it_changes() {
+    let state_dir = temp_state_dir();
+    let store = HostKeyStore::open(state_dir.to_str().unwrap());
+    assert_eq!(store.pinned("bubble.example.com").unwrap(), None);
+
+    assert_eq!(store.check("bubble.example.com", "bubble.example.com ssh-ed25519 AAAA").unwrap(), HostKeyCheck::Pinned);
+    // another hostname or a comment does not make it another key
+    assert_eq!(store.check("bubble.example.com", "10.0.0.1 ssh-ed25519 AAAA root@bubble").unwrap(), HostKeyCheck::Unchanged);
+    assert_eq!(store.check("bubble.example.com", "bubble.example.com ssh-ed25519 BBBB").unwrap(),
+               HostKeyCheck::Changed { pinned: String::from("bubble.example.com ssh-ed25519 AAAA") });
+
+    // the pinned key survives a restart, and hostnames are not case sensitive
+    let reopened = HostKeyStore::open(state_dir.to_str().unwrap());
+    assert_eq!(reopened.pinned("Bubble.Example.com").unwrap(), Some(String::from("bubble.example.com ssh-ed25519 AAAA")));
+    assert!(matches!(reopened.check("bubble.example.com", "ssh-ed25519 BBBB").unwrap(), HostKeyCheck::Changed { .. }));
+
+    // each bubble has its own key
+    assert_eq!(reopened.check("other.example.com", "ssh-ed25519 BBBB").unwrap(), HostKeyCheck::Pinned);
+    fs::remove_dir_all(&state_dir).unwrap();
+}
+
+#[test]
+fn only_one_of_concurrent_first_registrations_pins_its_key() {
+    let state_dir = temp_state_dir();
+    let store = Arc::new(HostKeyStore::open(state_dir.to_str().unwrap()));
+    let start = Arc::new(Barrier::new(8));
+    let checks: Vec<HostKeyCheck> = (0..8)
+        .map(|i| {
+            let store = store.clone();
+            let start = start.clone();
+            thread::spawn(move || {
+                start.wait();
+                store.check("bubble.example.com", format!("ssh-ed25519 KEY{}", i).as_str()).unwrap()
+            })
+        })
+        .collect::<Vec<_>>()
+        .into_iter()
+        .map(|t| t.join().unwrap())
+        .collect();
+    assert_eq!(checks.iter().filter(|c| **c == HostKeyCheck::Pinned).count(), 1);
+    assert_eq!(checks.iter().filter(|c| matches!(c, HostKeyCheck::Changed { .. })).count(), 7);
+
+    // what is on disk is the key that won
+    let pinned = store.pinned("bubble.example.com").unwrap().unwrap();
+    let path = store.path("bubble.example.com").unwrap().unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap().trim(), pinned);
+    fs::remove_dir_all(&state_dir).unwrap();
+}
+
+#[test]
+fn host_key_files_are_private_and_names_are_not_rewritten() {
+    let state_dir = temp_state_dir();
+    let store = HostKeyStore::open(state_dir.to_str().unwrap());
+    let dir = state_dir.join(HOST_KEYS_DIR);
+
+    for name in &["../../etc/passwd", "a/b", "a_b", ".hidden", "", "bubble example"] {
+        assert!(!is_valid_bubble_name(name), "{:?} should be refused", name);
+        assert!(store.path(name).is_err());
+        assert!(store.check(name, "ssh-ed25519 AAAA").is_err());
+    }
+    assert!(is_valid_bubble_name("nexus-dr66b-wn85d-ux27e.bubv.net"));
+
+    store.check("bubble.example.com", "ssh-ed25519 AAAA").unwrap();
+    let path = store.path("bubble.example.com").unwrap().unwrap();
+    assert_eq!(path.parent().unwrap(), dir.as_path());
+    assert_eq!(fs::read_to_string(&path).unwrap(), "ssh-ed25519 AAAA\n");
+    #[cfg(unix)] {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::metadata(&dir).unwrap().permissions().mode() & 0o777, 0o700);
+    }
+    fs::remove_dir_all(&state_dir).unwrap();
+}
@@ -0,0 +1,75 @@
+#![deny(warnings)]
+/**
+ * Copyright (c) 2020 Bubble, Inc.  All rights reserved.
+ * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
+ */
+
+use ipnet::IpNet;
+
+mod common;
+
+use common::ip;
+
+use bubble_flexrouter::net::{find_vpn_ip, is_internal_ip, is_local_ip, is_private_ip, is_valid_ip, parse_ip};
+
+#[test]
+fn private_ips_are_parsed_not_prefix_matched() {
+    assert!(is_private_ip(&ip("10.19.49.12")));
+    assert!(is_private_ip(&ip("100.64.3.4")));
+    assert!(is_private_ip(&ip("fd12:3456::1")));
+    assert!(is_private_ip(&ip("fc00::1")));
+    assert!(!is_private_ip(&ip("100.128.0.1")));
+    assert!(!is_private_ip(&ip("8.8.8.8")));
+    assert!(parse_ip("10.foo").is_none());
+    assert_eq!(parse_ip("[fd00::1]"), Some(ip("fd00::1")));
+}
+
+#[test]
+fn only_configured_addresses_are_valid() {
+    assert!(is_local_ip(&ip("127.0.0.1")));
+    assert!(!is_local_ip(&ip("192.0.2.77")));
+    // private, but not ours
+    assert!(!is_valid_ip(&ip("10.255.254.253")));
+}
+
+#[test]
+fn vpn_ip_is_found_in_subnet() {
+    let none: IpNet = "10.255.254.0/24".parse().unwrap();
+    assert_eq!(find_vpn_ip(&none), None);
+    for addr in bubble_flexrouter::net::local_addresses().unwrap() {
+        if is_private_ip(&addr) {
+            let subnet = IpNet::from(addr);
+            assert_eq!(find_vpn_ip(&subnet), Some(addr));
+        }
+    }
+}
+
+#[test]
+fn internal_ips_cannot_be_reached_through_ipv6_wrappers() {
+    let internal = [
+        "0.0.0.0",
+        "127.0.0.1",
+        "169.254.169.254",
+        "10.1.2.3",
+        "::",
+        "::1",
+        "fe80::1",
+        // IPv4-mapped
+        "::ffff:127.0.0.1",
+        "::ffff:169.254.169.254",
+        // NAT64 well-known prefix, and the local-use translation prefix
+        "64:ff9b::a9fe:a9fe",
+        "64:ff9b::7f00:1",
+        "64:ff9b:1::a9fe:a9fe",
+        // IPv4-compatible
+        "::169.254.169.254",
+        "::10.0.0.1"
+    ];
+    for addr in internal.iter() {
+        assert!(is_internal_ip(&ip(addr)), "{} should be internal", addr);
+    }
+    let public = ["8.8.8.8", "2001:4860:4860::8888", "::ffff:8.8.8.8", "64:ff9b::808:808", "::8.8.8.8"];
+    for addr in public.iter() {
+        assert!(!is_internal_ip(&ip(addr)), "{} should not be internal", addr);
+    }
+}
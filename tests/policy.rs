@@ -0,0 +1,129 @@
+#![deny(warnings)]
+/**
+ * Copyright (c) 2020 Bubble, Inc.  All rights reserved.
+ * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
+ */
+
+mod common;
+
+use common::ip;
+
+use bubble_flexrouter::policy::{Policy, ViolationReason};
+
+fn policy (json : &str) -> Policy {
+    serde_json::from_str(json).unwrap()
+}
+
+// the outcome of checking a resolved destination: "ok", "rule N", "not allowed" or "internal"
+fn outcome (policy : &Policy, host : &str, addr : &str, port : u16) -> String {
+    match policy.check(host, ip(addr), port) {
+        Ok(_) => String::from("ok"),
+        Err(v) => match v.reason {
+            ViolationReason::DeniedByRule(index) => format!("rule {}", index),
+            ViolationReason::NotAllowed => String::from("not allowed"),
+            ViolationReason::InternalAddress => String::from("internal")
+        }
+    }
+}
+
+#[test]
+fn domains_match_exactly_by_suffix_or_by_glob() {
+    let policy = policy(r#"{
+        "rules": [
+            { "action": "deny", "domains": ["exact.example"] },
+            { "action": "deny", "domains": [".suffix.example"] },
+            { "action": "deny", "domains": ["*.glob.example", "cdn?.example.net"] }
+        ]
+    }"#);
+    let table = [
+        ("exact.example", "rule 0"),
+        ("www.exact.example", "ok"),
+        ("EXACT.Example.", "rule 0"),
+        ("suffix.example", "rule 1"),
+        ("a.b.suffix.example", "rule 1"),
+        ("notsuffix.example", "ok"),
+        ("a.glob.example", "rule 2"),
+        ("glob.example", "ok"),
+        ("cdn1.example.net", "rule 2"),
+        ("cdn12.example.net", "ok"),
+        ("other.example", "ok")
+    ];
+    for (host, expected) in table.iter() {
+        assert_eq!(outcome(&policy, host, "198.51.100.7", 443), *expected, "host {}", host);
+    }
+}
+
+#[test]
+fn cidrs_and_ports_must_all_match() {
+    let policy = policy(r#"{
+        "rules": [
+            { "action": "deny", "ports": [25, 465] },
+            { "action": "deny", "cidrs": ["203.0.113.0/24"], "ports": [443] },
+            { "action": "deny", "cidrs": ["2001:db8:1::/48"] }
+        ]
+    }"#);
+    let table = [
+        ("198.51.100.7", 25, "rule 0"),
+        ("198.51.100.7", 465, "rule 0"),
+        ("198.51.100.7", 443, "ok"),
+        ("203.0.113.9", 443, "rule 1"),
+        ("203.0.113.9", 80, "ok"),
+        ("203.0.114.9", 443, "ok"),
+        ("2001:db8:1::5", 80, "rule 2"),
+        ("2001:db8:2::5", 80, "ok")
+    ];
+    for (addr, port, expected) in table.iter() {
+        assert_eq!(outcome(&policy, "host.example", addr, *port), *expected, "{}:{}", addr, port);
+    }
+}
+
+#[test]
+fn deny_wins_over_allow_and_default_applies_when_nothing_matches() {
+    let policy = policy(r#"{
+        "default": "deny",
+        "rules": [
+            { "action": "allow", "domains": [".allowed.example"] },
+            { "action": "deny", "domains": ["bad.allowed.example"] },
+            { "action": "allow", "cidrs": ["198.51.100.0/24"] }
+        ]
+    }"#);
+    assert_eq!(outcome(&policy, "www.allowed.example", "203.0.113.1", 443), "ok");
+    // the deny rule comes later, and still wins
+    assert_eq!(outcome(&policy, "bad.allowed.example", "203.0.113.1", 443), "rule 1");
+    assert_eq!(outcome(&policy, "bad.allowed.example", "198.51.100.1", 443), "rule 1");
+    assert_eq!(outcome(&policy, "elsewhere.example", "198.51.100.1", 443), "ok");
+    assert_eq!(outcome(&policy, "elsewhere.example", "203.0.113.1", 443), "not allowed");
+
+    // before resolving, only a deny is final: the address may still be allowed
+    assert!(policy.check_host("elsewhere.example", 443).is_ok());
+    assert!(matches!(policy.check_host("bad.allowed.example", 443).unwrap_err().reason, ViolationReason::DeniedByRule(1)));
+}
+
+#[test]
+fn internal_addresses_are_refused_unless_allowed() {
+    let mut policy = Policy::allow_all();
+    let table = [
+        ("127.0.0.1", "internal"),
+        ("10.1.2.3", "internal"),
+        ("192.168.1.1", "internal"),
+        ("169.254.169.254", "internal"),
+        ("::1", "internal"),
+        ("fd00::1", "internal"),
+        ("198.51.100.7", "ok")
+    ];
+    for (addr, expected) in table.iter() {
+        assert_eq!(outcome(&policy, "host.example", addr, 80), *expected, "{}", addr);
+    }
+    assert!(matches!(policy.check_host("192.168.1.1", 80).unwrap_err().reason, ViolationReason::InternalAddress));
+    assert!(policy.check_host("host.example", 80).is_ok());
+
+    policy.allow_internal_nets(vec!["10.0.0.0/8".parse().unwrap()]);
+    assert_eq!(outcome(&policy, "host.example", "10.1.2.3", 80), "ok");
+    assert!(policy.check_host("10.1.2.3", 80).is_ok());
+    assert_eq!(outcome(&policy, "host.example", "192.168.1.1", 80), "internal");
+
+    // allow_internal in the policy file does the same
+    let from_file = self::policy(r#"{ "allow_internal": ["192.168.1.0/24"] }"#);
+    assert_eq!(outcome(&from_file, "printer.local", "192.168.1.20", 631), "ok");
+    assert_eq!(outcome(&from_file, "router.local", "192.168.2.1", 80), "internal");
+}
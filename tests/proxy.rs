@@ -0,0 +1,152 @@
+#![deny(warnings)]
+/**
+ * Copyright (c) 2020 Bubble, Inc.  All rights reserved.
+ * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
+ */
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::{Body, Client, Method, Request};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::delay_for;
+
+mod common;
+
+use common::{free_port, start_echo_server};
+
+use bubble_flexrouter::dns_cache::DnsCacheConfig;
+use bubble_flexrouter::egress::EgressMode;
+use bubble_flexrouter::ping::{Ping, proxy_credential};
+use bubble_flexrouter::policy::Policy;
+use bubble_flexrouter::proxy::start_proxy;
+use bubble_flexrouter::remove_routes::RemoveRoutes;
+use bubble_flexrouter::route_ledger::RouteLedger;
+use bubble_flexrouter::route_manager::{MemoryRouteManager, NextHop};
+use bubble_flexrouter::routes::{RouteRegistry, RouteLimits};
+use bubble_flexrouter::uplinks::Uplinks;
+
+const AUTH_TOKEN: &str = "test-token-test-token-test-token-test-token-test-token";
+
+struct TestProxy {
+    port: u16,
+    manager: Arc<MemoryRouteManager>,
+    gateway: NextHop
+}
+
+async fn start_test_proxy (egress_mode : EgressMode) -> TestProxy {
+    let gateway = NextHop::Gateway("192.0.2.1".parse().unwrap());
+    let manager = Arc::new(MemoryRouteManager::new(gateway.clone()));
+    let routes = Arc::new(RouteRegistry::new(manager.clone(), gateway.clone(), RouteLedger::in_memory(), RouteLimits::default(), egress_mode, Uplinks::single()));
+    let mut policy = Policy::allow_all();
+    policy.allow_internal_nets(vec!["127.0.0.0/8".parse().unwrap()]);
+    let port = free_port();
+    tokio::spawn(start_proxy(
+        "127.0.0.1", "127.0.0.1", DnsCacheConfig::default(),
+        Some(port), None, None,
+        Arc::new(String::from(AUTH_TOKEN)), Arc::new(policy), routes, true));
+    for _ in 0..50 {
+        if TcpStream::connect(("127.0.0.1", port)).await.is_ok() {
+            break;
+        }
+        delay_for(Duration::from_millis(20)).await;
+    }
+    TestProxy { port, manager, gateway }
+}
+
+async fn connect_through (proxy : &TestProxy, target : SocketAddr, credential : Option<String>) -> (TcpStream, String) {
+    let mut stream = TcpStream::connect(("127.0.0.1", proxy.port)).await.unwrap();
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+    if let Some(credential) = credential {
+        request.push_str(format!("Proxy-Authorization: Basic {}\r\n", base64::encode(format!("bubble:{}", credential))).as_str());
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    let mut byte = [0u8; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        if stream.read(&mut byte).await.unwrap() == 0 {
+            break;
+        }
+        response.push(byte[0]);
+    }
+    (stream, String::from_utf8(response).unwrap())
+}
+
+async fn remove_routes (proxy : &TestProxy, hosts : Vec<&str>) -> u16 {
+    let body = RemoveRoutes {
+        ping: Ping::new(Arc::new(String::from(AUTH_TOKEN))),
+        routes: hosts.into_iter().map(String::from).collect()
+    };
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(format!("http://127.0.0.1:{}/remove", proxy.port))
+        .body(Body::from(serde_json::to_string(&body).unwrap()))
+        .unwrap();
+    Client::new().request(request).await.unwrap().status().as_u16()
+}
+
+#[tokio::test]
+async fn connect_adds_route_and_remove_deletes_it() {
+    let proxy = start_test_proxy(EgressMode::Routes).await;
+    let target = start_echo_server().await;
+
+    let credential = proxy_credential(Arc::new(String::from(AUTH_TOKEN)));
+    let (mut stream, response) = connect_through(&proxy, target, Some(credential)).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "unexpected response: {}", response);
+    stream.write_all(b"hello").await.unwrap();
+    let mut echoed = [0u8; 5];
+    stream.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"hello");
+    assert_eq!(proxy.manager.routes().get(&target.ip()), Some(&proxy.gateway));
+
+    assert_eq!(remove_routes(&proxy, vec!["127.0.0.1"]).await, 200);
+    assert!(proxy.manager.routes().is_empty());
+}
+
+#[tokio::test]
+async fn unauthenticated_connect_adds_no_route() {
+    let proxy = start_test_proxy(EgressMode::Routes).await;
+    let target = start_echo_server().await;
+
+    let (_stream, response) = connect_through(&proxy, target, None).await;
+    assert!(response.starts_with("HTTP/1.1 407"), "unexpected response: {}", response);
+    assert!(proxy.manager.routes().is_empty());
+}
+
+#[tokio::test]
+async fn bind_mode_connects_without_adding_routes() {
+    let proxy = start_test_proxy(EgressMode::Bind).await;
+    let target = start_echo_server().await;
+
+    let credential = proxy_credential(Arc::new(String::from(AUTH_TOKEN)));
+    let (mut stream, response) = connect_through(&proxy, target, Some(credential)).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "unexpected response: {}", response);
+    stream.write_all(b"hello").await.unwrap();
+    let mut echoed = [0u8; 5];
+    stream.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"hello");
+    assert!(proxy.manager.routes().is_empty());
+}
+
+async fn post_uplinks (proxy : &TestProxy, body : Body) -> u16 {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(format!("http://127.0.0.1:{}/uplinks", proxy.port))
+        .body(body)
+        .unwrap();
+    Client::new().request(request).await.unwrap().status().as_u16()
+}
+
+#[tokio::test]
+async fn malformed_uplinks_request_is_rejected() {
+    let proxy = start_test_proxy(EgressMode::Routes).await;
+
+    assert_eq!(post_uplinks(&proxy, Body::from("not json")).await, 400);
+    assert_eq!(post_uplinks(&proxy, Body::from(vec![0xffu8, 0xfe])).await, 400);
+    let ping = Ping::new(Arc::new(String::from(AUTH_TOKEN)));
+    assert_eq!(post_uplinks(&proxy, Body::from(serde_json::to_string(&ping).unwrap())).await, 200);
+}
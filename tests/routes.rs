@@ -0,0 +1,262 @@
+#![deny(warnings)]
+/**
+ * Copyright (c) 2020 Bubble, Inc.  All rights reserved.
+ * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
+ */
+
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::time::delay_for;
+
+mod common;
+
+use common::{ip, temp_state_dir};
+
+use bubble_flexrouter::egress::EgressMode;
+use bubble_flexrouter::route_ledger::{RouteLedger, ROUTE_LEDGER_FILE};
+use bubble_flexrouter::route_manager::{MemoryRouteManager, NextHop, RouteManager};
+use bubble_flexrouter::routes::{RouteRegistry, RouteLimits};
+use bubble_flexrouter::uplinks::Uplinks;
+
+#[tokio::test]
+async fn restart_after_crash_removes_only_our_routes() {
+    let gateway = NextHop::Gateway(ip("192.0.2.1"));
+    let manager = Arc::new(MemoryRouteManager::new(gateway.clone()));
+    let state_dir = temp_state_dir();
+    let state_dir_str = state_dir.to_str().unwrap();
+
+    // someone else's host route through the same gateway
+    manager.add_route(ip("203.0.113.9"), gateway.clone()).await.unwrap();
+
+    {
+        let routes = RouteRegistry::new(manager.clone(), gateway.clone(), RouteLedger::load(state_dir_str), RouteLimits::default(), EgressMode::Routes, Uplinks::single());
+        assert!(routes.ensure_route(&ip("198.51.100.1"), "a.example.com", routes.gateway()).await);
+        assert!(routes.ensure_route(&ip("198.51.100.2"), "c.example.com", routes.gateway()).await);
+        assert!(routes.ensure_route(&ip("198.51.100.1"), "b.example.com", routes.gateway()).await);
+
+        // each route was saved before it was added, but another hostname waits for the background writer
+        let mut entries = RouteLedger::load(state_dir_str).entries();
+        entries.sort_by_key(|e| e.destination);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].hostnames, vec!["a.example.com"]);
+        routes.save_ledger().await;
+        // dropped without flushing, as if the process had crashed
+    }
+    assert_eq!(manager.routes().len(), 3);
+
+    let ledger = RouteLedger::load(state_dir_str);
+    let mut entries = ledger.entries();
+    entries.sort_by_key(|e| e.destination);
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].hostnames, vec!["a.example.com", "b.example.com"]);
+    assert_eq!(entries[0].gateway, gateway);
+
+    let routes = RouteRegistry::new(manager.clone(), gateway.clone(), ledger, RouteLimits::default(), EgressMode::Routes, Uplinks::single());
+    assert!(routes.flush().await);
+    let remaining = manager.routes();
+    assert_eq!(remaining.len(), 1);
+    assert!(remaining.contains_key(&ip("203.0.113.9")));
+    assert!(RouteLedger::load(state_dir_str).entries().is_empty());
+    assert!(state_dir.join(ROUTE_LEDGER_FILE).exists());
+
+    fs::remove_dir_all(&state_dir).unwrap();
+}
+
+#[tokio::test]
+async fn ledger_writer_saves_later_changes_together() {
+    let gateway = NextHop::Gateway(ip("192.0.2.1"));
+    let manager = Arc::new(MemoryRouteManager::new(gateway.clone()));
+    let state_dir = temp_state_dir();
+    let state_dir_str = state_dir.to_str().unwrap();
+    let routes = Arc::new(RouteRegistry::new(manager.clone(), gateway.clone(), RouteLedger::load(state_dir_str), RouteLimits::default(), EgressMode::Routes, Uplinks::single()));
+    tokio::spawn(routes.clone().run_ledger_writer());
+
+    assert!(routes.ensure_route(&ip("198.51.100.1"), "a.example.com", routes.gateway()).await);
+    assert!(routes.ensure_route(&ip("198.51.100.1"), "b.example.com", routes.gateway()).await);
+    assert!(routes.ensure_route(&ip("198.51.100.1"), "c.example.com", routes.gateway()).await);
+    let saved = |state_dir : &str| RouteLedger::load(state_dir).entries()[0].hostnames.len();
+    assert_eq!(saved(state_dir_str), 1);
+    for _ in 0..50 {
+        if saved(state_dir_str) == 3 {
+            break;
+        }
+        delay_for(Duration::from_millis(100)).await;
+    }
+    assert_eq!(saved(state_dir_str), 3);
+
+    fs::remove_dir_all(&state_dir).unwrap();
+}
+
+#[tokio::test]
+async fn remove_leaves_routes_we_did_not_create() {
+    let gateway = NextHop::Gateway(ip("192.0.2.1"));
+    let manager = Arc::new(MemoryRouteManager::new(gateway.clone()));
+    manager.add_route(ip("203.0.113.9"), gateway.clone()).await.unwrap();
+
+    let routes = RouteRegistry::new(manager.clone(), gateway.clone(), RouteLedger::in_memory(), RouteLimits::default(), EgressMode::Routes, Uplinks::single());
+    // an existing route is used, but not taken over
+    assert!(routes.ensure_route(&ip("203.0.113.9"), "d.example.com", routes.gateway()).await);
+    assert!(routes.remove_route(&ip("203.0.113.9")).await);
+    assert!(manager.routes().contains_key(&ip("203.0.113.9")));
+}
+
+#[tokio::test]
+async fn least_recently_used_route_without_tunnels_is_evicted() {
+    let gateway = NextHop::Gateway(ip("192.0.2.1"));
+    let manager = Arc::new(MemoryRouteManager::new(gateway.clone()));
+    let limits = RouteLimits { max_routes: 2, ..RouteLimits::default() };
+    let routes = RouteRegistry::new(manager.clone(), gateway.clone(), RouteLedger::in_memory(), limits, EgressMode::Routes, Uplinks::single());
+
+    assert!(routes.ensure_route(&ip("198.51.100.1"), "a.example.com", routes.gateway()).await);
+    let _tunnel = routes.open_tunnel(&ip("198.51.100.1")).await;
+    assert!(routes.ensure_route(&ip("198.51.100.2"), "b.example.com", routes.gateway()).await);
+    assert!(routes.ensure_route(&ip("198.51.100.3"), "c.example.com", routes.gateway()).await);
+
+    // .1 is the least recently used, but has a tunnel open
+    let current = manager.routes();
+    assert_eq!(current.len(), 2);
+    assert!(current.contains_key(&ip("198.51.100.1")));
+    assert!(current.contains_key(&ip("198.51.100.3")));
+}
+
+#[tokio::test]
+async fn idle_routes_expire_unless_a_tunnel_is_open() {
+    let gateway = NextHop::Gateway(ip("192.0.2.1"));
+    let manager = Arc::new(MemoryRouteManager::new(gateway.clone()));
+    let limits = RouteLimits { idle_ttl: Duration::from_millis(50), ..RouteLimits::default() };
+    let routes = RouteRegistry::new(manager.clone(), gateway.clone(), RouteLedger::in_memory(), limits, EgressMode::Routes, Uplinks::single());
+
+    assert!(routes.ensure_route(&ip("198.51.100.1"), "a.example.com", routes.gateway()).await);
+    assert!(routes.ensure_route(&ip("198.51.100.2"), "b.example.com", routes.gateway()).await);
+    let tunnel = routes.open_tunnel(&ip("198.51.100.2")).await;
+
+    delay_for(Duration::from_millis(100)).await;
+    routes.expire_idle_routes().await;
+    assert_eq!(manager.routes().keys().collect::<Vec<_>>(), vec![&ip("198.51.100.2")]);
+
+    // closing the tunnel counts as a use, so the route lives one more idle period
+    drop(tunnel);
+    routes.expire_idle_routes().await;
+    assert_eq!(manager.routes().len(), 1);
+    delay_for(Duration::from_millis(100)).await;
+    routes.expire_idle_routes().await;
+    assert!(manager.routes().is_empty());
+}
+
+#[tokio::test]
+async fn idle_routes_we_did_not_create_are_left_alone_but_no_longer_tracked() {
+    let gateway = NextHop::Gateway(ip("192.0.2.1"));
+    let manager = Arc::new(MemoryRouteManager::new(gateway.clone()));
+    let limits = RouteLimits { idle_ttl: Duration::from_millis(50), ..RouteLimits::default() };
+    let routes = RouteRegistry::new(manager.clone(), gateway.clone(), RouteLedger::in_memory(), limits, EgressMode::Routes, Uplinks::single());
+
+    // someone else's host route, used but not taken over
+    manager.add_route(ip("203.0.113.9"), gateway.clone()).await.unwrap();
+    assert!(routes.ensure_route(&ip("203.0.113.9"), "d.example.com", routes.gateway()).await);
+
+    delay_for(Duration::from_millis(100)).await;
+    routes.expire_idle_routes().await;
+    assert!(manager.routes().contains_key(&ip("203.0.113.9")));
+
+    // once its owner removes it, the next connection sees that and adds a route of our own
+    manager.remove_route(ip("203.0.113.9")).await.unwrap();
+    assert!(routes.ensure_route(&ip("203.0.113.9"), "d.example.com", routes.gateway()).await);
+    assert_eq!(manager.routes().get(&ip("203.0.113.9")), Some(&gateway));
+    assert!(routes.flush().await);
+    assert!(manager.routes().is_empty());
+}
+
+#[tokio::test]
+async fn gateway_change_moves_only_our_routes() {
+    let old_gateway = NextHop::Gateway(ip("192.0.2.1"));
+    let new_gateway = NextHop::Gateway(ip("192.0.2.254"));
+    let manager = Arc::new(MemoryRouteManager::new(old_gateway.clone()));
+    manager.add_route(ip("203.0.113.9"), old_gateway.clone()).await.unwrap();
+
+    let routes = RouteRegistry::new(manager.clone(), old_gateway.clone(), RouteLedger::in_memory(), RouteLimits::default(), EgressMode::Routes, Uplinks::single());
+    let mut gateways = routes.subscribe_gateway();
+    assert_eq!(gateways.recv().await, Some(old_gateway.clone()));
+    assert!(routes.ensure_route(&ip("198.51.100.1"), "a.example.com", routes.gateway()).await);
+    assert!(routes.ensure_route(&ip("198.51.100.2"), "b.example.com", routes.gateway()).await);
+    assert!(!routes.check_gateway().await);
+
+    // the device moved to another network
+    manager.set_default_gateway(new_gateway.clone());
+    assert!(routes.check_gateway().await);
+    assert_eq!(routes.gateway(), new_gateway);
+    assert_eq!(gateways.recv().await, Some(new_gateway.clone()));
+
+    let current = manager.routes();
+    assert_eq!(current.get(&ip("198.51.100.1")), Some(&new_gateway));
+    assert_eq!(current.get(&ip("198.51.100.2")), Some(&new_gateway));
+    assert_eq!(current.get(&ip("203.0.113.9")), Some(&old_gateway));
+
+    // new routes go through the new gateway, and flush still finds the moved ones
+    assert!(routes.ensure_route(&ip("198.51.100.3"), "c.example.com", routes.gateway()).await);
+    assert_eq!(manager.routes().get(&ip("198.51.100.3")), Some(&new_gateway));
+    assert!(routes.flush().await);
+    assert_eq!(manager.routes().keys().collect::<Vec<_>>(), vec![&ip("203.0.113.9")]);
+}
+
+#[tokio::test]
+async fn point_to_point_routes_go_out_the_device() {
+    let gateway = NextHop::Device(String::from("ppp0"));
+    let manager = Arc::new(MemoryRouteManager::new(gateway.clone()));
+    let state_dir = temp_state_dir();
+    let state_dir_str = state_dir.to_str().unwrap();
+
+    {
+        let routes = RouteRegistry::new(manager.clone(), gateway.clone(), RouteLedger::load(state_dir_str), RouteLimits::default(), EgressMode::Routes, Uplinks::single());
+        // a device carries both address families
+        assert!(routes.ensure_route(&ip("198.51.100.1"), "a.example.com", routes.gateway()).await);
+        assert!(routes.ensure_route(&ip("2001:db8::1"), "b.example.com", routes.gateway()).await);
+    }
+    assert_eq!(manager.routes().get(&ip("198.51.100.1")), Some(&gateway));
+    assert_eq!(manager.routes().get(&ip("2001:db8::1")), Some(&gateway));
+
+    let saved = fs::read_to_string(state_dir.join(ROUTE_LEDGER_FILE)).unwrap();
+    assert!(saved.contains("\"dev ppp0\""));
+    let ledger = RouteLedger::load(state_dir_str);
+    assert!(ledger.entries().iter().all(|e| e.gateway == gateway));
+
+    let routes = RouteRegistry::new(manager.clone(), gateway.clone(), ledger, RouteLimits::default(), EgressMode::Routes, Uplinks::single());
+    assert!(routes.flush().await);
+    assert!(manager.routes().is_empty());
+
+    fs::remove_dir_all(&state_dir).unwrap();
+}
+
+#[tokio::test]
+async fn ipv6_routes_follow_the_ipv6_gateway() {
+    let gateway = NextHop::Gateway(ip("192.0.2.1"));
+    let gateway_v6 = NextHop::Gateway(ip("fe80::1"));
+    let manager = Arc::new(MemoryRouteManager::new(gateway.clone()));
+    manager.set_default_gateway_v6(Some(gateway_v6.clone()));
+
+    let routes = RouteRegistry::new(manager.clone(), gateway.clone(), RouteLedger::in_memory(), RouteLimits::default(), EgressMode::Routes, Uplinks::single());
+    assert_eq!(routes.gateway_for(&ip("2001:db8::1")), None);
+    assert!(routes.check_gateway().await);
+    assert_eq!(routes.gateway(), gateway);
+    assert_eq!(routes.gateway_for(&ip("2001:db8::1")), Some(gateway_v6.clone()));
+    assert_eq!(routes.gateway_for(&ip("198.51.100.1")), Some(gateway.clone()));
+
+    assert!(routes.ensure_route(&ip("2001:db8::1"), "a.example.com", routes.gateway_for(&ip("2001:db8::1")).unwrap()).await);
+    assert!(routes.ensure_route(&ip("198.51.100.1"), "a.example.com", routes.gateway_for(&ip("198.51.100.1")).unwrap()).await);
+
+    // only the IPv6 route moves when the IPv6 gateway changes
+    let new_gateway_v6 = NextHop::Gateway(ip("fe80::2"));
+    manager.set_default_gateway_v6(Some(new_gateway_v6.clone()));
+    assert!(routes.check_gateway().await);
+    assert_eq!(manager.routes().get(&ip("2001:db8::1")), Some(&new_gateway_v6));
+    assert_eq!(manager.routes().get(&ip("198.51.100.1")), Some(&gateway));
+
+    // without an IPv6 default route, IPv6 routes stay until there is one again
+    manager.set_default_gateway_v6(None);
+    assert!(routes.check_gateway().await);
+    assert_eq!(routes.gateway_for(&ip("2001:db8::1")), None);
+    assert_eq!(manager.routes().get(&ip("2001:db8::1")), Some(&new_gateway_v6));
+    assert!(routes.flush().await);
+    assert!(manager.routes().is_empty());
+}
@@ -0,0 +1,162 @@
+#![deny(warnings)]
+/**
+ * Copyright (c) 2020 Bubble, Inc.  All rights reserved.
+ * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
+ */
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::time::delay_for;
+
+mod common;
+
+use common::{free_port, start_echo_server};
+
+use bubble_flexrouter::dns_cache::{create_resolver, DnsCache, DnsCacheConfig};
+use bubble_flexrouter::egress::EgressMode;
+use bubble_flexrouter::policy::Policy;
+use bubble_flexrouter::route_ledger::RouteLedger;
+use bubble_flexrouter::route_manager::{MemoryRouteManager, NextHop};
+use bubble_flexrouter::routes::{RouteRegistry, RouteLimits};
+use bubble_flexrouter::socks::start_socks;
+use bubble_flexrouter::uplinks::Uplinks;
+
+const PROXY_AUTH: &str = "test-proxy-credential";
+
+struct TestSocks {
+    port: u16,
+    manager: Arc<MemoryRouteManager>,
+    gateway: NextHop
+}
+
+async fn start_test_socks (proxy_auth : Option<&str>) -> TestSocks {
+    let gateway = NextHop::Gateway("192.0.2.1".parse().unwrap());
+    let manager = Arc::new(MemoryRouteManager::new(gateway.clone()));
+    let routes = Arc::new(RouteRegistry::new(manager.clone(), gateway.clone(), RouteLedger::in_memory(), RouteLimits::default(), EgressMode::Routes, Uplinks::single()));
+    let mut policy = Policy::allow_all();
+    policy.allow_internal_nets(vec!["127.0.0.0/8".parse().unwrap()]);
+    // only IP literals are resolved in these tests, so the name server is never asked
+    let dns: SocketAddr = "127.0.0.1:53".parse().unwrap();
+    let resolver = Arc::new(create_resolver(dns, dns).await);
+    let resolver_cache = Arc::new(Mutex::new(DnsCache::new(DnsCacheConfig::default())));
+    let port = free_port();
+    tokio::spawn(start_socks(port, routes, resolver, resolver_cache, Arc::new(policy), proxy_auth.map(|a| Arc::new(String::from(a)))));
+    for _ in 0..50 {
+        if TcpStream::connect(("127.0.0.1", port)).await.is_ok() {
+            break;
+        }
+        delay_for(Duration::from_millis(20)).await;
+    }
+    TestSocks { port, manager, gateway }
+}
+
+// send the greeting offering methods, return the method the server chose
+async fn greet (stream : &mut TcpStream, methods : &[u8]) -> u8 {
+    let mut greeting = vec![0x05, methods.len() as u8];
+    greeting.extend_from_slice(methods);
+    stream.write_all(&greeting).await.unwrap();
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await.unwrap();
+    assert_eq!(choice[0], 0x05);
+    choice[1]
+}
+
+// RFC 1929 sub-negotiation, return the status byte
+async fn login (stream : &mut TcpStream, username : &str, password : &str) -> u8 {
+    let mut request = vec![0x01, username.len() as u8];
+    request.extend_from_slice(username.as_bytes());
+    request.push(password.len() as u8);
+    request.extend_from_slice(password.as_bytes());
+    stream.write_all(&request).await.unwrap();
+    let mut status = [0u8; 2];
+    stream.read_exact(&mut status).await.unwrap();
+    assert_eq!(status[0], 0x01);
+    status[1]
+}
+
+// CONNECT to an IPv4 target, return the reply code
+async fn connect (stream : &mut TcpStream, target : SocketAddr) -> u8 {
+    let ip = match target {
+        SocketAddr::V4(addr) => addr.ip().octets(),
+        SocketAddr::V6(_) => panic!("IPv4 targets only")
+    };
+    let mut request = vec![0x05, 0x01, 0x00, 0x01];
+    request.extend_from_slice(&ip);
+    request.extend_from_slice(&target.port().to_be_bytes());
+    stream.write_all(&request).await.unwrap();
+    // VER REP RSV ATYP, then a bound IPv4 address and port
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[0], 0x05);
+    assert_eq!(reply[3], 0x01);
+    reply[1]
+}
+
+async fn assert_echoes (stream : &mut TcpStream) {
+    stream.write_all(b"hello").await.unwrap();
+    let mut echoed = [0u8; 5];
+    stream.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"hello");
+}
+
+// true once the server has closed the connection
+async fn closed (stream : &mut TcpStream) -> bool {
+    let mut buf = [0u8; 1];
+    matches!(stream.read(&mut buf).await, Ok(0) | Err(_))
+}
+
+#[tokio::test]
+async fn no_auth_connect_reaches_echo_server_and_adds_route() {
+    let socks = start_test_socks(None).await;
+    let target = start_echo_server().await;
+
+    let mut stream = TcpStream::connect(("127.0.0.1", socks.port)).await.unwrap();
+    assert_eq!(greet(&mut stream, &[0x00]).await, 0x00);
+    assert_eq!(connect(&mut stream, target).await, 0x00);
+    assert_echoes(&mut stream).await;
+    assert_eq!(socks.manager.routes().get(&target.ip()), Some(&socks.gateway));
+}
+
+#[tokio::test]
+async fn password_is_required_when_proxy_auth_is_set() {
+    let socks = start_test_socks(Some(PROXY_AUTH)).await;
+
+    // a client that only offers no-auth is turned away
+    let mut stream = TcpStream::connect(("127.0.0.1", socks.port)).await.unwrap();
+    assert_eq!(greet(&mut stream, &[0x00]).await, 0xff);
+    assert!(closed(&mut stream).await);
+
+    let target = start_echo_server().await;
+    let mut stream = TcpStream::connect(("127.0.0.1", socks.port)).await.unwrap();
+    assert_eq!(greet(&mut stream, &[0x00, 0x02]).await, 0x02);
+    assert_eq!(login(&mut stream, "bubble", PROXY_AUTH).await, 0x00);
+    assert_eq!(connect(&mut stream, target).await, 0x00);
+    assert_echoes(&mut stream).await;
+}
+
+#[tokio::test]
+async fn wrong_password_is_rejected() {
+    let socks = start_test_socks(Some(PROXY_AUTH)).await;
+
+    let mut stream = TcpStream::connect(("127.0.0.1", socks.port)).await.unwrap();
+    assert_eq!(greet(&mut stream, &[0x02]).await, 0x02);
+    assert_eq!(login(&mut stream, "bubble", "not-the-credential").await, 0x01);
+    assert!(closed(&mut stream).await);
+    assert!(socks.manager.routes().is_empty());
+}
+
+#[tokio::test]
+async fn refused_connect_is_reported() {
+    let socks = start_test_socks(None).await;
+    // nothing listens on a port that was just freed
+    let target: SocketAddr = format!("127.0.0.1:{}", free_port()).parse().unwrap();
+
+    let mut stream = TcpStream::connect(("127.0.0.1", socks.port)).await.unwrap();
+    assert_eq!(greet(&mut stream, &[0x00]).await, 0x00);
+    assert_eq!(connect(&mut stream, target).await, 0x05);
+}
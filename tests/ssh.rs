@@ -0,0 +1,120 @@
+#![deny(warnings)]
+/**
+ * Copyright (c) 2020 Bubble, Inc.  All rights reserved.
+ * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
+ */
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ssh2::ErrorCode;
+
+use bubble_flexrouter::ssh::{classify_ssh_error, tunnel_label, Pump, TunnelError, TunnelStage};
+
+const PRIV_KEY: &str = "/home/bubble/.ssh/flex_key";
+const PORT: u16 = 4022;
+
+fn classify (stage : TunnelStage, code : ErrorCode) -> TunnelError {
+    classify_ssh_error(stage, ssh2::Error::new(code, "test error"), PRIV_KEY, PORT)
+}
+
+#[test]
+fn authentication_errors_are_classified() {
+    match classify(TunnelStage::Authenticate, ErrorCode::Session(-16)) {
+        TunnelError::KeyFile(e) => assert_eq!(e, format!("{}: test error", PRIV_KEY)),
+        e => panic!("expected KeyFile, got {:?}", e)
+    }
+    for code in &[-18, -19] {
+        match classify(TunnelStage::Authenticate, ErrorCode::Session(*code)) {
+            TunnelError::AuthRefused(e) => assert_eq!(e, "test error"),
+            e => panic!("expected AuthRefused for {}, got {:?}", code, e)
+        }
+    }
+    // anything else, such as a dropped connection (-13) or a non-session error, is a plain ssh error
+    for code in &[ErrorCode::Session(-13), ErrorCode::Session(-32), ErrorCode::SFTP(4)] {
+        match classify(TunnelStage::Authenticate, *code) {
+            TunnelError::Ssh(e) => assert!(e.to_string().contains("test error"), "unexpected message: {}", e),
+            e => panic!("expected Ssh for {:?}, got {:?}", code, e)
+        }
+    }
+}
+
+#[test]
+fn forwarding_errors_are_classified() {
+    match classify(TunnelStage::Forward, ErrorCode::Session(-32)) {
+        TunnelError::PortInUse(port) => assert_eq!(port, PORT),
+        e => panic!("expected PortInUse, got {:?}", e)
+    }
+    for code in &[ErrorCode::Session(-13), ErrorCode::Session(-18), ErrorCode::SFTP(4)] {
+        match classify(TunnelStage::Forward, *code) {
+            TunnelError::ForwardingFailed(e) => assert_eq!(e, "test error"),
+            e => panic!("expected ForwardingFailed for {:?}, got {:?}", code, e)
+        }
+    }
+}
+
+#[test]
+fn tunnel_label_names_the_registration() {
+    assert_eq!(tunnel_label("10.19.0.2", "bubble.example.com", PORT), "10.19.0.2 via bubble.example.com:4022");
+    assert_eq!(format!("{}", TunnelError::PortInUse(PORT)), "bubble could not listen on port 4022, it is probably already bound");
+}
+
+// a connected pair of loopback sockets
+fn socket_pair () -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    (client, server)
+}
+
+// pump until peer, which must be non-blocking, reaches EOF; returns what it read
+fn pump_to_eof (pump : &mut Pump<TcpStream, TcpStream>, peer : &mut TcpStream) -> Vec<u8> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut buf = vec![0u8; 16 * 1024];
+    let mut received = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        assert!(Instant::now() < deadline, "pump stalled after {} bytes", received.len());
+        pump.pump(&mut buf).unwrap();
+        match peer.read(&mut chunk) {
+            Ok(0) => return received,
+            Ok(n) => received.extend_from_slice(&chunk[..n]),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(1)),
+            Err(e) => panic!("error reading from peer: {}", e)
+        }
+    }
+}
+
+#[test]
+fn pump_forwards_both_ways_and_passes_on_eof() {
+    // bubble_peer stands in for the bubble's side of the channel, proxy_peer for our proxy
+    let (bubble_peer, bubble_end) = socket_pair();
+    let (proxy_end, mut proxy_peer) = socket_pair();
+    bubble_end.set_nonblocking(true).unwrap();
+    proxy_end.set_nonblocking(true).unwrap();
+    proxy_peer.set_nonblocking(true).unwrap();
+    let mut pump = Pump::new(bubble_end, proxy_end);
+    assert_eq!(pump.proxy_interest(), (true, false));
+
+    // more than fits in the socket buffers, so some writes only go through in part
+    let request: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let sent = request.clone();
+    let mut writer = bubble_peer.try_clone().unwrap();
+    let sender = thread::spawn(move || {
+        writer.write_all(&sent).unwrap();
+        writer.shutdown(Shutdown::Write).unwrap();
+    });
+    let received = pump_to_eof(&mut pump, &mut proxy_peer);
+    sender.join().unwrap();
+    assert!(received == request, "proxy received {} of {} bytes, or different bytes", received.len(), request.len());
+    assert!(!pump.is_done());
+
+    proxy_peer.write_all(b"response").unwrap();
+    proxy_peer.shutdown(Shutdown::Write).unwrap();
+    let mut bubble_peer = bubble_peer;
+    bubble_peer.set_nonblocking(true).unwrap();
+    assert_eq!(pump_to_eof(&mut pump, &mut bubble_peer), b"response");
+    assert!(pump.is_done());
+}
@@ -0,0 +1,108 @@
+#![deny(warnings)]
+/**
+ * Copyright (c) 2020 Bubble, Inc.  All rights reserved.
+ * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
+ */
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+mod common;
+
+use common::{registration, start_fake_bubble, SESSION};
+
+use bubble_flexrouter::backoff::BackoffConfig;
+use bubble_flexrouter::bubble_client::{BubbleClient, BubbleClientConfig};
+use bubble_flexrouter::host_keys::HostKeyStore;
+use bubble_flexrouter::ssh::{TunnelError, TunnelTarget};
+use bubble_flexrouter::tunnel::{RegisterError, TunnelManager, TunnelState};
+
+fn client (addr : SocketAddr) -> Arc<BubbleClient> {
+    let base_url = format!("http://{}/api", addr);
+    Arc::new(BubbleClient::with_base_url("127.0.0.1", base_url.as_str(), SESSION, &BubbleClientConfig::default()).unwrap())
+}
+
+#[test]
+fn only_legal_transitions_are_allowed() {
+    use TunnelState::*;
+    assert!(Unregistered.can_transition_to(Registering));
+    assert!(!Unregistered.can_transition_to(Active));
+    assert!(!Unregistered.can_transition_to(Connecting));
+    assert!(Registering.can_transition_to(Connecting));
+    assert!(!Registering.can_transition_to(Active));
+    assert!(Connecting.can_transition_to(Active));
+    assert!(Active.can_transition_to(Degraded));
+    assert!(Degraded.can_transition_to(Active));
+    assert!(Degraded.can_transition_to(Reconnecting));
+    assert!(!Active.can_transition_to(Active));
+    assert!(!Active.can_transition_to(Connecting));
+    assert!(Reconnecting.can_transition_to(Connecting));
+    assert!(!Reconnecting.can_transition_to(Active));
+    assert!(Reconnecting.can_transition_to(WaitingForNetwork));
+    assert!(!Active.can_transition_to(WaitingForNetwork));
+    assert!(WaitingForNetwork.can_transition_to(Reconnecting));
+    assert!(!WaitingForNetwork.can_transition_to(Connecting));
+    assert!(Deleted.can_transition_to(Registering));
+    assert!(!Deleted.can_transition_to(Reconnecting));
+}
+
+#[tokio::test]
+async fn failed_registration_returns_to_unregistered() {
+    let tunnel = TunnelManager::new(10, BackoffConfig::default(), HostKeyStore::in_memory());
+    assert_eq!(tunnel.state(), TunnelState::Unregistered);
+
+    // no ssh server on 127.0.0.1 has this host key, so opening the tunnel fails
+    let fake = start_fake_bubble("127.0.0.1 ssh-ed25519 AAAA");
+    let bubble = client(fake.addr);
+    let result = tunnel.register(&registration("10.19.0.3"), bubble.clone(), TunnelTarget::Port(1), Arc::new(String::from("/nonexistent"))).await;
+    assert!(matches!(result, Err(RegisterError::Bubble(_))));
+    assert_eq!(tunnel.state(), TunnelState::Unregistered);
+
+    let result = tunnel.register(&registration("10.19.0.2"), bubble, TunnelTarget::Port(1), Arc::new(String::from("/nonexistent"))).await;
+    // refused if nothing listens on port 22 here; if an ssh server does, its host key is not the one registered
+    assert!(matches!(result, Err(RegisterError::Tunnel(TunnelError::Unreachable(_))) | Err(RegisterError::Tunnel(TunnelError::HostKeyMismatch { .. }))));
+    // the bubble accepted the registration, but without a tunnel it is withdrawn; the refused one never existed
+    assert_eq!(fake.deleted(), vec![String::from("10.19.0.2")]);
+
+    let report = tunnel.report();
+    assert_eq!(report.state, TunnelState::Unregistered);
+    assert_eq!(report.port, None);
+    let path: Vec<(TunnelState, TunnelState)> = report.transitions.iter().map(|t| (t.from, t.to)).collect();
+    assert_eq!(path, vec![
+        (TunnelState::Unregistered, TunnelState::Registering),
+        (TunnelState::Registering, TunnelState::Unregistered),
+        (TunnelState::Unregistered, TunnelState::Registering),
+        (TunnelState::Registering, TunnelState::Connecting),
+        (TunnelState::Connecting, TunnelState::Unregistered)
+    ]);
+
+    // unregistering when there is nothing to unregister is not a transition
+    tunnel.unregister("test").await;
+    assert_eq!(tunnel.report().transitions.len(), 5);
+    assert_eq!(tunnel.report().bubble, None);
+}
+
+#[tokio::test]
+async fn changed_host_key_is_refused() {
+    let host_keys = HostKeyStore::in_memory();
+    host_keys.check("127.0.0.1", "127.0.0.1 ssh-ed25519 BBBB").unwrap();
+    let tunnel = TunnelManager::new(10, BackoffConfig::default(), host_keys);
+
+    // no ssh server on 127.0.0.1 has this host key, so opening the tunnel fails
+    let fake = start_fake_bubble("127.0.0.1 ssh-ed25519 AAAA");
+    let bubble = client(fake.addr);
+    let result = tunnel.register(&registration("10.19.0.2"), bubble, TunnelTarget::Port(1), Arc::new(String::from("/nonexistent"))).await;
+    match result {
+        Err(RegisterError::HostKeyChanged { bubble, pinned, offered }) => {
+            assert_eq!(bubble, "127.0.0.1");
+            assert_eq!(pinned, "127.0.0.1 ssh-ed25519 BBBB");
+            assert_eq!(offered, "127.0.0.1 ssh-ed25519 AAAA");
+        }
+        other => panic!("expected a changed host key, got {:?}", other)
+    }
+    let report = tunnel.report();
+    assert_eq!(report.state, TunnelState::Unregistered);
+    assert!(report.transitions.last().unwrap().reason.contains("host key"));
+    // the registration the bubble accepted is not left behind
+    assert_eq!(fake.deleted(), vec![String::from("10.19.0.2")]);
+}
@@ -0,0 +1,103 @@
+#![deny(warnings)]
+/**
+ * Copyright (c) 2020 Bubble, Inc.  All rights reserved.
+ * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
+ */
+
+use std::sync::Arc;
+
+use tokio::net::TcpListener;
+
+mod common;
+
+use common::ip;
+
+use bubble_flexrouter::egress::EgressMode;
+use bubble_flexrouter::route_ledger::RouteLedger;
+use bubble_flexrouter::route_manager::{MemoryRouteManager, NextHop};
+use bubble_flexrouter::routes::{RouteRegistry, RouteLimits};
+use bubble_flexrouter::uplinks::Uplinks;
+
+const UPLINKS_JSON: &str = r#"{
+  "uplinks": [
+    { "name": "wifi" },
+    { "name": "lte", "next_hop": "dev wwan0" },
+    { "name": "office", "next_hop": "192.0.2.254" }
+  ],
+  "rules": [
+    { "uplink": "lte", "domains": [".video.example"] },
+    { "uplink": "office", "cidrs": ["203.0.113.0/24"] }
+  ]
+}"#;
+
+#[test]
+fn rules_and_header_choose_the_uplink() {
+    let uplinks = Uplinks::parse(UPLINKS_JSON).unwrap();
+    assert_eq!(uplinks.select("www.example.com", &ip("198.51.100.1"), None).name, "wifi");
+    assert_eq!(uplinks.select("cdn.video.example", &ip("198.51.100.1"), None).name, "lte");
+    assert_eq!(uplinks.select("www.example.com", &ip("203.0.113.7"), None).name, "office");
+    // the bubble's choice wins over the rules; an unknown name is ignored
+    assert_eq!(uplinks.select("cdn.video.example", &ip("198.51.100.1"), Some("office")).name, "office");
+    assert_eq!(uplinks.select("cdn.video.example", &ip("198.51.100.1"), Some("satellite")).name, "lte");
+
+    // a down uplink is passed over for the next healthy one in the list
+    uplinks.set_health("lte", Err(String::from("no carrier")));
+    uplinks.set_health("wifi", Err(String::from("no carrier")));
+    assert_eq!(uplinks.select("cdn.video.example", &ip("198.51.100.1"), Some("lte")).name, "office");
+    // with everything down, the preferred uplink is tried anyway
+    uplinks.set_health("office", Err(String::from("no carrier")));
+    assert_eq!(uplinks.select("cdn.video.example", &ip("198.51.100.1"), None).name, "lte");
+
+    let health = uplinks.health();
+    assert_eq!(health.len(), 3);
+    assert!(health.iter().all(|h| !h.healthy && h.last_check.is_some()));
+    assert_eq!(health[1].next_hop, Some(NextHop::Device(String::from("wwan0"))));
+}
+
+#[test]
+fn invalid_uplinks_are_rejected() {
+    assert!(Uplinks::parse(r#"{ "uplinks": [] }"#).is_err());
+    assert!(Uplinks::parse(r#"{ "uplinks": [{ "name": "a" }, { "name": "a" }] }"#).is_err());
+    assert!(Uplinks::parse(r#"{ "uplinks": [{ "name": "a" }], "rules": [{ "uplink": "b", "cidrs": ["203.0.113.0/24"] }] }"#).is_err());
+    assert!(Uplinks::parse(r#"{ "uplinks": [{ "name": "a", "next_hop": "dev" }] }"#).is_err());
+}
+
+#[tokio::test]
+async fn route_moves_to_another_uplink_when_its_uplink_goes_down() {
+    let gateway = NextHop::Gateway(ip("192.0.2.1"));
+    let office = NextHop::Gateway(ip("192.0.2.254"));
+    let manager = Arc::new(MemoryRouteManager::new(gateway.clone()));
+    let routes = RouteRegistry::new(manager.clone(), gateway.clone(), RouteLedger::in_memory(), RouteLimits::default(), EgressMode::Routes, Uplinks::parse(UPLINKS_JSON).unwrap());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    routes.connect(addr, "localhost", Some("office")).await.unwrap();
+    assert_eq!(manager.routes().get(&addr.ip()), Some(&office));
+
+    routes.uplinks().set_health("office", Err(String::from("no carrier")));
+    routes.connect(addr, "localhost", Some("office")).await.unwrap();
+    assert_eq!(manager.routes().get(&addr.ip()), Some(&gateway));
+
+    // a route carrying a tunnel stays put
+    routes.uplinks().set_health("office", Ok(()));
+    let _tunnel = routes.open_tunnel(&addr.ip()).await;
+    routes.connect(addr, "localhost", Some("office")).await.unwrap();
+    assert_eq!(manager.routes().get(&addr.ip()), Some(&gateway));
+}
+
+#[tokio::test]
+async fn health_check_connects_through_each_uplink() {
+    let gateway = NextHop::Gateway(ip("192.0.2.1"));
+    let manager = Arc::new(MemoryRouteManager::new(gateway.clone()));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let json = format!(r#"{{ "uplinks": [{{ "name": "wifi" }}, {{ "name": "lte", "next_hop": "dev wwan0" }}], "check_target": "{}" }}"#, listener.local_addr().unwrap());
+    let routes = RouteRegistry::new(manager.clone(), gateway.clone(), RouteLedger::in_memory(), RouteLimits::default(), EgressMode::Routes, Uplinks::parse(json.as_str()).unwrap());
+
+    routes.check_uplinks().await;
+    assert!(routes.uplinks().health().iter().all(|h| h.healthy));
+
+    drop(listener);
+    routes.check_uplinks().await;
+    let health = routes.uplinks().health();
+    assert!(health.iter().all(|h| !h.healthy && h.error.is_some()));
+}
@@ -0,0 +1,60 @@
+#![deny(warnings)]
+#![cfg(unix)]
+/**
+ * Copyright (c) 2020 Bubble, Inc.  All rights reserved.
+ * For personal (non-commercial) use, see license: https://getbubblenow.com/bubble-license/
+ */
+
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+
+mod common;
+
+use common::temp_state_dir;
+
+use bubble_flexrouter::util::try_bind_private_socket;
+
+#[tokio::test]
+async fn private_socket_is_owner_only_as_soon_as_it_is_bound() {
+    let dir = temp_state_dir();
+    let path = dir.join("proxy.sock");
+    let _listener = try_bind_private_socket(path.to_str().unwrap()).unwrap();
+
+    let meta = fs::symlink_metadata(&path).unwrap();
+    assert!(meta.file_type().is_socket());
+    assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+    assert!(UnixStream::connect(&path).is_ok());
+    // the private directory it was bound in is gone
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+}
+
+#[tokio::test]
+async fn stale_socket_is_replaced_but_other_files_are_not() {
+    let dir = temp_state_dir();
+    let path = dir.join("proxy.sock");
+    drop(try_bind_private_socket(path.to_str().unwrap()).unwrap());
+    let listener = try_bind_private_socket(path.to_str().unwrap());
+    assert!(listener.is_ok(), "stale socket was not replaced: {:?}", listener.err());
+    assert!(UnixStream::connect(&path).is_ok());
+
+    let file_path = dir.join("important.txt");
+    fs::write(&file_path, "keep me").unwrap();
+    assert!(try_bind_private_socket(file_path.to_str().unwrap()).is_err());
+    assert_eq!(fs::read_to_string(&file_path).unwrap(), "keep me");
+}
+
+#[tokio::test]
+async fn socket_in_use_is_not_taken_over() {
+    let dir = temp_state_dir();
+    let path = dir.join("proxy.sock");
+    let _running = try_bind_private_socket(path.to_str().unwrap()).unwrap();
+    match try_bind_private_socket(path.to_str().unwrap()) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::AddrInUse),
+        Ok(_) => panic!("took over a socket that is in use")
+    }
+    // the running listener still has it
+    assert!(UnixStream::connect(&path).is_ok());
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+}